@@ -0,0 +1,43 @@
+//! Host functions letting the offchain workers claim the named duties of the remote authority,
+//! so that the work of a validator run by several replicas is done by a single one of them:
+//!
+//! ```ignore
+//! if duty_claims::duty_claims::claim("price-feed", block_number) {
+//! 	// submit the price
+//! }
+//! ```
+//!
+//! The node registers the host functions and provides the claims to the offchain workers, a
+//! claim made anywhere else is denied.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use sp_runtime_interface::runtime_interface;
+
+#[cfg(feature = "std")]
+use sp_externalities::ExternalitiesExt;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+/// Claims of the named duties, blocking until resolved since the host functions are synchronous.
+#[cfg(feature = "std")]
+pub trait BlockingDutyClaims: Send + Sync {
+	fn claim(&self, duty: &str, index: u64) -> bool;
+}
+
+#[cfg(feature = "std")]
+sp_externalities::decl_extension! {
+	/// Claims of the named duties made by the node.
+	pub struct DutyClaimsExt(Arc<dyn BlockingDutyClaims>);
+}
+
+#[runtime_interface]
+pub trait DutyClaims {
+	/// Whether this replica performs the named duty at the given index. A duty is granted once
+	/// per index, and only for an index greater than any claimed before.
+	fn claim(&mut self, duty: &str, index: u64) -> bool {
+		match self.extension::<DutyClaimsExt>() {
+			Some(claims) => claims.claim(duty, index),
+			None => false,
+		}
+	}
+}
@@ -1,5 +1,9 @@
 use clap::Parser;
-use permission_resolver::RemoteAuthorityPermissionResolverFactory;
+use permission_resolver::{
+	majority, DutyTimeouts, FailPolicies, Fallback, FallbackDuties, PluginEndpoint,
+	PluginPermissionResolverFactory, QuorumPermissionResolverFactory,
+	RemoteAuthorityPermissionResolverFactory, ReplicaPriority, TlsConfig,
+};
 use sc_cli::{
 	ChainSpec, CliConfiguration, Error, ImportParams, KeystoreParams, NetworkParams,
 	OffchainWorkerParams, Role, SharedParams,
@@ -7,7 +11,7 @@ use sc_cli::{
 use sc_service::{config::PrometheusConfig, BasePath, TransactionPoolOptions};
 use sc_telemetry::TelemetryEndpoints;
 use sp_authority_permission::{AlwaysPermissionGrantedFactory, PermissionResolverFactory};
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 #[derive(Debug, clap::Parser)]
 pub struct Cli {
@@ -23,9 +27,393 @@ pub struct RunCmd {
 	#[clap(flatten)]
 	pub base: sc_cli::RunCmd,
 
-	/// List of tikv pd server addresses
+	/// List of tikv pd server addresses, or a single DNS SRV name (`srv://_pd._tcp.example.com`)
+	/// or pd URL (`http://pd.example.com:2379`, `https://` needs the certificates below) to
+	/// discover the pd servers by.
 	#[clap(long)]
 	pub remote_authority: Vec<String>,
+
+	/// Another tikv cluster asked along with the one of `--remote-authority`, given once per
+	/// cluster with its pd server addresses separated by commas. A duty is granted only by a
+	/// quorum of the clusters, and a cluster that can't be asked denies it, so the fail policies
+	/// can't be given along with it.
+	#[clap(long, multiple_occurrences = true, number_of_values = 1)]
+	pub remote_authority_quorum_backend: Vec<String>,
+
+	/// Number of tikv clusters that have to grant a duty, at least the majority of them, which
+	/// is the default.
+	#[clap(long, requires = "remote_authority_quorum_backend")]
+	pub remote_authority_quorum: Option<usize>,
+
+	/// Number of seconds between the refreshes of the discovered pd servers, the tikv client is
+	/// rebuilt when they change.
+	#[clap(long, default_value_t = 30)]
+	pub remote_authority_discovery_refresh_secs: u64,
+
+	/// CA certificate verifying the tikv pd and tikv servers, secures the connections with
+	/// mutual TLS together with the client certificate and key.
+	#[clap(long, requires_all = &["remote_authority_cert_path", "remote_authority_key_path"])]
+	pub remote_authority_ca_path: Option<PathBuf>,
+
+	/// Client certificate presented to the tikv pd and tikv servers.
+	#[clap(long, requires_all = &["remote_authority_ca_path", "remote_authority_key_path"])]
+	pub remote_authority_cert_path: Option<PathBuf>,
+
+	/// Private key of the client certificate.
+	#[clap(long, requires_all = &["remote_authority_ca_path", "remote_authority_cert_path"])]
+	pub remote_authority_key_path: Option<PathBuf>,
+
+	/// Use flat tikv keys instead of keys scoped by the genesis hash and the local authority key.
+	/// All replicas of a validator have to use the same setting.
+	#[clap(long)]
+	pub remote_authority_legacy_keys: bool,
+
+	/// Name of this replica recorded with its claims in tikv, the node name by default.
+	#[clap(long)]
+	pub remote_authority_replica_id: Option<String>,
+
+	/// Write raw claimed indexes instead of claim records, while some replicas of the validator
+	/// still run a version that can't read the records.
+	#[clap(long)]
+	pub remote_authority_legacy_values: bool,
+
+	/// Number of slots a block authoring claim may be away from the slot of the local clock.
+	#[clap(long, default_value_t = 10)]
+	pub remote_authority_max_slot_drift: u64,
+
+	/// Overwrite claimed slots too far ahead of the local clock. Only use it once the clock of the
+	/// replica that claimed them is fixed, and remove it after the recovery.
+	#[clap(long)]
+	pub remote_authority_clear_skewed_slots: bool,
+
+	/// Let a single replica author blocks and vote while it holds a lease in tikv, instead of
+	/// racing the other replicas for every slot. The standbys take over once the lease expires.
+	/// Replicas have to use distinct replica ids.
+	#[clap(long)]
+	pub remote_authority_lease: bool,
+
+	/// Keep the record of every claim in tikv for the post-incident forensics. Records of the
+	/// finalized slots are pruned.
+	#[clap(long)]
+	pub remote_authority_claim_history: bool,
+
+	/// Keep the claim history for at least this number of seconds, the claims of the finalized
+	/// slots are pruned once they are older.
+	#[clap(long, requires = "remote_authority_claim_history")]
+	pub remote_authority_claim_history_retention_secs: Option<u64>,
+
+	/// Claim with the tikv RawKV compare-and-swap instead of the transactions, taking a single
+	/// round trip per claim. Every replica of the validator has to use it, since the raw keys
+	/// aren't visible to the transactions. Claim history isn't kept in this mode.
+	#[clap(long, conflicts_with = "remote_authority_claim_history")]
+	pub remote_authority_compare_and_swap: bool,
+
+	/// Claim in pessimistic tikv transactions, locking the claimed key on read. Under heavy
+	/// contention the replicas losing a claim wait for the winner instead of writing and failing
+	/// at commit.
+	#[clap(long, conflicts_with = "remote_authority_compare_and_swap")]
+	pub remote_authority_pessimistic: bool,
+
+	/// Priority of this replica when claiming slots, 0 being the highest. A replica of lower
+	/// priority claims a slot only after the replicas above it had the chance to, so the replica
+	/// closest to the rest of the network authors while it's up.
+	#[clap(long, conflicts_with = "remote_authority_lease")]
+	pub remote_authority_priority: Option<u32>,
+
+	/// Fraction of the slot each priority level waits after the level above it before claiming a
+	/// slot, not negative and less than a slot once multiplied by the priority.
+	#[clap(long, default_value_t = 0.1, requires = "remote_authority_priority")]
+	pub remote_authority_priority_delay: f64,
+
+	/// Claim the next Aura slot of the validator in the background once this replica won a slot,
+	/// so that the claim latency is spent before that slot starts. Replicas of lower priority
+	/// don't pre-claim. The flat keys of `--remote-authority-legacy-keys` are shared by the
+	/// authorities, so the slots aren't pre-claimed with them.
+	#[clap(
+		long,
+		conflicts_with_all = &["remote_authority_lease", "remote_authority_legacy_keys"]
+	)]
+	pub remote_authority_pre_claim: bool,
+
+	/// Record the parent block with the slot claims, so that a standby takes over a slot whose
+	/// winner built on a block since orphaned by finality, without two blocks being signed on the
+	/// same parent.
+	#[clap(
+		long,
+		conflicts_with_all = &[
+			"remote_authority_lease",
+			"remote_authority_pre_claim",
+			"remote_authority_legacy_values",
+		]
+	)]
+	pub remote_authority_fork_aware: bool,
+
+	/// Make and record the claims without acting on them, every permission is granted like
+	/// without the remote authority. The claims that would have been decided differently are
+	/// logged and counted, so that the cluster can be validated before it's trusted.
+	#[clap(long)]
+	pub remote_authority_shadow: bool,
+
+	/// Duty granted like without the remote authority while the tikv cluster can't be reached,
+	/// instead of being decided by its fail policy. A denial of the cluster is never overridden.
+	/// Block authoring and GRANDPA voting aren't offered, every replica would equivocate.
+	#[clap(long, value_enum)]
+	pub remote_authority_fallback: Vec<FallbackDuty>,
+
+	/// Number of seconds the tikv cluster has to be reachable before the fallback duties move
+	/// back to it. Its denials are final meanwhile.
+	#[clap(long, default_value_t = 60, requires = "remote_authority_fallback")]
+	pub remote_authority_fallback_hysteresis_secs: u64,
+
+	/// Unix socket of a permission plugin deciding the duties instead of a tikv cluster. The
+	/// fail policies of the remote authority apply when it can't be asked.
+	#[clap(long, conflicts_with_all = &["remote_authority", "permission_plugin_command"])]
+	pub permission_plugin_socket: Option<PathBuf>,
+
+	/// Command starting a permission plugin deciding the duties instead of a tikv cluster, spoken
+	/// to over its stdin and stdout. The arguments are separated by whitespace.
+	#[clap(long, conflicts_with = "remote_authority")]
+	pub permission_plugin_command: Option<String>,
+
+	/// Time limit in milliseconds of the permission plugin deciding a duty.
+	#[clap(long, default_value_t = 1000)]
+	pub permission_plugin_timeout_ms: u64,
+
+	/// Number of seconds between the health checks of the permission plugin.
+	#[clap(long, default_value_t = 10)]
+	pub permission_plugin_health_interval_secs: u64,
+
+	/// What to do with block authoring when the tikv cluster can't be asked for the permission.
+	#[clap(
+		long,
+		value_enum,
+		default_value_t = FailPolicy::Deny,
+		conflicts_with = "remote_authority_quorum_backend"
+	)]
+	pub remote_authority_slot_fail_policy: FailPolicy,
+
+	/// What to do with GRANDPA voting when the tikv cluster can't be asked for the permission.
+	#[clap(
+		long,
+		value_enum,
+		default_value_t = FailPolicy::Deny,
+		conflicts_with = "remote_authority_quorum_backend"
+	)]
+	pub remote_authority_round_fail_policy: FailPolicy,
+
+	/// What to do with "I'm online" heartbeats when the tikv cluster can't be asked for the
+	/// permission.
+	#[clap(
+		long,
+		value_enum,
+		default_value_t = FailPolicy::Deny,
+		conflicts_with = "remote_authority_quorum_backend"
+	)]
+	pub remote_authority_session_fail_policy: FailPolicy,
+
+	/// What to do with the duties named by the offchain workers when the tikv cluster can't be
+	/// asked for the permission.
+	#[clap(
+		long,
+		value_enum,
+		default_value_t = FailPolicy::Deny,
+		conflicts_with = "remote_authority_quorum_backend"
+	)]
+	pub remote_authority_duty_fail_policy: FailPolicy,
+
+	/// Time limit in milliseconds of resolving the block authoring permission, a third of the slot
+	/// duration by default.
+	#[clap(long)]
+	pub remote_authority_slot_timeout_ms: Option<u64>,
+
+	/// Time limit in milliseconds of resolving the GRANDPA voting permission, the GRANDPA gossip
+	/// duration by default.
+	#[clap(long)]
+	pub remote_authority_round_timeout_ms: Option<u64>,
+
+	/// Time limit in milliseconds of resolving the "I'm online" heartbeat permission, a third of
+	/// the slot duration by default.
+	#[clap(long)]
+	pub remote_authority_session_timeout_ms: Option<u64>,
+
+	/// Time limit in milliseconds of claiming a duty named by the offchain workers, a third of the
+	/// slot duration by default.
+	#[clap(long)]
+	pub remote_authority_duty_timeout_ms: Option<u64>,
+}
+
+/// Decision about a duty when the tikv cluster can't be asked for the permission.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum FailPolicy {
+	/// Skip the duty.
+	Deny,
+	/// Perform the duty.
+	Grant,
+	/// Perform the duty only if no other replica has been seen claiming duties.
+	GrantIfAlone,
+}
+
+impl From<FailPolicy> for permission_resolver::FailPolicy {
+	fn from(policy: FailPolicy) -> Self {
+		match policy {
+			FailPolicy::Deny => permission_resolver::FailPolicy::Deny,
+			FailPolicy::Grant => permission_resolver::FailPolicy::Grant,
+			FailPolicy::GrantIfAlone => permission_resolver::FailPolicy::GrantIfAlone,
+		}
+	}
+}
+
+/// Duty that may be performed without the tikv cluster while it can't be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FallbackDuty {
+	/// "I'm online" heartbeats.
+	Session,
+}
+
+impl RunCmd {
+	fn remote_authority_tls(&self) -> Option<TlsConfig> {
+		match (
+			&self.remote_authority_ca_path,
+			&self.remote_authority_cert_path,
+			&self.remote_authority_key_path,
+		) {
+			(Some(ca_path), Some(cert_path), Some(key_path)) => Some(TlsConfig {
+				ca_path: ca_path.clone(),
+				cert_path: cert_path.clone(),
+				key_path: key_path.clone(),
+			}),
+			_ => None,
+		}
+	}
+
+	/// Factories of the tikv permission resolvers, the one of `--remote-authority` first and one
+	/// more for each quorum backend. Empty if no pd server address is given.
+	pub fn remote_authority_factories(&self) -> Vec<RemoteAuthorityPermissionResolverFactory> {
+		if self.remote_authority.is_empty() {
+			return Vec::new()
+		}
+		let quorum_backends = self
+			.remote_authority_quorum_backend
+			.iter()
+			.map(|remote_urls| remote_urls.split(',').map(|url| url.trim().to_owned()).collect());
+		// the fail policies conflict with the quorum backends, so the clusters that can't be
+		// asked deny and don't make up a quorum
+		std::iter::once(self.remote_authority.clone())
+			.chain(quorum_backends)
+			.map(|remote_urls| self.remote_authority_factory(remote_urls))
+			.collect()
+	}
+
+	/// Number of the tikv clusters that have to grant a duty.
+	pub fn remote_authority_quorum(&self) -> usize {
+		self.remote_authority_quorum
+			.unwrap_or_else(|| majority(self.remote_authority_quorum_backend.len() + 1))
+	}
+
+	fn remote_authority_factory(
+		&self,
+		remote_urls: Vec<String>,
+	) -> RemoteAuthorityPermissionResolverFactory {
+		RemoteAuthorityPermissionResolverFactory {
+			remote_urls,
+			discovery_refresh: Duration::from_secs(
+				self.remote_authority_discovery_refresh_secs.max(1),
+			),
+			cached: true,
+			tls: self.remote_authority_tls(),
+			legacy_keys: self.remote_authority_legacy_keys,
+			scope: None,
+			legacy_values: self.remote_authority_legacy_values,
+			replica_id: self.remote_authority_replica_id.clone(),
+			node_version: String::new(),
+			authority_set_id: None,
+			fail_policies: FailPolicies {
+				slot: self.remote_authority_slot_fail_policy.into(),
+				round: self.remote_authority_round_fail_policy.into(),
+				session: self.remote_authority_session_fail_policy.into(),
+				duty: self.remote_authority_duty_fail_policy.into(),
+			},
+			retry_policy: Default::default(),
+			timeouts: DutyTimeouts {
+				slot: self.remote_authority_slot_timeout_ms.map(Duration::from_millis),
+				round: self.remote_authority_round_timeout_ms.map(Duration::from_millis),
+				session: self.remote_authority_session_timeout_ms.map(Duration::from_millis),
+				duty: self.remote_authority_duty_timeout_ms.map(Duration::from_millis),
+			},
+			prometheus_registry: None,
+			cluster: None,
+			slot_duration: None,
+			max_slot_drift: self.remote_authority_max_slot_drift,
+			clear_skewed_slots: self.remote_authority_clear_skewed_slots,
+			lease: self.remote_authority_lease,
+			history: self.remote_authority_claim_history,
+			history_retention: self
+				.remote_authority_claim_history_retention_secs
+				.map(Duration::from_secs),
+			compare_and_swap: self.remote_authority_compare_and_swap,
+			pessimistic: self.remote_authority_pessimistic,
+			priority: self.remote_authority_priority.map(|priority| ReplicaPriority {
+				priority,
+				delay_fraction: self.remote_authority_priority_delay,
+			}),
+			pre_claim: self.remote_authority_pre_claim,
+			slot_schedule: None,
+			fork_aware: self.remote_authority_fork_aware,
+			fork_view: None,
+			shadow: self.remote_authority_shadow.then(|| {
+				Arc::new(AlwaysPermissionGrantedFactory {}) as Arc<dyn PermissionResolverFactory>
+			}),
+			fallback: self.remote_authority_fallback(),
+			spawner: None,
+			clients: Default::default(),
+		}
+	}
+
+	/// Factory of the permission plugin resolver, `None` if no plugin is given.
+	pub fn permission_plugin_factory(&self) -> Option<PluginPermissionResolverFactory> {
+		let endpoint = match (&self.permission_plugin_socket, &self.permission_plugin_command) {
+			(Some(path), _) => PluginEndpoint::Socket(path.clone()),
+			(None, Some(command)) =>
+				PluginEndpoint::Command(command.split_whitespace().map(str::to_owned).collect()),
+			(None, None) => return None,
+		};
+		let timeout = Some(Duration::from_millis(self.permission_plugin_timeout_ms));
+		Some(PluginPermissionResolverFactory {
+			endpoint,
+			fail_policies: FailPolicies {
+				slot: self.remote_authority_slot_fail_policy.into(),
+				round: self.remote_authority_round_fail_policy.into(),
+				session: self.remote_authority_session_fail_policy.into(),
+				duty: self.remote_authority_duty_fail_policy.into(),
+			},
+			timeouts: DutyTimeouts {
+				slot: timeout,
+				round: timeout,
+				session: timeout,
+				duty: timeout,
+			},
+			health_interval: Duration::from_secs(
+				self.permission_plugin_health_interval_secs.max(1),
+			),
+			authority_set_id: None,
+			spawner: None,
+		})
+	}
+
+	fn remote_authority_fallback(&self) -> Option<Fallback> {
+		if self.remote_authority_fallback.is_empty() {
+			return None
+		}
+		Some(Fallback {
+			secondary: Arc::new(AlwaysPermissionGrantedFactory {}),
+			duties: FallbackDuties {
+				slot: false,
+				round: false,
+				session: self.remote_authority_fallback.contains(&FallbackDuty::Session),
+			},
+			hysteresis: Duration::from_secs(self.remote_authority_fallback_hysteresis_secs),
+		})
+	}
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -190,13 +578,21 @@ impl CliConfiguration for RunCmd {
 	}
 
 	fn permission_resolver_factory(&self) -> Box<dyn PermissionResolverFactory> {
-		if self.remote_authority.is_empty() {
-			Box::new(AlwaysPermissionGrantedFactory {})
-		} else {
-			Box::new(RemoteAuthorityPermissionResolverFactory {
-				remote_urls: self.remote_authority.clone(),
-				cached: true,
-			})
+		if let Some(plugin) = self.permission_plugin_factory() {
+			return Box::new(plugin)
+		}
+		let mut factories = self.remote_authority_factories();
+		match factories.len() {
+			0 => Box::new(AlwaysPermissionGrantedFactory {}),
+			1 => Box::new(factories.remove(0)),
+			_ => Box::new(QuorumPermissionResolverFactory {
+				timeouts: factories[0].timeouts,
+				backends: factories
+					.into_iter()
+					.map(|factory| Box::new(factory) as Box<dyn PermissionResolverFactory>)
+					.collect(),
+				quorum: self.remote_authority_quorum(),
+			}),
 		}
 	}
 }
@@ -189,8 +189,13 @@ pub fn run() -> sc_cli::Result<()> {
 		},
 		None => {
 			let runner = cli.create_runner(&cli.run)?;
+			let remote_authority = cli.run.remote_authority_factories();
+			let quorum = cli.run.remote_authority_quorum();
+			let plugin = cli.run.permission_plugin_factory();
 			runner.run_node_until_exit(|config| async move {
-				service::new_full(config).map_err(sc_cli::Error::Service)
+				service::new_full(config, remote_authority, quorum, plugin)
+					.await
+					.map_err(sc_cli::Error::Service)
 			})
 		},
 	}
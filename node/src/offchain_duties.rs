@@ -0,0 +1,41 @@
+//! Claims of the named duties made by the offchain workers through the `duty-claims` host
+//! functions.
+
+use duty_claims::{BlockingDutyClaims, DutyClaimsExt};
+use permission_resolver::DutyClaims;
+use sc_client_api::execution_extensions::ExtensionsFactory;
+use sp_core::offchain::Capabilities;
+use sp_externalities::Extensions;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+/// Claims made on the runtime of the node. The offchain workers run on their own threads, so
+/// blocking them until the claim is resolved is fine.
+pub struct NodeDutyClaims {
+	/// Claims of the remote authority, every duty is granted to a node without replicas.
+	pub claims: Option<Arc<dyn DutyClaims>>,
+	pub runtime: Handle,
+}
+
+impl BlockingDutyClaims for NodeDutyClaims {
+	fn claim(&self, duty: &str, index: u64) -> bool {
+		match &self.claims {
+			Some(claims) => self.runtime.block_on(claims.claim(duty, index)),
+			None => true,
+		}
+	}
+}
+
+/// Provides the claims to the offchain calls, the block import and authoring get no
+/// capabilities and no claims.
+pub struct DutyClaimsExtensions(pub Arc<NodeDutyClaims>);
+
+impl ExtensionsFactory for DutyClaimsExtensions {
+	fn extensions_for(&self, capabilities: Capabilities) -> Extensions {
+		let mut extensions = Extensions::new();
+		if capabilities != Capabilities::none() {
+			extensions.register(DutyClaimsExt(self.0.clone()));
+		}
+		extensions
+	}
+}
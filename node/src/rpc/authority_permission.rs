@@ -0,0 +1,91 @@
+//! RPC telling which replica of this validator holds its duties.
+
+use std::sync::Arc;
+
+use jsonrpsee::{
+	core::{async_trait, Error as JsonRpseeError, RpcResult},
+	proc_macros::rpc,
+	types::error::{CallError, ErrorObject},
+};
+use permission_resolver::{Duty, DutyQuery, RoundIndex};
+use sc_rpc_api::DenyUnsafe;
+use serde::{Deserialize, Serialize};
+
+/// Replica holding a duty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DutyHolder {
+	/// Replica that claimed the duty, empty if it's unknown.
+	pub replica_id: String,
+	/// Grows with every change of the holder, `null` if unknown.
+	pub fencing_token: Option<u64>,
+	/// Time of the claim in milliseconds since the UNIX epoch, zero if unknown.
+	pub claimed_at_ms: u64,
+}
+
+impl From<permission_resolver::DutyHolder> for DutyHolder {
+	fn from(holder: permission_resolver::DutyHolder) -> Self {
+		DutyHolder {
+			replica_id: holder.replica_id,
+			fencing_token: holder.fencing_token,
+			claimed_at_ms: holder.claimed_at_ms,
+		}
+	}
+}
+
+/// Authority permission RPC methods, unsafe since every call reads the remote authority and the
+/// answers tell the replicas of the validator apart.
+#[rpc(server)]
+pub trait AuthorityPermissionApi {
+	/// Replica holding the block authoring permission of the slot.
+	#[method(name = "authorityPermission_slotHolder")]
+	async fn slot_holder(&self, slot: u64) -> RpcResult<Option<DutyHolder>>;
+
+	/// Replica holding the GRANDPA voting permission of the round of the authority set.
+	#[method(name = "authorityPermission_roundHolder")]
+	async fn round_holder(&self, set_id: u64, round: u64) -> RpcResult<Option<DutyHolder>>;
+
+	/// Replica holding the "I'm online" heartbeat permission of the session.
+	#[method(name = "authorityPermission_sessionHolder")]
+	async fn session_holder(&self, session_index: u32) -> RpcResult<Option<DutyHolder>>;
+}
+
+/// Provides the duty holders recorded by the remote authority.
+pub struct AuthorityPermission {
+	query: Arc<dyn DutyQuery>,
+	deny_unsafe: DenyUnsafe,
+}
+
+impl AuthorityPermission {
+	/// Create new `AuthorityPermission` with the given query.
+	pub fn new(query: Arc<dyn DutyQuery>, deny_unsafe: DenyUnsafe) -> Self {
+		Self { query, deny_unsafe }
+	}
+
+	async fn holder(&self, duty: Duty) -> RpcResult<Option<DutyHolder>> {
+		self.deny_unsafe.check_if_safe()?;
+		match self.query.holder(duty).await {
+			Ok(holder) => Ok(holder.map(Into::into)),
+			Err(e) => Err(JsonRpseeError::Call(CallError::Custom(ErrorObject::owned(
+				1,
+				"Could not query the remote authority",
+				Some(e.to_string()),
+			)))),
+		}
+	}
+}
+
+#[async_trait]
+impl AuthorityPermissionApiServer for AuthorityPermission {
+	async fn slot_holder(&self, slot: u64) -> RpcResult<Option<DutyHolder>> {
+		self.holder(Duty::Slot(slot)).await
+	}
+
+	async fn round_holder(&self, set_id: u64, round: u64) -> RpcResult<Option<DutyHolder>> {
+		self.holder(Duty::Round(RoundIndex { set_id, round })).await
+	}
+
+	async fn session_holder(&self, session_index: u32) -> RpcResult<Option<DutyHolder>> {
+		self.holder(Duty::Session(session_index)).await
+	}
+}
@@ -1,7 +1,14 @@
 //! Service and ServiceFactory implementation. Specialized wrapper over substrate service.
 
+use crate::offchain_duties::{DutyClaimsExtensions, NodeDutyClaims};
+use futures::StreamExt;
 use node_template_runtime::{self, opaque::Block, RuntimeApi};
-use sc_client_api::{BlockBackend, ExecutorProvider};
+use permission_resolver::{
+	majority, ClaimHistory, DutyClaims, DutyQuery, FactoryError, ForkView, KeyScope,
+	PluginPermissionResolverFactory, QuorumDutyClaims, QuorumPermissionResolverFactory,
+	RemoteAuthorityPermissionResolverFactory, RetryPolicy, SlotSchedule,
+};
+use sc_client_api::{BlockBackend, BlockchainEvents, ExecutorProvider};
 use sc_consensus_aura::{ImportQueueParams, SlotProportion, StartAuraParams};
 pub use sc_executor::NativeElseWasmExecutor;
 use sc_finality_grandpa::SharedVoterState;
@@ -10,9 +17,21 @@ use sc_service::{
 	error::Error as ServiceError, init_permission_resolver, Configuration, TaskManager,
 };
 use sc_telemetry::{Telemetry, TelemetryWorker};
-use sp_authority_permission::PermissionResolver;
-use sp_consensus_aura::sr25519::AuthorityPair as AuraPair;
-use std::{sync::Arc, time::Duration};
+use sp_api::ProvideRuntimeApi;
+use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
+use sp_blockchain::HeaderBackend;
+use sp_consensus_aura::{sr25519::AuthorityPair as AuraPair, AuraApi};
+use sp_core::{
+	crypto::{key_types, ByteArray},
+	H256,
+};
+use sp_keystore::{SyncCryptoStore, SyncCryptoStorePtr};
+use sp_runtime::generic::BlockId;
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::runtime::Handle;
 
 // Our native executor instance.
 pub struct ExecutorDispatch;
@@ -20,10 +39,11 @@ pub struct ExecutorDispatch;
 impl sc_executor::NativeExecutionDispatch for ExecutorDispatch {
 	/// Only enable the benchmarking host functions when we actually want to benchmark.
 	#[cfg(feature = "runtime-benchmarks")]
-	type ExtendHostFunctions = frame_benchmarking::benchmarking::HostFunctions;
-	/// Otherwise we only use the default Substrate host functions.
+	type ExtendHostFunctions =
+		(frame_benchmarking::benchmarking::HostFunctions, duty_claims::duty_claims::HostFunctions);
+	/// Otherwise we only add the duty claims to the default Substrate host functions.
 	#[cfg(not(feature = "runtime-benchmarks"))]
-	type ExtendHostFunctions = ();
+	type ExtendHostFunctions = duty_claims::duty_claims::HostFunctions;
 
 	fn dispatch(method: &str, data: &[u8]) -> Option<Vec<u8>> {
 		node_template_runtime::api::dispatch(method, data)
@@ -34,6 +54,13 @@ impl sc_executor::NativeExecutionDispatch for ExecutorDispatch {
 	}
 }
 
+/// Time between the GRANDPA gossip rounds.
+// FIXME #1578 make this available through chainspec
+const GRANDPA_GOSSIP_DURATION: Duration = Duration::from_millis(333);
+
+/// Minimal time between the prunings of the claim history.
+const CLAIM_HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
 pub(crate) type FullClient =
 	sc_service::TFullClient<Block, RuntimeApi, NativeElseWasmExecutor<ExecutorDispatch>>;
 type FullBackend = sc_service::TFullBackend<Block>;
@@ -159,8 +186,130 @@ fn remote_keystore(_url: &String) -> Result<Arc<LocalKeystore>, &'static str> {
 	Err("Remote Keystore not supported.")
 }
 
+/// Public key of the local Aura authority, or of the GRANDPA one if there is no Aura key.
+fn local_authority_public_key(keystore: &SyncCryptoStorePtr) -> Option<Vec<u8>> {
+	SyncCryptoStore::sr25519_public_keys(&**keystore, key_types::AURA)
+		.first()
+		.map(|key| key.0.to_vec())
+		.or_else(|| {
+			SyncCryptoStore::ed25519_public_keys(&**keystore, key_types::GRANDPA)
+				.first()
+				.map(|key| key.0.to_vec())
+		})
+}
+
+/// Chain of the local client. The parent of a slot is the chain head the slot worker built the
+/// inherent data of the slot on, and a block is orphaned once the finalized chain reached its
+/// number without it.
+struct ClientForkView {
+	client: Arc<FullClient>,
+	/// Latest slot of the slot worker along with the chain head it's built on.
+	slot_parent: Mutex<Option<(u64, H256)>>,
+}
+
+impl ClientForkView {
+	fn new(client: Arc<FullClient>) -> ClientForkView {
+		ClientForkView { client, slot_parent: Mutex::new(None) }
+	}
+
+	/// Records the chain head the slot worker builds the block of the slot on.
+	fn on_slot(&self, slot: u64, parent: H256) {
+		*self.slot_parent.lock().unwrap() = Some((slot, parent));
+	}
+}
+
+impl ForkView for ClientForkView {
+	fn parent(&self, slot: u64) -> Option<Vec<u8>> {
+		match *self.slot_parent.lock().unwrap() {
+			Some((on_slot, parent)) if on_slot == slot => Some(parent.as_ref().to_vec()),
+			_ => None,
+		}
+	}
+
+	fn is_orphaned(&self, block: &[u8]) -> bool {
+		if block.len() != H256::len_bytes() {
+			return false
+		}
+		let hash = H256::from_slice(block);
+		let finalized_number = self.client.info().finalized_number;
+		match self.client.number(hash) {
+			// the best chains of the replicas may differ until finalized
+			Ok(Some(number)) if number <= finalized_number =>
+				self.client.hash(number).ok().flatten() != Some(hash),
+			// not imported yet, the holder may be ahead of this replica
+			_ => false,
+		}
+	}
+}
+
+/// Slots of the local Aura authority, the authorities of the best block take the slots in turns.
+struct AuraSlotSchedule {
+	client: Arc<FullClient>,
+	keystore: SyncCryptoStorePtr,
+}
+
+impl SlotSchedule for AuraSlotSchedule {
+	fn next_slot(&self, slot: u64) -> Option<u64> {
+		let best = BlockId::Hash(self.client.info().best_hash);
+		let authorities = self.client.runtime_api().authorities(&best).ok()?;
+		let index = authorities.iter().position(|authority| {
+			SyncCryptoStore::has_keys(&*self.keystore, &[(authority.to_raw_vec(), key_types::AURA)])
+		})? as u64;
+		let count = authorities.len() as u64;
+		let next = slot + 1;
+		Some(next + (index + count - next % count) % count)
+	}
+}
+
+/// Prunes the claims of the finalized slots from the history, keeping the ones younger than the
+/// retention if given.
+async fn prune_claim_history(
+	client: Arc<FullClient>,
+	history: ClaimHistory,
+	slot_duration: Duration,
+	retention: Option<Duration>,
+) {
+	let mut finality_notifications = client.finality_notification_stream();
+	let mut last_pruned: Option<Instant> = None;
+	while let Some(notification) = finality_notifications.next().await {
+		if last_pruned.map_or(false, |at| at.elapsed() < CLAIM_HISTORY_PRUNE_INTERVAL) {
+			continue
+		}
+		// the genesis block has no slot
+		let slot = match sc_consensus_aura::find_pre_digest::<
+			Block,
+			sp_consensus_aura::sr25519::AuthoritySignature,
+		>(&notification.header)
+		{
+			Ok(slot) => slot,
+			Err(_) => continue,
+		};
+		last_pruned = Some(Instant::now());
+		let mut before_ms = u64::from(slot).saturating_mul(slot_duration.as_millis() as u64);
+		if let Some(retention) = retention {
+			let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+			before_ms = before_ms.min(now.saturating_sub(retention).as_millis() as u64);
+		}
+		match history.prune(before_ms).await {
+			Ok(pruned) => log::debug!(
+				target: "permission-resolver",
+				"Pruned {} claim(s) of the finalized slots from the history", pruned
+			),
+			Err(e) => log::warn!(
+				target: "permission-resolver",
+				"Could not prune the claim history: {}", e
+			),
+		}
+	}
+}
+
 /// Builds a new service for a full client.
-pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError> {
+pub async fn new_full(
+	mut config: Configuration,
+	remote_authority: Vec<RemoteAuthorityPermissionResolverFactory>,
+	quorum: usize,
+	plugin: Option<PluginPermissionResolverFactory>,
+) -> Result<TaskManager, ServiceError> {
 	let sc_service::PartialComponents {
 		client,
 		backend,
@@ -182,10 +331,9 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 				))),
 		};
 	}
-	let grandpa_protocol_name = sc_finality_grandpa::protocol_standard_name(
-		&client.block_hash(0).ok().flatten().expect("Genesis block exists; qed"),
-		&config.chain_spec,
-	);
+	let genesis_hash = client.block_hash(0).ok().flatten().expect("Genesis block exists; qed");
+	let grandpa_protocol_name =
+		sc_finality_grandpa::protocol_standard_name(&genesis_hash, &config.chain_spec);
 
 	config
 		.network
@@ -208,9 +356,110 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 			warp_sync: Some(warp_sync),
 		})?;
 
-	let permission_resolver: Arc<dyn PermissionResolver> = init_permission_resolver(&config);
+	let slot_duration = sc_consensus_aura::slot_duration(&*client)?;
+	let mut duty_query: Option<Arc<dyn DutyQuery>> = None;
+	let mut duty_claims: Option<Arc<dyn DutyClaims>> = None;
+	let mut fork_view: Option<Arc<ClientForkView>> = None;
+	let permission_resolver: Arc<dyn PermissionResolver> = if let Some(mut plugin) = plugin {
+		let authority_set = grandpa_link.shared_authority_set().clone();
+		plugin.authority_set_id = Some(Arc::new(move || authority_set.set_id()));
+		plugin.spawner = Some(Arc::new(task_manager.spawn_handle()));
+		Arc::from(plugin.create().await)
+	} else if remote_authority.is_empty() {
+		init_permission_resolver(&config)
+	} else {
+		if quorum < majority(remote_authority.len()) || quorum > remote_authority.len() {
+			return Err(ServiceError::Other(format!(
+				"Quorum of {} out of {} remote authority clusters isn't their majority or can't be \
+				reached",
+				quorum,
+				remote_authority.len(),
+			)))
+		}
+		let clusters = remote_authority.len();
+		let mut backends: Vec<Box<dyn PermissionResolverFactory>> = Vec::new();
+		let mut claims: Vec<Arc<dyn DutyClaims>> = Vec::new();
+		let mut timeouts = Default::default();
+		for (index, mut factory) in remote_authority.into_iter().enumerate() {
+			if !factory.legacy_keys {
+				let authority = local_authority_public_key(&keystore_container.sync_keystore())
+					.ok_or_else(|| {
+						ServiceError::Other(
+							"Remote authority needs an Aura or GRANDPA key in the keystore to \
+							scope its keys, use --remote-authority-legacy-keys to share flat keys"
+								.into(),
+						)
+					})?;
+				factory.scope =
+					Some(KeyScope { genesis_hash: genesis_hash.as_ref().to_vec(), authority });
+			}
+			let authority_set = grandpa_link.shared_authority_set().clone();
+			factory.authority_set_id = Some(Arc::new(move || authority_set.set_id()));
+			factory.retry_policy = RetryPolicy::within_slot(slot_duration.as_duration());
+			factory.slot_duration = Some(slot_duration.as_duration());
+			let slot_timeout = slot_duration.as_duration() / 3;
+			factory.timeouts.slot.get_or_insert(slot_timeout);
+			factory.timeouts.round.get_or_insert(GRANDPA_GOSSIP_DURATION);
+			factory.timeouts.session.get_or_insert(slot_timeout);
+			factory.timeouts.duty.get_or_insert(slot_timeout);
+			factory.prometheus_registry = config.prometheus_registry().cloned();
+			if clusters > 1 {
+				factory.cluster = Some(index.to_string());
+			}
+			factory.replica_id.get_or_insert_with(|| config.network.node_name.clone());
+			factory.node_version = config.impl_version.clone();
+			factory.spawner = Some(Arc::new(task_manager.spawn_handle()));
+			if factory.pre_claim {
+				factory.slot_schedule = Some(Arc::new(AuraSlotSchedule {
+					client: client.clone(),
+					keystore: keystore_container.sync_keystore(),
+				}));
+			}
+			if factory.fork_aware {
+				let view =
+					fork_view.get_or_insert_with(|| Arc::new(ClientForkView::new(client.clone())));
+				factory.fork_view = Some(view.clone());
+			}
+			let invalid = |e: FactoryError| ServiceError::Other(e.to_string());
+			factory.validate().map_err(invalid)?;
+			claims.push(factory.create_duty_claims().map_err(invalid)?);
+			// every cluster keeps the history of its own claims
+			if let Some(history) = factory.create_history().map_err(invalid)? {
+				task_manager.spawn_handle().spawn(
+					"remote-authority-claim-history",
+					None,
+					prune_claim_history(
+						client.clone(),
+						history,
+						slot_duration.as_duration(),
+						factory.history_retention,
+					),
+				);
+			}
+			// the holders are looked up on the first cluster alone
+			if index == 0 {
+				duty_query = Some(Arc::from(factory.create_query().map_err(invalid)?));
+				timeouts = factory.timeouts;
+			}
+			backends.push(Box::new(factory));
+		}
+		if backends.len() == 1 {
+			duty_claims = claims.pop();
+			Arc::from(backends.remove(0).create().await)
+		} else {
+			duty_claims =
+				Some(Arc::new(QuorumDutyClaims::new(claims, quorum).with_timeout(timeouts.duty)));
+			let factory = QuorumPermissionResolverFactory { backends, quorum, timeouts };
+			Arc::from(factory.create().await)
+		}
+	};
 
 	if config.offchain_worker.enabled {
+		let duty_claims =
+			Arc::new(NodeDutyClaims { claims: duty_claims, runtime: Handle::current() });
+		client
+			.execution_extensions()
+			.set_extensions_factory(Box::new(DutyClaimsExtensions(duty_claims)));
 		sc_service::build_offchain_workers(
 			&config,
 			task_manager.spawn_handle(),
@@ -232,8 +481,12 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 		let pool = transaction_pool.clone();
 
 		Box::new(move |deny_unsafe, _| {
-			let deps =
-				crate::rpc::FullDeps { client: client.clone(), pool: pool.clone(), deny_unsafe };
+			let deps = crate::rpc::FullDeps {
+				client: client.clone(),
+				pool: pool.clone(),
+				deny_unsafe,
+				duty_query: duty_query.clone(),
+			};
 			crate::rpc::create_full(deps).map_err(Into::into)
 		})
 	};
@@ -263,8 +516,6 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 		let can_author_with =
 			sp_consensus::CanAuthorWithNativeVersion::new(client.executor().clone());
 
-		let slot_duration = sc_consensus_aura::slot_duration(&*client)?;
-
 		let aura = sc_consensus_aura::start_aura::<AuraPair, _, _, _, _, _, _, _, _, _, _, _>(
 			StartAuraParams {
 				slot_duration,
@@ -272,16 +523,23 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 				select_chain,
 				block_import,
 				proposer_factory,
-				create_inherent_data_providers: move |_, ()| async move {
-					let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
-
-					let slot =
-						sp_consensus_aura::inherents::InherentDataProvider::from_timestamp_and_slot_duration(
-							*timestamp,
-							slot_duration,
-						);
-
-					Ok((timestamp, slot))
+				create_inherent_data_providers: move |parent, ()| {
+					let fork_view = fork_view.clone();
+					async move {
+						let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
+
+						let slot =
+							sp_consensus_aura::inherents::InherentDataProvider::from_timestamp_and_slot_duration(
+								*timestamp,
+								slot_duration,
+							);
+						// the slot is claimed on the chain head the slot worker builds on
+						if let Some(fork_view) = &fork_view {
+							fork_view.on_slot(u64::from(*slot), parent);
+						}
+
+						Ok((timestamp, slot))
+					}
 				},
 				force_authoring,
 				backoff_authoring_blocks,
@@ -310,8 +568,7 @@ pub fn new_full(mut config: Configuration) -> Result<TaskManager, ServiceError>
 			if role.is_authority() { Some(keystore_container.sync_keystore()) } else { None };
 
 		let grandpa_config = sc_finality_grandpa::Config {
-			// FIXME #1578 make this available through chainspec
-			gossip_duration: Duration::from_millis(333),
+			gossip_duration: GRANDPA_GOSSIP_DURATION,
 			justification_period: 512,
 			name: Some(name),
 			observer_enabled: false,
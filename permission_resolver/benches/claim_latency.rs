@@ -0,0 +1,136 @@
+//! Latency of the slot claims made with the optimistic and pessimistic transactions and with the
+//! RawKV compare-and-swap, against the TiKV cluster of the PD given in `TIKV_PD` (`127.0.0.1:2379`
+//! by default):
+//!
+//! ```sh
+//! TIKV_PD=127.0.0.1:2379 cargo bench -p permission-resolver --bench claim_latency
+//! ```
+//!
+//! The keys are scoped to a random genesis hash, so the claims of the running validators are
+//! left alone.
+
+use permission_resolver::{KeyScope, RemoteAuthorityPermissionResolverFactory};
+use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
+use std::time::{Duration, Instant};
+
+/// Claims measured in every scenario.
+const CLAIMS: u64 = 1000;
+/// Time given to the replicas to connect to the cluster.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn factory(
+	pd: &str,
+	scope: &KeyScope,
+	replica_id: &str,
+	mode: &str,
+) -> RemoteAuthorityPermissionResolverFactory {
+	RemoteAuthorityPermissionResolverFactory {
+		remote_urls: vec![pd.to_owned()],
+		discovery_refresh: Duration::from_secs(30),
+		cached: false,
+		tls: None,
+		legacy_keys: false,
+		scope: Some(scope.clone()),
+		legacy_values: false,
+		replica_id: Some(replica_id.to_owned()),
+		node_version: env!("CARGO_PKG_VERSION").to_owned(),
+		authority_set_id: None,
+		fail_policies: Default::default(),
+		retry_policy: Default::default(),
+		timeouts: Default::default(),
+		prometheus_registry: None,
+		cluster: None,
+		slot_duration: None,
+		max_slot_drift: 0,
+		clear_skewed_slots: false,
+		lease: false,
+		history: false,
+		history_retention: None,
+		compare_and_swap: mode == "cas",
+		pessimistic: mode == "pessimistic",
+		priority: None,
+		pre_claim: false,
+		slot_schedule: None,
+		fork_aware: false,
+		fork_view: None,
+		shadow: None,
+		fallback: None,
+		spawner: None,
+		clients: Default::default(),
+	}
+}
+
+/// Claims slots until the first one is granted, the replicas connect in the background.
+async fn connected(replica: &dyn PermissionResolver, slot: &mut u64) {
+	let started = Instant::now();
+	loop {
+		*slot += 1;
+		if replica.resolve_slot((*slot).into()).await {
+			return
+		}
+		assert!(started.elapsed() < CONNECT_TIMEOUT, "Could not claim a slot, is TiKV running?");
+		tokio::time::sleep(Duration::from_millis(100)).await;
+	}
+}
+
+async fn timed_claim(replica: &dyn PermissionResolver, slot: u64) -> (bool, Duration) {
+	let started = Instant::now();
+	let granted = replica.resolve_slot(slot.into()).await;
+	(granted, started.elapsed())
+}
+
+fn report(mode: &str, scenario: &str, mut latencies: Vec<Duration>) {
+	latencies.sort();
+	let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+	let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+	println!(
+		"{:<12} {:<12} mean {:>10.3?} p50 {:>10.3?} p99 {:>10.3?} max {:>10.3?}",
+		mode,
+		scenario,
+		mean,
+		percentile(50),
+		percentile(99),
+		percentile(100),
+	);
+}
+
+async fn bench(pd: &str, mode: &str) {
+	let scope = KeyScope {
+		genesis_hash: rand::random::<[u8; 32]>().to_vec(),
+		authority: b"claim-latency".to_vec(),
+	};
+	let alice = factory(pd, &scope, "alice", mode).create().await;
+	let bob = factory(pd, &scope, "bob", mode).create().await;
+	let mut slot = 0;
+	connected(&*alice, &mut slot).await;
+	connected(&*bob, &mut slot).await;
+
+	// a single replica claiming every slot
+	let mut latencies = Vec::new();
+	for _ in 0..CLAIMS {
+		slot += 1;
+		let started = Instant::now();
+		assert!(alice.resolve_slot(slot.into()).await);
+		latencies.push(started.elapsed());
+	}
+	report(mode, "uncontended", latencies);
+
+	// both replicas racing for every slot
+	let mut latencies = Vec::new();
+	for _ in 0..CLAIMS {
+		slot += 1;
+		let ((alice_won, alice_latency), (bob_won, bob_latency)) =
+			tokio::join!(timed_claim(&*alice, slot), timed_claim(&*bob, slot));
+		assert!(alice_won ^ bob_won, "Exactly one replica has to win slot {}", slot);
+		latencies.extend([alice_latency, bob_latency]);
+	}
+	report(mode, "contended", latencies);
+}
+
+#[tokio::main]
+async fn main() {
+	let pd = std::env::var("TIKV_PD").unwrap_or_else(|_| "127.0.0.1:2379".to_owned());
+	for mode in ["optimistic", "pessimistic", "cas"] {
+		bench(&pd, mode).await;
+	}
+}
@@ -1,17 +1,25 @@
+use crate::{
+	claim::{AuthoritySetIdProvider, RoundIndex},
+	duty::DutyClaims,
+};
 use async_trait::async_trait;
 use sp_authority_permission::PermissionResolver;
 use sp_consensus_slots::Slot;
-use std::sync::Mutex;
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
 
-type Cache = Mutex<Option<(u64, bool)>>;
+type Cache<V = u64> = Mutex<Option<(V, bool)>>;
 
 /// Cache for permission resolver. It's holds vales for the last requests of the slot/round/session.
 /// This prevent from frequently requesting of the permission resolver.
 pub struct PermissionResolverCache {
 	resolver: Box<dyn PermissionResolver>,
 	slot: Cache,
-	round: Cache,
+	round: Cache<RoundIndex>,
 	session: Cache,
+	authority_set_id: Arc<dyn AuthoritySetIdProvider>,
 }
 
 impl PermissionResolverCache {
@@ -22,11 +30,22 @@ impl PermissionResolverCache {
 			slot: Mutex::new(None),
 			round: Mutex::new(None),
 			session: Mutex::new(None),
+			authority_set_id: Arc::new(|| 0),
 		}
 	}
 
+	/// Key the cached rounds by the given GRANDPA authority set id, so that a round of a new set
+	/// is not answered with the permission of the same round of the previous set.
+	pub fn with_authority_set_id(
+		mut self,
+		authority_set_id: Arc<dyn AuthoritySetIdProvider>,
+	) -> PermissionResolverCache {
+		self.authority_set_id = authority_set_id;
+		self
+	}
+
 	/// Check the permission of the slot/round/session.
-	fn check_permission(cache: &Cache, value: u64) -> Option<bool> {
+	fn check_permission<V: PartialEq + Copy>(cache: &Cache<V>, value: V) -> Option<bool> {
 		if let Some(v) = *cache.lock().unwrap() {
 			if v.0 == value {
 				return Some(v.1.clone())
@@ -36,7 +55,7 @@ impl PermissionResolverCache {
 	}
 
 	/// Set the cached permission of the slot/round/session.
-	fn set_permission(cache: &Cache, value: u64, permission: bool) {
+	fn set_permission<V>(cache: &Cache<V>, value: V, permission: bool) {
 		*cache.lock().unwrap() = Some((value, permission));
 	}
 }
@@ -55,14 +74,13 @@ impl PermissionResolver for PermissionResolverCache {
 	}
 
 	async fn resolve_round(&self, round: u64) -> bool {
-		if let Some(permission) =
-			PermissionResolverCache::check_permission(&self.round, round.into())
-		{
+		let index = RoundIndex { set_id: self.authority_set_id.set_id(), round };
+		if let Some(permission) = PermissionResolverCache::check_permission(&self.round, index) {
 			return permission
 		}
 
 		let permission = self.resolver.resolve_round(round).await;
-		PermissionResolverCache::set_permission(&self.round, round.clone(), permission.clone());
+		PermissionResolverCache::set_permission(&self.round, index, permission);
 		permission
 	}
 
@@ -83,6 +101,70 @@ impl PermissionResolver for PermissionResolverCache {
 	}
 }
 
+/// Most duties the claims are cached for, the duties claimed longest ago are evicted beyond it.
+const MAX_CACHED_DUTIES: usize = 1024;
+
+/// Claim cached for a duty, along with when it was asked for last.
+struct CachedClaim {
+	index: u64,
+	permission: bool,
+	used: u64,
+}
+
+#[derive(Default)]
+struct CachedClaims {
+	claims: HashMap<String, CachedClaim>,
+	/// Number of claims asked for so far.
+	uses: u64,
+}
+
+/// Cache of the claims of the named duties, holding the last claimed index of each duty. The names
+/// come from the runtime, so the duties asked for longest ago are evicted beyond
+/// `MAX_CACHED_DUTIES`.
+pub struct DutyClaimsCache {
+	claims: Box<dyn DutyClaims>,
+	last: Mutex<CachedClaims>,
+}
+
+impl DutyClaimsCache {
+	pub fn new(claims: Box<dyn DutyClaims>) -> DutyClaimsCache {
+		DutyClaimsCache { claims, last: Mutex::new(CachedClaims::default()) }
+	}
+}
+
+#[async_trait]
+impl DutyClaims for DutyClaimsCache {
+	async fn claim(&self, duty: &str, index: u64) -> bool {
+		{
+			let mut last = self.last.lock().unwrap();
+			last.uses += 1;
+			let uses = last.uses;
+			if let Some(cached) = last.claims.get_mut(duty) {
+				cached.used = uses;
+				if cached.index == index {
+					return cached.permission
+				}
+			}
+		}
+
+		let permission = self.claims.claim(duty, index).await;
+		let mut last = self.last.lock().unwrap();
+		if last.claims.len() >= MAX_CACHED_DUTIES && !last.claims.contains_key(duty) {
+			let evicted = last
+				.claims
+				.iter()
+				.min_by_key(|(_, cached)| cached.used)
+				.map(|(duty, _)| duty.clone());
+			if let Some(evicted) = evicted {
+				last.claims.remove(&evicted);
+			}
+		}
+		let used = last.uses;
+		last.claims.insert(duty.to_owned(), CachedClaim { index, permission, used });
+		permission
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -174,6 +256,31 @@ mod tests {
 		assert_eq!(counters.lock().unwrap().round, 2);
 	}
 
+	#[tokio::test]
+	async fn test_permission_resolver_cache_round_after_set_change() {
+		let counters = Arc::new(Mutex::new(PermissionCounters { slot: 0, round: 0, session: 0 }));
+		let set_id = Arc::new(Mutex::new(0));
+		let provider = {
+			let set_id = set_id.clone();
+			Arc::new(move || *set_id.lock().unwrap())
+		};
+		let resolver =
+			PermissionResolverCache::new(Box::new(PermissionResolverMock::new(counters.clone())))
+				.with_authority_set_id(provider);
+
+		resolver.resolve_round(1).await;
+		assert_eq!(counters.lock().unwrap().round, 1);
+
+		// Same round number of the next set, should be forwarded to mock.
+		*set_id.lock().unwrap() = 1;
+		resolver.resolve_round(1).await;
+		assert_eq!(counters.lock().unwrap().round, 2);
+
+		// Re-request within the same set, should call cache instead of mock.
+		resolver.resolve_round(1).await;
+		assert_eq!(counters.lock().unwrap().round, 2);
+	}
+
 	#[tokio::test]
 	async fn test_permission_resolver_cache_session() {
 		let counters = Arc::new(Mutex::new(PermissionCounters { slot: 0, round: 0, session: 0 }));
@@ -198,4 +305,54 @@ mod tests {
 		assert!(permission);
 		assert_eq!(counters.lock().unwrap().session, 2);
 	}
+
+	struct DutyClaimsMock {
+		calls: Arc<Mutex<Vec<(String, u64)>>>,
+	}
+
+	#[async_trait]
+	impl DutyClaims for DutyClaimsMock {
+		async fn claim(&self, duty: &str, index: u64) -> bool {
+			self.calls.lock().unwrap().push((duty.to_owned(), index));
+			true
+		}
+	}
+
+	#[tokio::test]
+	async fn test_duty_claims_cache() {
+		let calls = Arc::new(Mutex::new(Vec::new()));
+		let claims = DutyClaimsCache::new(Box::new(DutyClaimsMock { calls: calls.clone() }));
+
+		assert!(claims.claim("price-feed", 1).await);
+		assert!(claims.claim("price-feed", 1).await);
+		assert!(claims.claim("indexer", 1).await);
+		assert!(claims.claim("price-feed", 2).await);
+		assert_eq!(
+			*calls.lock().unwrap(),
+			vec![
+				("price-feed".to_owned(), 1),
+				("indexer".to_owned(), 1),
+				("price-feed".to_owned(), 2)
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_duty_claims_cache_evicts_duties_asked_for_longest_ago() {
+		let calls = Arc::new(Mutex::new(Vec::new()));
+		let claims = DutyClaimsCache::new(Box::new(DutyClaimsMock { calls: calls.clone() }));
+
+		assert!(claims.claim("price-feed", 1).await);
+		for job in 0..MAX_CACHED_DUTIES {
+			assert!(claims.claim(&format!("job-{}", job), 1).await);
+			// kept by being asked for again
+			assert!(claims.claim("price-feed", 1).await);
+		}
+		assert_eq!(claims.last.lock().unwrap().claims.len(), MAX_CACHED_DUTIES);
+		calls.lock().unwrap().clear();
+
+		assert!(claims.claim("price-feed", 1).await);
+		assert!(claims.claim("job-0", 1).await);
+		assert_eq!(*calls.lock().unwrap(), vec![("job-0".to_owned(), 1)]);
+	}
 }
@@ -0,0 +1,258 @@
+use crate::{
+	claim::ClaimValue, retry::Attempt, ConnectionState, Key, RemoteAuthorityPermissionResolver,
+	ResolveError, TiKVRawClient,
+};
+use log::debug;
+use std::{collections::HashMap, sync::Mutex};
+use tikv_client::Value;
+
+/// Most stored claims remembered, beyond it they're forgotten and read again by the next claims.
+const MAX_SEEN: usize = 1024;
+
+/// RawKV client along with the stored claims it saw last, so that an uncontended claim is swapped
+/// in without reading the stored claim first.
+pub(crate) struct CasClient {
+	pub client: Box<dyn TiKVRawClient>,
+	seen: Mutex<HashMap<String, Option<Value>>>,
+}
+
+impl CasClient {
+	pub fn new(client: Box<dyn TiKVRawClient>) -> CasClient {
+		CasClient { client, seen: Mutex::new(HashMap::new()) }
+	}
+
+	fn seen(&self, path: &str) -> Option<Value> {
+		self.seen.lock().unwrap().get(path).cloned().flatten()
+	}
+
+	fn see(&self, path: &str, stored: Option<Value>) {
+		let mut seen = self.seen.lock().unwrap();
+		// the named duties come and go
+		if seen.len() >= MAX_SEEN && !seen.contains_key(path) {
+			seen.clear();
+		}
+		seen.insert(path.to_owned(), stored);
+	}
+
+	/// The outcome of a failed request is unknown, the stored claim is read again by the next
+	/// claim.
+	fn forget(&self, path: &str) {
+		self.seen.lock().unwrap().remove(path);
+	}
+}
+
+impl RemoteAuthorityPermissionResolver {
+	/// Swaps the claim in place of the stored claim seen last, in a single round trip unless
+	/// another replica claimed in the meantime. A failed swap returns the stored claim, which is
+	/// checked like in a transaction and swapped against again, so the strictly greater index
+	/// still wins. Denials based on the claim seen last are confirmed with a read, the key may
+	/// have been cleared by hand since.
+	pub(crate) async fn do_compare_and_swap<V: ClaimValue>(
+		&self,
+		cas: &CasClient,
+		key: &Key,
+		value: V,
+		latest_plausible: Option<V>,
+		parent: Option<&[u8]>,
+		attempt: &mut Attempt,
+	) -> Result<bool, ResolveError> {
+		let deadline = attempt.deadline;
+		debug!(target: "permission-resolver", "Checking {} {} permission...", key.as_str(), value);
+		if cas.client.connection_state() == ConnectionState::Connecting {
+			return Err(ResolveError::Disconnected)
+		}
+		let path = self.namespace.key(key.as_str());
+		let mut stored = cas.seen(&path);
+		let mut confirmed = false;
+		loop {
+			let checked =
+				self.check_claim(key, value, latest_plausible, parent, stored.clone(), attempt);
+			if checked.is_err() {
+				cas.forget(&path);
+			}
+			let record = checked?;
+			if record.is_none() && confirmed {
+				self.contention.record(false);
+				return Ok(false)
+			}
+			let record = match record {
+				Some(record) => record,
+				None => {
+					stored = match deadline.run(cas.client.get(path.clone())).await {
+						Ok(Ok(stored)) => stored,
+						Ok(Err(source)) => {
+							cas.forget(&path);
+							return Err(ResolveError::Read { key: key.as_str().to_owned(), source })
+						},
+						Err(e) => {
+							cas.forget(&path);
+							return Err(e)
+						},
+					};
+					cas.see(&path, stored.clone());
+					confirmed = true;
+					continue
+				},
+			};
+			let claim = self.claim_value(&record);
+			let swap = cas.client.compare_and_swap(path.clone(), stored.clone(), claim.clone());
+			let (previous, swapped) = match deadline.run(swap).await {
+				Ok(Ok(swap)) => swap,
+				Ok(Err(source)) => {
+					cas.forget(&path);
+					attempt.unconfirmed = self.own_claim_value(&record);
+					return Err(ResolveError::Write { key: key.as_str().to_owned(), source })
+				},
+				Err(e) => {
+					// the swap may still land
+					cas.forget(&path);
+					attempt.unconfirmed = self.own_claim_value(&record);
+					return Err(e)
+				},
+			};
+			if swapped {
+				cas.see(&path, Some(claim));
+				self.contention.record(true);
+				debug!(
+					target: "permission-resolver",
+					"Granted {} {} permission with fencing token {}",
+					key.as_str(),
+					value,
+					record.fencing_token,
+				);
+				return Ok(true)
+			}
+			cas.see(&path, previous.clone());
+			stored = previous;
+			confirmed = true;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		query::TiKVDutyQuery, testing::InMemoryTiKVClient, Backend, Duty, DutyQuery, KeyNamespace,
+		RetryPolicy, TryPermissionResolver,
+	};
+	use sp_authority_permission::PermissionResolver;
+	use std::{sync::atomic::Ordering, time::Duration};
+
+	async fn replica(
+		client: &InMemoryTiKVClient,
+		replica_id: &str,
+	) -> RemoteAuthorityPermissionResolver {
+		let cas = CasClient::new(Box::new(client.clone()));
+		RemoteAuthorityPermissionResolver::with_backend(
+			Backend::CompareAndSwap(cas),
+			KeyNamespace::Legacy,
+		)
+		.with_replica(replica_id.to_owned(), "4.0.0-dev".to_owned())
+	}
+
+	#[tokio::test]
+	async fn test_grants_only_greater_slots() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(!alice.resolve_slot(5.into()).await);
+		assert!(!alice.resolve_slot(4.into()).await);
+		assert!(alice.resolve_slot(6.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_uncontended_claim_takes_single_request() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		client.raw_requests.store(0, Ordering::SeqCst);
+		assert!(alice.resolve_slot(6.into()).await);
+		assert_eq!(client.raw_requests.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_swaps_against_claim_of_other_replica() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		let bob = replica(&client, "bob").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(6.into()).await);
+		assert!(!alice.resolve_slot(6.into()).await);
+		assert!(alice.resolve_slot(7.into()).await);
+
+		let query = TiKVDutyQuery {
+			backend: Backend::CompareAndSwap(CasClient::new(Box::new(client.clone()))),
+			namespace: KeyNamespace::Legacy,
+			lease: false,
+		};
+		let holder = query.holder(Duty::Slot(7)).await.unwrap().unwrap();
+		assert_eq!(holder.replica_id, "alice");
+		assert_eq!(holder.fencing_token, Some(3));
+	}
+
+	#[tokio::test]
+	async fn test_only_one_replica_wins_race() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		let bob = replica(&client, "bob").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(6.into()).await);
+
+		let (alice_won, bob_won) =
+			tokio::join!(alice.resolve_slot(7.into()), bob.resolve_slot(7.into()));
+		assert!(alice_won ^ bob_won);
+	}
+
+	#[tokio::test]
+	async fn test_confirms_denial_with_read() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		assert!(alice.resolve_slot(10.into()).await);
+		client.data.lock().unwrap().clear();
+		assert!(alice.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_reports_corrupted_value() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(Key::SLOT.as_str().to_owned(), b"garbage".to_vec());
+		assert!(matches!(
+			alice.try_resolve_slot(5.into()).await,
+			Err(ResolveError::Corrupt { key, .. }) if key == "slot"
+		));
+
+		client.data.lock().unwrap().clear();
+		assert!(alice.try_resolve_slot(5.into()).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_grants_claim_swapped_before_request_failed() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await.with_retry_policy(RetryPolicy {
+			min_backoff: Duration::from_millis(1),
+			max_backoff: Duration::from_millis(1),
+			deadline: Duration::from_secs(1),
+		});
+		let bob = replica(&client, "bob").await;
+		client.lost_replies.store(1, Ordering::SeqCst);
+		assert!(alice.try_resolve_slot(5.into()).await.unwrap());
+		assert!(!bob.resolve_slot(5.into()).await);
+		assert!(!alice.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_unavailable_backend_fails() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(alice.try_resolve_slot(5.into()).await.is_err());
+		client.unavailable.store(false, Ordering::SeqCst);
+		assert!(alice.resolve_slot(5.into()).await);
+	}
+}
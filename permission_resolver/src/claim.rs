@@ -0,0 +1,349 @@
+use codec::{Decode, DecodeAll, Encode};
+use std::{
+	fmt,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Prefix of the claim records, telling them apart from the raw values written before.
+const RECORD_PREFIX: &[u8] = b"claim";
+
+/// Index stored under a permission key. Claims only move forward, so a new index is granted only
+/// if it is strictly greater than the stored one.
+pub(crate) trait ClaimValue:
+	Ord + Copy + fmt::Display + Encode + Decode + Send + Sync
+{
+	fn serialize(&self) -> Vec<u8>;
+	/// Decodes the value, failing with the reason if it isn't a well-formed index.
+	fn deserialize(value: &[u8]) -> Result<Self, &'static str>;
+	/// Part of the history key, sorting in the order of the indexes.
+	fn history_key(&self) -> String;
+}
+
+impl ClaimValue for u64 {
+	fn serialize(&self) -> Vec<u8> {
+		u64::to_be_bytes(*self).to_vec()
+	}
+
+	fn deserialize(value: &[u8]) -> Result<Self, &'static str> {
+		<[u8; 8]>::try_from(value)
+			.map(u64::from_be_bytes)
+			.map_err(|_| "expected 8 bytes")
+	}
+
+	fn history_key(&self) -> String {
+		format!("{:020}", self)
+	}
+}
+
+/// Source of the id of the current GRANDPA authority set.
+pub trait AuthoritySetIdProvider: Send + Sync {
+	fn set_id(&self) -> u64;
+}
+
+impl<F: Fn() -> u64 + Send + Sync> AuthoritySetIdProvider for F {
+	fn set_id(&self) -> u64 {
+		self()
+	}
+}
+
+/// GRANDPA round within an authority set. Round numbers restart with every set change, so rounds
+/// are ordered by the set id first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct RoundIndex {
+	pub set_id: u64,
+	pub round: u64,
+}
+
+impl fmt::Display for RoundIndex {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} (set {})", self.round, self.set_id)
+	}
+}
+
+impl ClaimValue for RoundIndex {
+	fn serialize(&self) -> Vec<u8> {
+		let mut value = u64::to_be_bytes(self.set_id).to_vec();
+		value.extend_from_slice(&u64::to_be_bytes(self.round));
+		value
+	}
+
+	/// Values written before rounds were aware of the set id hold only the round number, these
+	/// are treated as rounds of the set 0.
+	fn deserialize(value: &[u8]) -> Result<Self, &'static str> {
+		match value.len() {
+			8 => Ok(RoundIndex { set_id: 0, round: u64::deserialize(value)? }),
+			16 => Ok(RoundIndex {
+				set_id: u64::deserialize(&value[..8])?,
+				round: u64::deserialize(&value[8..])?,
+			}),
+			_ => Err("expected 8 or 16 bytes"),
+		}
+	}
+
+	fn history_key(&self) -> String {
+		format!("{:020}/{:020}", self.set_id, self.round)
+	}
+}
+
+/// Claim of a duty along with the replica that made it.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct ClaimRecord<V> {
+	/// Claimed slot, round or session.
+	pub index: V,
+	/// Replica that made the claim, empty for the raw values written before the records.
+	pub replica_id: String,
+	/// Wall-clock time of the claim in milliseconds since the UNIX epoch, zero if unknown.
+	pub timestamp_ms: u64,
+	/// Version of the node that made the claim, empty if unknown.
+	pub node_version: String,
+	/// Incremented with every claim of the duty, zero if unknown.
+	pub fencing_token: u64,
+	/// Hashes of the blocks the slot was claimed to be built on in the fork-aware mode, the last
+	/// one is built on by the holder. Empty if not known.
+	pub parents: Vec<Vec<u8>>,
+}
+
+/// Claim record written before the fencing tokens.
+#[derive(Encode, Decode)]
+struct ClaimRecordV1<V> {
+	index: V,
+	replica_id: String,
+	timestamp_ms: u64,
+	node_version: String,
+}
+
+/// Claim record without the parents, still written outside of the fork-aware mode so that the
+/// replicas that don't know the parents can read it.
+#[derive(Encode, Decode)]
+struct ClaimRecordV2<V> {
+	index: V,
+	replica_id: String,
+	timestamp_ms: u64,
+	node_version: String,
+	fencing_token: u64,
+}
+
+#[derive(Encode, Decode)]
+enum VersionedClaimRecord<V> {
+	#[codec(index = 1)]
+	V1(ClaimRecordV1<V>),
+	#[codec(index = 2)]
+	V2(ClaimRecordV2<V>),
+	#[codec(index = 3)]
+	V3(ClaimRecord<V>),
+}
+
+impl<V: ClaimValue> ClaimRecord<V> {
+	/// Record of a claim made now.
+	pub(crate) fn new(
+		index: V,
+		replica_id: &str,
+		node_version: &str,
+		fencing_token: u64,
+	) -> ClaimRecord<V> {
+		let timestamp_ms = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+		ClaimRecord {
+			index,
+			replica_id: replica_id.to_owned(),
+			timestamp_ms,
+			node_version: node_version.to_owned(),
+			fencing_token,
+			parents: Vec::new(),
+		}
+	}
+
+	/// Records the block the slot is claimed to be built on, after the ones it was claimed on
+	/// before.
+	pub(crate) fn on_parent(mut self, mut parents: Vec<Vec<u8>>, parent: &[u8]) -> ClaimRecord<V> {
+		parents.push(parent.to_vec());
+		self.parents = parents;
+		self
+	}
+
+	pub(crate) fn serialize(&self) -> Vec<u8> {
+		let mut value = RECORD_PREFIX.to_vec();
+		let record = if self.parents.is_empty() {
+			VersionedClaimRecord::V2(ClaimRecordV2 {
+				index: self.index,
+				replica_id: self.replica_id.clone(),
+				timestamp_ms: self.timestamp_ms,
+				node_version: self.node_version.clone(),
+				fencing_token: self.fencing_token,
+			})
+		} else {
+			VersionedClaimRecord::V3(self.clone())
+		};
+		record.encode_to(&mut value);
+		value
+	}
+
+	/// Raw values written before the records are read as records of unknown replicas, they are
+	/// replaced by records with the next claims.
+	pub(crate) fn deserialize(value: &[u8]) -> Result<ClaimRecord<V>, &'static str> {
+		let mut encoded = match value.strip_prefix(RECORD_PREFIX) {
+			Some(encoded) => encoded,
+			None =>
+				return Ok(ClaimRecord {
+					index: V::deserialize(value)?,
+					replica_id: String::new(),
+					timestamp_ms: 0,
+					node_version: String::new(),
+					fencing_token: 0,
+					parents: Vec::new(),
+				}),
+		};
+		if !matches!(encoded.first(), Some(1..=3)) {
+			return Err("unsupported claim record version")
+		}
+		match VersionedClaimRecord::decode_all(&mut encoded) {
+			Ok(VersionedClaimRecord::V1(record)) => Ok(ClaimRecord {
+				index: record.index,
+				replica_id: record.replica_id,
+				timestamp_ms: record.timestamp_ms,
+				node_version: record.node_version,
+				fencing_token: 0,
+				parents: Vec::new(),
+			}),
+			Ok(VersionedClaimRecord::V2(record)) => Ok(ClaimRecord {
+				index: record.index,
+				replica_id: record.replica_id,
+				timestamp_ms: record.timestamp_ms,
+				node_version: record.node_version,
+				fencing_token: record.fencing_token,
+				parents: Vec::new(),
+			}),
+			Ok(VersionedClaimRecord::V3(record)) => Ok(record),
+			Err(_) => Err("malformed claim record"),
+		}
+	}
+}
+
+impl<V: fmt::Display> fmt::Display for ClaimRecord<V> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.replica_id.is_empty() {
+			write!(f, "{} by unknown replica", self.index)
+		} else {
+			write!(
+				f,
+				"{} by {} ({}) at {} ms",
+				self.index, self.replica_id, self.node_version, self.timestamp_ms
+			)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_round_index_roundtrip() {
+		let index = RoundIndex { set_id: 3, round: 42 };
+		assert_eq!(RoundIndex::deserialize(&index.serialize()), Ok(index));
+	}
+
+	#[test]
+	fn test_round_index_reads_legacy_value() {
+		let index = RoundIndex::deserialize(&u64::to_be_bytes(42));
+		assert_eq!(index, Ok(RoundIndex { set_id: 0, round: 42 }));
+	}
+
+	#[test]
+	fn test_claim_record_roundtrip() {
+		let record =
+			ClaimRecord::new(RoundIndex { set_id: 1, round: 7 }, "alice-1", "4.0.0-dev", 3);
+		assert!(record.timestamp_ms > 0);
+		assert_eq!(ClaimRecord::deserialize(&record.serialize()), Ok(record));
+	}
+
+	#[test]
+	fn test_claim_record_reads_raw_value() {
+		let record = ClaimRecord::<u64>::deserialize(&u64::to_be_bytes(42)).unwrap();
+		assert_eq!(record.index, 42);
+		assert!(record.replica_id.is_empty());
+	}
+
+	#[test]
+	fn test_claim_record_is_versioned() {
+		let record = ClaimRecord::new(42u64, "alice-1", "4.0.0-dev", 3);
+		let value = record.serialize();
+		assert!(value.starts_with(RECORD_PREFIX));
+		assert_eq!(value[RECORD_PREFIX.len()], 2);
+	}
+
+	#[test]
+	fn test_claim_record_with_parents_roundtrip() {
+		let record = ClaimRecord::new(42u64, "alice-1", "4.0.0-dev", 3)
+			.on_parent(vec![vec![1; 32]], &[2; 32]);
+		let value = record.serialize();
+		assert_eq!(value[RECORD_PREFIX.len()], 3);
+		let read = ClaimRecord::<u64>::deserialize(&value).unwrap();
+		assert_eq!(read.parents, vec![vec![1; 32], vec![2; 32]]);
+		assert_eq!(read, record);
+	}
+
+	#[test]
+	fn test_claim_record_reads_version_without_fencing_token() {
+		let mut value = RECORD_PREFIX.to_vec();
+		VersionedClaimRecord::V1(ClaimRecordV1 {
+			index: 42u64,
+			replica_id: "alice-1".to_owned(),
+			timestamp_ms: 1,
+			node_version: "4.0.0-dev".to_owned(),
+		})
+		.encode_to(&mut value);
+		let record = ClaimRecord::<u64>::deserialize(&value).unwrap();
+		assert_eq!(record.replica_id, "alice-1");
+		assert_eq!(record.fencing_token, 0);
+	}
+
+	#[test]
+	fn test_rejects_empty_value() {
+		assert!(u64::deserialize(&[]).is_err());
+		assert!(RoundIndex::deserialize(&[]).is_err());
+		assert!(ClaimRecord::<u64>::deserialize(&[]).is_err());
+	}
+
+	#[test]
+	fn test_rejects_short_value() {
+		assert!(u64::deserialize(&[0; 7]).is_err());
+		assert!(RoundIndex::deserialize(&[0; 12]).is_err());
+		let value = ClaimRecord::new(42u64, "alice-1", "4.0.0-dev", 3).serialize();
+		assert!(ClaimRecord::<u64>::deserialize(&value[..value.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn test_rejects_long_value() {
+		assert!(u64::deserialize(&[0; 9]).is_err());
+		assert!(RoundIndex::deserialize(&[0; 17]).is_err());
+		let mut value = ClaimRecord::new(42u64, "alice-1", "4.0.0-dev", 3).serialize();
+		value.push(0);
+		assert!(ClaimRecord::<u64>::deserialize(&value).is_err());
+	}
+
+	#[test]
+	fn test_rejects_garbage_value() {
+		assert!(ClaimRecord::<u64>::deserialize(b"not a claim").is_err());
+		assert!(ClaimRecord::<RoundIndex>::deserialize(b"claim\x01garbage").is_err());
+		assert_eq!(
+			ClaimRecord::<u64>::deserialize(b"claim\x04"),
+			Err("unsupported claim record version")
+		);
+	}
+
+	#[test]
+	fn test_history_keys_sort_like_indexes() {
+		assert!(9u64.history_key() < 10u64.history_key());
+		let earlier = RoundIndex { set_id: 1, round: 900 };
+		let later = RoundIndex { set_id: 2, round: 1 };
+		assert!(earlier.history_key() < later.history_key());
+	}
+
+	#[test]
+	fn test_round_index_orders_by_set_id_first() {
+		assert!(RoundIndex { set_id: 1, round: 1 } > RoundIndex { set_id: 0, round: 1000 });
+		assert!(RoundIndex { set_id: 1, round: 2 } > RoundIndex { set_id: 1, round: 1 });
+	}
+}
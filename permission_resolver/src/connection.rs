@@ -0,0 +1,408 @@
+use crate::{
+	discovery::{Discovery, DiscoveryError},
+	metrics::BackendMetrics,
+	retry::RetryPolicy,
+	TiKVClient, TiKVRawClient, TiKVTransaction,
+};
+use async_trait::async_trait;
+use log::{info, warn};
+use sp_core::traits::SpawnNamed;
+use std::{
+	future::Future,
+	sync::{Arc, Mutex, RwLock},
+	time::Duration,
+};
+use tikv_client::{Error, Value};
+
+/// Backoff between the attempts to connect, connecting is retried until it succeeds.
+const CONNECT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+	min_backoff: Duration::from_millis(500),
+	max_backoff: Duration::from_secs(30),
+	deadline: Duration::MAX,
+};
+
+/// State of the connection to the TiKV cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+	/// The client is not created yet, every transaction fails.
+	Connecting,
+	/// The client is created, it takes care of the PD failover on its own from now on.
+	Connected,
+}
+
+/// Failed discovery of the PD servers or connection to them.
+#[derive(Debug, thiserror::Error)]
+enum ConnectError {
+	#[error("Could not find the PD servers, reason: {0}")]
+	Discovery(#[from] DiscoveryError),
+	#[error(transparent)]
+	Connect(#[from] Error),
+}
+
+type SharedClient<C> = Arc<RwLock<Option<Arc<C>>>>;
+
+/// TiKV client connecting in the background, so that the node can start while the PD is
+/// unreachable.
+pub struct ReconnectingTiKVClient<C: ?Sized = dyn TiKVClient> {
+	client: SharedClient<C>,
+}
+
+impl<C: ?Sized + Send + Sync + 'static> ReconnectingTiKVClient<C> {
+	/// Starts connecting with the given function to the discovered PD servers, until it succeeds
+	/// or the client is dropped. The PD servers found by a lookup are refreshed afterwards, and
+	/// the client is rebuilt when they change. Connecting runs on the given spawner, so that it
+	/// stops along with the node, or on the tokio runtime if there is none.
+	pub(crate) fn spawn<Connect, F>(
+		discovery: Discovery,
+		connect: Connect,
+		metrics: Option<BackendMetrics>,
+		spawner: Option<&dyn SpawnNamed>,
+	) -> ReconnectingTiKVClient<C>
+	where
+		Connect: Fn(Vec<String>) -> F + Send + 'static,
+		F: Future<Output = Result<Box<C>, Error>> + Send + 'static,
+	{
+		let client: SharedClient<C> = Arc::new(RwLock::new(None));
+		let shared = Arc::downgrade(&client);
+		if let Some(metrics) = &metrics {
+			metrics.set_connected(false);
+		}
+		let connecting = async move {
+			let mut attempts = 1;
+			// PD servers of the connected client
+			let mut connected_to: Option<Vec<String>> = None;
+			loop {
+				let result = match discovery.discover().await {
+					Ok(endpoints) if connected_to.as_ref() == Some(&endpoints) => Ok(None),
+					Ok(endpoints) => connect(endpoints.clone())
+						.await
+						.map(|connected| Some((endpoints, connected)))
+						.map_err(ConnectError::from),
+					Err(e) => Err(ConnectError::from(e)),
+				};
+				let shared = match shared.upgrade() {
+					Some(shared) => shared,
+					None => return,
+				};
+				match result {
+					Ok(Some((endpoints, connected))) => {
+						match connected_to {
+							Some(_) => info!(
+								target: "permission-resolver",
+								"PD servers changed, reconnected to TiKV through {}",
+								endpoints.join(", "),
+							),
+							None => info!(
+								target: "permission-resolver",
+								"Connected to TiKV in {} attempt(s)", attempts
+							),
+						}
+						*shared.write().unwrap() = Some(Arc::from(connected));
+						if let Some(metrics) = &metrics {
+							metrics.set_connected(true);
+						}
+						connected_to = Some(endpoints);
+					},
+					Ok(None) => {},
+					Err(e) if connected_to.is_some() => warn!(
+						target: "permission-resolver",
+						"Could not refresh the PD servers, staying connected, reason: {}", e
+					),
+					Err(e) => {
+						let backoff = CONNECT_RETRY_POLICY.backoff(attempts);
+						warn!(
+							target: "permission-resolver",
+							"Could not connect to TiKV, retrying in {:?}, reason: {}",
+							backoff,
+							e,
+						);
+						drop(shared);
+						tokio::time::sleep(backoff).await;
+						attempts += 1;
+						continue
+					},
+				}
+				if !discovery.is_dynamic() {
+					return
+				}
+				drop(shared);
+				tokio::time::sleep(discovery.refresh).await;
+			}
+		};
+		crate::spawn(spawner, "remote-authority-connect", connecting);
+		ReconnectingTiKVClient { client }
+	}
+
+	fn connected(&self) -> Option<Arc<C>> {
+		self.client.read().unwrap().clone()
+	}
+
+	fn state(&self) -> ConnectionState {
+		match self.connected() {
+			Some(_) => ConnectionState::Connected,
+			None => ConnectionState::Connecting,
+		}
+	}
+}
+
+type SharedSlot<C> = Mutex<Option<Arc<ReconnectingTiKVClient<C>>>>;
+
+/// Clients of the TiKV cluster shared by every resolver created by a factory, so that they share
+/// the PD connections and the region cache. Each client starts connecting on its first use.
+#[derive(Default)]
+pub struct SharedTiKVClients {
+	transaction: SharedSlot<dyn TiKVClient>,
+	raw: SharedSlot<dyn TiKVRawClient>,
+	metrics: Mutex<Option<Option<BackendMetrics>>>,
+}
+
+impl SharedTiKVClients {
+	pub(crate) fn transaction(
+		&self,
+		connect: impl FnOnce() -> ReconnectingTiKVClient,
+	) -> Arc<ReconnectingTiKVClient> {
+		self.transaction
+			.lock()
+			.unwrap()
+			.get_or_insert_with(|| Arc::new(connect()))
+			.clone()
+	}
+
+	pub(crate) fn raw(
+		&self,
+		connect: impl FnOnce() -> ReconnectingTiKVClient<dyn TiKVRawClient>,
+	) -> Arc<ReconnectingTiKVClient<dyn TiKVRawClient>> {
+		self.raw.lock().unwrap().get_or_insert_with(|| Arc::new(connect())).clone()
+	}
+
+	/// Metrics of the clients, registered once.
+	pub(crate) fn metrics(
+		&self,
+		register: impl FnOnce() -> Option<BackendMetrics>,
+	) -> Option<BackendMetrics> {
+		self.metrics.lock().unwrap().get_or_insert_with(register).clone()
+	}
+}
+
+fn not_connected() -> Error {
+	Error::StringError("Not connected to TiKV".to_owned())
+}
+
+#[async_trait]
+impl TiKVClient for ReconnectingTiKVClient {
+	async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		match self.connected() {
+			Some(client) => client.begin_optimistic().await,
+			None => Err(not_connected()),
+		}
+	}
+
+	async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		match self.connected() {
+			Some(client) => client.begin_pessimistic().await,
+			None => Err(not_connected()),
+		}
+	}
+
+	fn connection_state(&self) -> ConnectionState {
+		self.state()
+	}
+}
+
+#[async_trait]
+impl TiKVRawClient for ReconnectingTiKVClient<dyn TiKVRawClient> {
+	async fn get(&self, key: String) -> Result<Option<Value>, Error> {
+		match self.connected() {
+			Some(client) => client.get(key).await,
+			None => Err(not_connected()),
+		}
+	}
+
+	async fn compare_and_swap(
+		&self,
+		key: String,
+		previous: Option<Value>,
+		value: Value,
+	) -> Result<(Option<Value>, bool), Error> {
+		match self.connected() {
+			Some(client) => client.compare_and_swap(key, previous, value).await,
+			None => Err(not_connected()),
+		}
+	}
+
+	fn connection_state(&self) -> ConnectionState {
+		self.state()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		discovery::{
+			tests::{target, StubSrvLookup},
+			PdEndpoints,
+		},
+		testing::CountingSpawner,
+	};
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	fn static_discovery() -> Discovery {
+		Discovery::new(
+			PdEndpoints::Static(vec!["127.0.0.1:2379".to_owned()]),
+			Duration::from_secs(30),
+		)
+	}
+
+	struct NoopTiKVClient;
+
+	#[async_trait]
+	impl TiKVClient for NoopTiKVClient {
+		async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+			Err(Error::StringError("connected".to_owned()))
+		}
+
+		async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+			self.begin_optimistic().await
+		}
+	}
+
+	#[tokio::test]
+	async fn test_connects_in_background() {
+		let attempts = Arc::new(AtomicU32::new(0));
+		let counter = attempts.clone();
+		let client = ReconnectingTiKVClient::spawn(
+			static_discovery(),
+			move |_| {
+				let attempt = counter.fetch_add(1, Ordering::SeqCst);
+				async move {
+					if attempt == 0 {
+						Err(Error::StringError("PD is unreachable".to_owned()))
+					} else {
+						Ok(Box::new(NoopTiKVClient) as Box<dyn TiKVClient>)
+					}
+				}
+			},
+			None,
+			None,
+		);
+		assert_eq!(client.connection_state(), ConnectionState::Connecting);
+		assert!(client.begin_optimistic().await.is_err());
+
+		tokio::time::sleep(CONNECT_RETRY_POLICY.min_backoff + Duration::from_millis(100)).await;
+		assert_eq!(client.connection_state(), ConnectionState::Connected);
+		assert!(
+			matches!(client.begin_optimistic().await, Err(Error::StringError(m)) if m == "connected")
+		);
+		assert_eq!(attempts.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn test_connects_on_given_spawner() {
+		let spawner = CountingSpawner::default();
+		let client = ReconnectingTiKVClient::spawn(
+			static_discovery(),
+			|_| async { Ok(Box::new(NoopTiKVClient) as Box<dyn TiKVClient>) },
+			None,
+			Some(&spawner),
+		);
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		assert_eq!(spawner.spawned.load(Ordering::SeqCst), 1);
+		assert_eq!(client.connection_state(), ConnectionState::Connected);
+	}
+
+	#[tokio::test]
+	async fn test_shares_client() {
+		let clients = SharedTiKVClients::default();
+		let connects = AtomicU32::new(0);
+		let connect = || {
+			connects.fetch_add(1, Ordering::SeqCst);
+			ReconnectingTiKVClient::spawn(
+				static_discovery(),
+				|_| async { Ok(Box::new(NoopTiKVClient) as Box<dyn TiKVClient>) },
+				None,
+				None,
+			)
+		};
+		let first = clients.transaction(connect);
+		let second = clients.transaction(connect);
+		assert!(Arc::ptr_eq(&first, &second));
+		assert_eq!(connects.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_stops_connecting_once_dropped() {
+		let attempts = Arc::new(AtomicU32::new(0));
+		let counter = attempts.clone();
+		let client = ReconnectingTiKVClient::<dyn TiKVClient>::spawn(
+			static_discovery(),
+			move |_| {
+				counter.fetch_add(1, Ordering::SeqCst);
+				async { Err(Error::StringError("PD is unreachable".to_owned())) }
+			},
+			None,
+			None,
+		);
+		drop(client);
+		tokio::time::sleep(CONNECT_RETRY_POLICY.min_backoff * 3).await;
+		assert_eq!(attempts.load(Ordering::SeqCst), 1);
+	}
+
+	/// Client failing every transaction with the PD servers it was built for.
+	struct EndpointsTiKVClient(Vec<String>);
+
+	#[async_trait]
+	impl TiKVClient for EndpointsTiKVClient {
+		async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+			Err(Error::StringError(self.0.join(",")))
+		}
+
+		async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+			self.begin_optimistic().await
+		}
+	}
+
+	async fn endpoints(client: &ReconnectingTiKVClient) -> String {
+		match client.begin_optimistic().await {
+			Err(Error::StringError(endpoints)) => endpoints,
+			_ => panic!("Not connected"),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_rebuilds_client_when_pd_servers_change() {
+		let srv = Arc::new(StubSrvLookup::default());
+		*srv.targets.lock().unwrap() = vec![target(10, "pd-1."), target(10, "pd-2.")];
+		let discovery = Discovery::new(
+			PdEndpoints::Srv("_pd._tcp.example.com".to_owned()),
+			Duration::from_millis(20),
+		)
+		.with_srv_lookup(srv.clone());
+		let connects = Arc::new(AtomicU32::new(0));
+		let counter = connects.clone();
+		let client = ReconnectingTiKVClient::spawn(
+			discovery,
+			move |endpoints| {
+				counter.fetch_add(1, Ordering::SeqCst);
+				async { Ok(Box::new(EndpointsTiKVClient(endpoints)) as Box<dyn TiKVClient>) }
+			},
+			None,
+			None,
+		);
+		tokio::time::sleep(Duration::from_millis(70)).await;
+		assert_eq!(endpoints(&client).await, "pd-1:2379,pd-2:2379");
+		assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+		*srv.targets.lock().unwrap() = vec![target(10, "pd-2."), target(10, "pd-3.")];
+		tokio::time::sleep(Duration::from_millis(70)).await;
+		assert_eq!(endpoints(&client).await, "pd-2:2379,pd-3:2379");
+		assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+		// the connected client is kept while the PD servers cannot be found
+		srv.targets.lock().unwrap().clear();
+		tokio::time::sleep(Duration::from_millis(70)).await;
+		assert_eq!(client.connection_state(), ConnectionState::Connected);
+		assert_eq!(endpoints(&client).await, "pd-2:2379,pd-3:2379");
+		assert_eq!(connects.load(Ordering::SeqCst), 2);
+	}
+}
@@ -0,0 +1,412 @@
+use crate::security::{TlsConfig, TlsConfigError};
+use async_trait::async_trait;
+use hyper::{body, Body, Client, StatusCode, Uri};
+use hyper_rustls::HttpsConnectorBuilder;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Prefix of the DNS SRV names given instead of the PD addresses.
+const SRV_SCHEME: &str = "srv://";
+/// Path of the PD API listing the members of the PD cluster.
+const MEMBERS_PATH: &str = "/pd/api/v1/members";
+/// Time limit of a single discovery.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the addresses of the PD servers come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PdEndpoints {
+	/// Fixed `host:port` addresses.
+	Static(Vec<String>),
+	/// DNS SRV record listing the PD servers, given as `srv://_pd._tcp.example.com`.
+	Srv(String),
+	/// Members API of a PD server, given as `http://pd.example.com:2379`, or as
+	/// `https://pd.example.com:2379` when the PD servers are secured by TLS.
+	Members(String),
+}
+
+/// Misconfigured or failed discovery of the PD servers.
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+	#[error("No PD address given")]
+	Empty,
+	#[error("{0} has to be the only PD address")]
+	NotAlone(String),
+	#[error("Invalid discovery URL {0}")]
+	InvalidUrl(String),
+	#[error("{0} needs the certificates of the remote authority")]
+	NoTls(String),
+	#[error(transparent)]
+	Tls(#[from] TlsConfigError),
+	#[error("Could not look up {name}, reason: {source}")]
+	Dns { name: String, source: trust_dns_resolver::error::ResolveError },
+	#[error("Could not ask {url} for the PD members, reason: {source}")]
+	Http { url: String, source: hyper::Error },
+	#[error("{url} answered with {status}")]
+	Status { url: String, status: StatusCode },
+	#[error("Could not read the PD members from {url}, reason: {source}")]
+	Members { url: String, source: serde_json::Error },
+	#[error("Discovery timed out after {0:?}")]
+	Timeout(Duration),
+	#[error("No PD server found")]
+	NotFound,
+}
+
+impl PdEndpoints {
+	/// Reads the addresses given on the command line, a discovery name or URL has to be given
+	/// alone.
+	pub fn parse(addresses: &[String]) -> Result<PdEndpoints, DiscoveryError> {
+		let discovery = addresses.iter().find(|address| {
+			address.starts_with(SRV_SCHEME) ||
+				address.starts_with("http://") ||
+				address.starts_with("https://")
+		});
+		let discovery = match discovery {
+			Some(discovery) if addresses.len() > 1 =>
+				return Err(DiscoveryError::NotAlone(discovery.clone())),
+			Some(discovery) => discovery,
+			None if addresses.is_empty() => return Err(DiscoveryError::Empty),
+			None => return Ok(PdEndpoints::Static(addresses.to_vec())),
+		};
+		if let Some(name) = discovery.strip_prefix(SRV_SCHEME) {
+			return Ok(PdEndpoints::Srv(name.to_owned()))
+		}
+		let uri: Uri =
+			discovery.parse().map_err(|_| DiscoveryError::InvalidUrl(discovery.clone()))?;
+		let invalid = || DiscoveryError::InvalidUrl(discovery.clone());
+		let scheme = uri.scheme_str().ok_or_else(invalid)?;
+		let authority = uri.authority().ok_or_else(invalid)?;
+		Ok(PdEndpoints::Members(format!("{}://{}{}", scheme, authority, MEMBERS_PATH)))
+	}
+
+	/// Checks that the PD servers can be asked for their members with the given certificates.
+	pub fn check_tls(&self, tls: Option<&TlsConfig>) -> Result<(), DiscoveryError> {
+		match self {
+			PdEndpoints::Members(url) if url.starts_with("https://") && tls.is_none() =>
+				Err(DiscoveryError::NoTls(url.clone())),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Target of a DNS SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SrvTarget {
+	pub priority: u16,
+	pub weight: u16,
+	pub host: String,
+	pub port: u16,
+}
+
+/// Looks up the DNS SRV records, so that the lookup can be replaced in tests.
+#[async_trait]
+pub(crate) trait SrvLookup: Send + Sync {
+	async fn lookup(&self, name: &str) -> Result<Vec<SrvTarget>, DiscoveryError>;
+}
+
+/// Lookup with the resolvers configured in the system.
+struct SystemSrvLookup;
+
+#[async_trait]
+impl SrvLookup for SystemSrvLookup {
+	async fn lookup(&self, name: &str) -> Result<Vec<SrvTarget>, DiscoveryError> {
+		let dns = |source| DiscoveryError::Dns { name: name.to_owned(), source };
+		let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(dns)?;
+		let records = resolver.srv_lookup(name).await.map_err(dns)?;
+		Ok(records
+			.iter()
+			.map(|record| SrvTarget {
+				priority: record.priority(),
+				weight: record.weight(),
+				host: record.target().to_utf8(),
+				port: record.port(),
+			})
+			.collect())
+	}
+}
+
+#[derive(Deserialize)]
+struct Members {
+	members: Vec<Member>,
+}
+
+#[derive(Deserialize)]
+struct Member {
+	client_urls: Vec<String>,
+}
+
+/// Finds the PD servers, the ones found by a DNS or PD lookup are refreshed periodically.
+#[derive(Clone)]
+pub(crate) struct Discovery {
+	endpoints: PdEndpoints,
+	srv: Arc<dyn SrvLookup>,
+	tls: Option<TlsConfig>,
+	/// Time between the refreshes of the PD servers.
+	pub refresh: Duration,
+}
+
+impl Discovery {
+	pub fn new(endpoints: PdEndpoints, refresh: Duration) -> Discovery {
+		Discovery { endpoints, srv: Arc::new(SystemSrvLookup), tls: None, refresh }
+	}
+
+	/// Asks the PD servers for their members with the given certificates.
+	pub fn with_tls(mut self, tls: Option<TlsConfig>) -> Discovery {
+		self.tls = tls;
+		self
+	}
+
+	#[cfg(test)]
+	pub fn with_srv_lookup(mut self, srv: Arc<dyn SrvLookup>) -> Discovery {
+		self.srv = srv;
+		self
+	}
+
+	/// Whether the PD servers may change.
+	pub fn is_dynamic(&self) -> bool {
+		!matches!(self.endpoints, PdEndpoints::Static(_))
+	}
+
+	/// Addresses of the PD servers, the ones found by a lookup are sorted, so that they can be
+	/// compared between the refreshes.
+	pub async fn discover(&self) -> Result<Vec<String>, DiscoveryError> {
+		let endpoints = match &self.endpoints {
+			PdEndpoints::Static(addresses) => return Ok(addresses.clone()),
+			PdEndpoints::Srv(name) =>
+				tokio::time::timeout(DISCOVERY_TIMEOUT, self.srv.lookup(name))
+					.await
+					.map_err(|_| DiscoveryError::Timeout(DISCOVERY_TIMEOUT))?
+					.map(srv_endpoints)?,
+			PdEndpoints::Members(url) =>
+				tokio::time::timeout(DISCOVERY_TIMEOUT, members(url, self.tls.as_ref()))
+					.await
+					.map_err(|_| DiscoveryError::Timeout(DISCOVERY_TIMEOUT))??,
+		};
+		if endpoints.is_empty() {
+			return Err(DiscoveryError::NotFound)
+		}
+		Ok(endpoints)
+	}
+}
+
+/// Targets of the most preferred priority, the other ones are only backups.
+fn srv_endpoints(targets: Vec<SrvTarget>) -> Vec<String> {
+	let priority = match targets.iter().map(|target| target.priority).min() {
+		Some(priority) => priority,
+		None => return Vec::new(),
+	};
+	let mut endpoints: Vec<_> = targets
+		.into_iter()
+		.filter(|target| target.priority == priority)
+		.map(|target| format!("{}:{}", target.host.trim_end_matches('.'), target.port))
+		.collect();
+	endpoints.sort();
+	endpoints.dedup();
+	endpoints
+}
+
+/// Client addresses of the PD members, as listed by the PD API.
+async fn members(url: &str, tls: Option<&TlsConfig>) -> Result<Vec<String>, DiscoveryError> {
+	let uri = url.parse().map_err(|_| DiscoveryError::InvalidUrl(url.to_owned()))?;
+	let http = |source| DiscoveryError::Http { url: url.to_owned(), source };
+	let request = match tls {
+		Some(tls) => {
+			let connector = HttpsConnectorBuilder::new()
+				.with_tls_config(tls.rustls_config()?)
+				.https_or_http()
+				.enable_http1()
+				.build();
+			Client::builder().build::<_, Body>(connector).get(uri)
+		},
+		None if url.starts_with("https://") => return Err(DiscoveryError::NoTls(url.to_owned())),
+		None => Client::new().get(uri),
+	};
+	let response = request.await.map_err(http)?;
+	if response.status() != StatusCode::OK {
+		return Err(DiscoveryError::Status { url: url.to_owned(), status: response.status() })
+	}
+	let body = body::to_bytes(response.into_body()).await.map_err(http)?;
+	let members: Members = serde_json::from_slice(&body)
+		.map_err(|source| DiscoveryError::Members { url: url.to_owned(), source })?;
+	let mut endpoints: Vec<_> = members
+		.members
+		.into_iter()
+		.flat_map(|member| member.client_urls)
+		.map(|url| {
+			let address = url.strip_prefix("http://").or_else(|| url.strip_prefix("https://"));
+			address.unwrap_or(&url).trim_end_matches('/').to_owned()
+		})
+		.collect();
+	endpoints.sort();
+	endpoints.dedup();
+	Ok(endpoints)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::security::tests::TestCa;
+	use hyper::{
+		server::conn::Http,
+		service::{make_service_fn, service_fn},
+		Body, Response, Server,
+	};
+	use rustls::ServerConfig;
+	use std::{convert::Infallible, net::SocketAddr, sync::Mutex};
+	use tempfile::TempDir;
+	use tokio::net::TcpListener;
+	use tokio_rustls::TlsAcceptor;
+
+	/// Stub DNS resolver answering with the targets set by the test.
+	#[derive(Default)]
+	pub(crate) struct StubSrvLookup {
+		pub targets: Mutex<Vec<SrvTarget>>,
+	}
+
+	#[async_trait]
+	impl SrvLookup for StubSrvLookup {
+		async fn lookup(&self, _name: &str) -> Result<Vec<SrvTarget>, DiscoveryError> {
+			Ok(self.targets.lock().unwrap().clone())
+		}
+	}
+
+	pub(crate) fn target(priority: u16, host: &str) -> SrvTarget {
+		SrvTarget { priority, weight: 10, host: host.to_owned(), port: 2379 }
+	}
+
+	/// Serves the given PD members API response on a local port.
+	fn serve_members(status: StatusCode, body: &'static str) -> SocketAddr {
+		let make_service = make_service_fn(move |_| async move {
+			Ok::<_, Infallible>(service_fn(move |request| async move {
+				assert_eq!(request.uri().path(), MEMBERS_PATH);
+				let mut response = Response::new(Body::from(body));
+				*response.status_mut() = status;
+				Ok::<_, Infallible>(response)
+			}))
+		});
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+		let address = server.local_addr();
+		tokio::spawn(server);
+		address
+	}
+
+	/// Serves the given PD members API response over TLS on a local port.
+	async fn serve_members_tls(server: ServerConfig, body: &'static str) -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let acceptor = TlsAcceptor::from(Arc::new(server));
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				if let Ok(stream) = acceptor.accept(stream).await {
+					let service = service_fn(move |_| async move {
+						Ok::<_, Infallible>(Response::new(Body::from(body)))
+					});
+					tokio::spawn(Http::new().serve_connection(stream, service));
+				}
+			}
+		});
+		address
+	}
+
+	#[test]
+	fn test_parses_static_addresses() {
+		let addresses = vec!["pd-1:2379".to_owned(), "pd-2:2379".to_owned()];
+		assert_eq!(PdEndpoints::parse(&addresses).unwrap(), PdEndpoints::Static(addresses));
+	}
+
+	#[test]
+	fn test_parses_discovery_names() {
+		let srv = PdEndpoints::parse(&["srv://_pd._tcp.example.com".to_owned()]).unwrap();
+		assert_eq!(srv, PdEndpoints::Srv("_pd._tcp.example.com".to_owned()));
+		let members = PdEndpoints::parse(&["http://pd.example.com:2379".to_owned()]).unwrap();
+		assert_eq!(
+			members,
+			PdEndpoints::Members("http://pd.example.com:2379/pd/api/v1/members".to_owned())
+		);
+		let members = PdEndpoints::parse(&["https://pd.example.com:2379".to_owned()]).unwrap();
+		assert_eq!(
+			members,
+			PdEndpoints::Members("https://pd.example.com:2379/pd/api/v1/members".to_owned())
+		);
+	}
+
+	#[test]
+	fn test_rejects_discovery_name_among_addresses() {
+		let addresses = vec!["pd-1:2379".to_owned(), "srv://_pd._tcp.example.com".to_owned()];
+		assert!(matches!(PdEndpoints::parse(&addresses), Err(DiscoveryError::NotAlone(_))));
+		assert!(matches!(PdEndpoints::parse(&[]), Err(DiscoveryError::Empty)));
+	}
+
+	#[tokio::test]
+	async fn test_discovers_preferred_srv_targets() {
+		let srv = Arc::new(StubSrvLookup::default());
+		*srv.targets.lock().unwrap() = vec![
+			target(20, "backup.example.com."),
+			target(10, "pd-2.example.com."),
+			target(10, "pd-1.example.com."),
+		];
+		let discovery =
+			Discovery::new(PdEndpoints::Srv("_pd._tcp.example.com".to_owned()), Duration::ZERO)
+				.with_srv_lookup(srv.clone());
+		assert_eq!(
+			discovery.discover().await.unwrap(),
+			vec!["pd-1.example.com:2379".to_owned(), "pd-2.example.com:2379".to_owned()]
+		);
+
+		srv.targets.lock().unwrap().clear();
+		assert!(matches!(discovery.discover().await, Err(DiscoveryError::NotFound)));
+	}
+
+	#[tokio::test]
+	async fn test_discovers_pd_members() {
+		let address = serve_members(
+			StatusCode::OK,
+			r#"{"header":{},"members":[
+				{"name":"pd-2","client_urls":["http://10.0.0.2:2379"]},
+				{"name":"pd-1","client_urls":["http://10.0.0.1:2379/"]}
+			]}"#,
+		);
+		let endpoints = PdEndpoints::parse(&[format!("http://{}", address)]).unwrap();
+		let discovery = Discovery::new(endpoints, Duration::ZERO);
+		assert_eq!(
+			discovery.discover().await.unwrap(),
+			vec!["10.0.0.1:2379".to_owned(), "10.0.0.2:2379".to_owned()]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_reports_failed_members_request() {
+		let address = serve_members(StatusCode::SERVICE_UNAVAILABLE, "");
+		let endpoints = PdEndpoints::parse(&[format!("http://{}", address)]).unwrap();
+		let discovery = Discovery::new(endpoints, Duration::ZERO);
+		assert!(matches!(
+			discovery.discover().await,
+			Err(DiscoveryError::Status { status: StatusCode::SERVICE_UNAVAILABLE, .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn test_discovers_pd_members_over_tls() {
+		let dir = TempDir::new().unwrap();
+		let ca = TestCa::new();
+		let tls = ca.client(dir.path());
+		let address = serve_members_tls(
+			ca.server(),
+			r#"{"header":{},"members":[{"name":"pd-1","client_urls":["https://10.0.0.1:2379"]}]}"#,
+		)
+		.await;
+		let endpoints =
+			PdEndpoints::parse(&[format!("https://localhost:{}", address.port())]).unwrap();
+		assert!(endpoints.check_tls(Some(&tls)).is_ok());
+		let discovery = Discovery::new(endpoints, Duration::ZERO).with_tls(Some(tls));
+		assert_eq!(discovery.discover().await.unwrap(), vec!["10.0.0.1:2379".to_owned()]);
+	}
+
+	#[tokio::test]
+	async fn test_rejects_pd_members_over_tls_without_certificates() {
+		let endpoints = PdEndpoints::parse(&["https://localhost:2379".to_owned()]).unwrap();
+		assert!(matches!(endpoints.check_tls(None), Err(DiscoveryError::NoTls(_))));
+		let discovery = Discovery::new(endpoints, Duration::ZERO);
+		assert!(matches!(discovery.discover().await, Err(DiscoveryError::NoTls(_))));
+	}
+}
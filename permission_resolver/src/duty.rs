@@ -0,0 +1,120 @@
+use crate::{
+	permission_or_fail_policy, Key, RemoteAuthorityPermissionResolver, ResolveError,
+	TryPermissionResolver,
+};
+use async_trait::async_trait;
+use log::error;
+
+/// Longest accepted duty name.
+const MAX_DUTY_NAME: usize = 64;
+
+impl Key {
+	/// Key of the duty with the given name, made of lowercase letters, digits and dashes.
+	pub(crate) fn duty(name: &str) -> Result<Key, ResolveError> {
+		let valid = !name.is_empty() &&
+			name.len() <= MAX_DUTY_NAME &&
+			name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+		if !valid {
+			return Err(ResolveError::InvalidDuty(name.to_owned()))
+		}
+		Ok(Key::DUTY(format!("duty/{}", name).into()))
+	}
+
+	pub(crate) fn is_duty(&self) -> bool {
+		matches!(self, Key::DUTY(_))
+	}
+}
+
+/// Claims of the duties named by their users, so that the work beyond authoring and voting, like
+/// the offchain workers or the maintenance jobs, is done by a single replica as well.
+#[async_trait]
+pub trait DutyClaims: Send + Sync {
+	/// Whether this replica performs the named duty at the given index. Like with the slots, a
+	/// duty is granted once per index, and only for an index greater than any claimed before.
+	async fn claim(&self, duty: &str, index: u64) -> bool;
+}
+
+pub(crate) fn invalid_duty(e: ResolveError) -> bool {
+	error!(target: "permission-resolver", "Denying duty claim, reason: {}", e);
+	false
+}
+
+#[async_trait]
+impl DutyClaims for RemoteAuthorityPermissionResolver {
+	async fn claim(&self, duty: &str, index: u64) -> bool {
+		let key = match Key::duty(duty) {
+			Ok(key) => key,
+			Err(e) => return invalid_duty(e),
+		};
+		let result = self.try_resolve_duty(duty, index).await;
+		permission_or_fail_policy(key, self.fail_policies.duty, &self.contention, result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::InMemoryTiKVClient, FailPolicies, FailPolicy, KeyNamespace};
+	use std::sync::atomic::Ordering;
+
+	async fn replica(client: &InMemoryTiKVClient) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy).await
+	}
+
+	#[test]
+	fn test_duty_keys() {
+		assert_eq!(Key::duty("price-feed").unwrap().as_str(), "duty/price-feed");
+		assert!(Key::duty("price-feed").unwrap().is_duty());
+	}
+
+	#[test]
+	fn test_rejects_invalid_duty_names() {
+		for name in ["", "Price-Feed", "price/feed", "slot ", &"a".repeat(MAX_DUTY_NAME + 1)] {
+			assert!(matches!(Key::duty(name), Err(ResolveError::InvalidDuty(_))), "{:?}", name);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_grants_only_greater_indexes() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		assert!(alice.claim("price-feed", 10).await);
+		assert!(!bob.claim("price-feed", 10).await);
+		assert!(!alice.claim("price-feed", 9).await);
+		assert!(bob.claim("price-feed", 11).await);
+	}
+
+	#[tokio::test]
+	async fn test_duties_are_claimed_independently() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		assert!(alice.claim("price-feed", 10).await);
+		assert!(bob.claim("indexer", 10).await);
+		assert!(bob.try_resolve_slot(10.into()).await.unwrap());
+		assert!(client.data.lock().unwrap().contains_key("duty/price-feed"));
+		assert!(client.data.lock().unwrap().contains_key("duty/indexer"));
+	}
+
+	#[tokio::test]
+	async fn test_only_one_replica_wins_race() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		let (alice_won, bob_won) =
+			tokio::join!(alice.claim("maintenance", 1), bob.claim("maintenance", 1));
+		assert!(alice_won ^ bob_won);
+	}
+
+	#[tokio::test]
+	async fn test_duty_fail_policy_applies() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client)
+			.await
+			.with_fail_policies(FailPolicies { duty: FailPolicy::Grant, ..Default::default() });
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(alice.claim("price-feed", 1).await);
+		assert!(!alice.claim("Price Feed", 1).await);
+	}
+}
@@ -0,0 +1,41 @@
+use crate::{discovery::DiscoveryError, namespace::to_hex, security::TlsConfigError};
+
+/// Failure of the permission backend, as opposed to the permission being denied.
+#[derive(Debug, thiserror::Error)]
+pub enum ResolveError {
+	#[error("Could not start transaction, reason: {0}")]
+	Begin(#[source] tikv_client::Error),
+	#[error("Could not get {key} value for update, reason: {source}")]
+	Read { key: String, source: tikv_client::Error },
+	#[error("Could not put {key} value, reason: {source}")]
+	Write { key: String, source: tikv_client::Error },
+	#[error("Could not commit transaction, reason: {0}")]
+	Commit(#[source] tikv_client::Error),
+	#[error("Could not rollback transaction, reason: {0}")]
+	Rollback(#[source] tikv_client::Error),
+	#[error("Not connected to TiKV yet")]
+	Disconnected,
+	#[error("Permission was not resolved within {0:?}")]
+	Timeout(std::time::Duration),
+	#[error("Stored {key} value 0x{} is corrupted, reason: {reason}", to_hex(.value))]
+	Corrupt { key: String, reason: &'static str, value: Vec<u8> },
+	#[error("Invalid duty name {0:?}, expected up to 64 lowercase letters, digits and dashes")]
+	InvalidDuty(String),
+	#[error("Could not talk to the permission plugin, reason: {0}")]
+	PluginIo(#[source] std::io::Error),
+	#[error("Permission plugin failed, reason: {0}")]
+	Plugin(String),
+}
+
+/// Configuration of the remote authority missing what its mode needs.
+#[derive(Debug, thiserror::Error)]
+pub enum FactoryError {
+	#[error("Invalid remote authority address: {0}")]
+	Discovery(#[from] DiscoveryError),
+	#[error("Invalid remote authority certificates: {0}")]
+	Tls(#[from] TlsConfigError),
+	#[error("{0}")]
+	Missing(&'static str),
+	#[error("{0}")]
+	Invalid(&'static str),
+}
@@ -0,0 +1,333 @@
+use crate::{
+	metrics::BackendMetrics, retry::is_transient, Key, ResolveError, TryPermissionResolver,
+};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
+use sp_consensus_slots::Slot;
+use std::{
+	future::Future,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tokio::time::Instant;
+
+impl ResolveError {
+	/// Whether the backend couldn't be reached, as opposed to answering wrongly or being asked
+	/// wrongly.
+	pub fn is_connectivity(&self) -> bool {
+		match self {
+			// a transaction starts with a timestamp from PD alone
+			ResolveError::Begin(_) |
+			ResolveError::Disconnected |
+			ResolveError::Timeout(_) |
+			ResolveError::PluginIo(_) => true,
+			ResolveError::Read { source: e, .. } |
+			ResolveError::Write { source: e, .. } |
+			ResolveError::Commit(e) => is_transient(e),
+			ResolveError::Rollback(_) |
+			ResolveError::Corrupt { .. } |
+			ResolveError::InvalidDuty(_) |
+			ResolveError::Plugin(_) => false,
+		}
+	}
+}
+
+/// Duties moved to the secondary backend while the primary one can't be reached. The factory only
+/// moves the sessions, since every replica would author and vote otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FallbackDuties {
+	pub slot: bool,
+	pub round: bool,
+	pub session: bool,
+}
+
+/// Secondary backend of the duties that may be resolved without the primary one.
+#[derive(Clone)]
+pub struct Fallback {
+	pub secondary: Arc<dyn PermissionResolverFactory>,
+	pub duties: FallbackDuties,
+	/// Time the primary backend has to answer without a connectivity error before the duties
+	/// move back to it.
+	pub hysteresis: Duration,
+}
+
+/// Backend the fallback duties are resolved by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Active {
+	Primary,
+	/// Since when the primary backend has been answering, if it has.
+	Secondary {
+		reachable_since: Option<Instant>,
+	},
+}
+
+/// Permission resolver moving some of the duties to a secondary backend while the primary backend
+/// can't be reached, and only then. A denial of the primary backend is final. The primary backend
+/// is still asked while the secondary one decides, and its grants are trusted again once it has
+/// been answering for the hysteresis, so that a flapping backend doesn't switch the duties back
+/// and forth.
+/// The other duties are resolved by the primary backend alone, its fail policies included.
+pub struct FallbackPermissionResolver {
+	primary: Box<dyn TryPermissionResolver>,
+	secondary: Box<dyn PermissionResolver>,
+	duties: FallbackDuties,
+	hysteresis: Duration,
+	active: Mutex<Active>,
+	metrics: Option<BackendMetrics>,
+}
+
+impl FallbackPermissionResolver {
+	pub fn new(
+		primary: Box<dyn TryPermissionResolver>,
+		secondary: Box<dyn PermissionResolver>,
+		duties: FallbackDuties,
+		hysteresis: Duration,
+	) -> FallbackPermissionResolver {
+		FallbackPermissionResolver {
+			primary,
+			secondary,
+			duties,
+			hysteresis,
+			active: Mutex::new(Active::Primary),
+			metrics: None,
+		}
+	}
+
+	pub(crate) fn with_metrics(
+		mut self,
+		metrics: Option<BackendMetrics>,
+	) -> FallbackPermissionResolver {
+		self.metrics = metrics;
+		self
+	}
+
+	fn switch(&self, active: &mut Active, to: Active) {
+		match (*active, to) {
+			(Active::Primary, Active::Secondary { .. }) => warn!(
+				target: "permission-resolver",
+				"Primary backend can't be reached, moving the fallback duties to the secondary one",
+			),
+			(Active::Secondary { .. }, Active::Primary) => info!(
+				target: "permission-resolver",
+				"Primary backend has been answering for {:?}, moving the fallback duties back to it",
+				self.hysteresis,
+			),
+			_ => {},
+		}
+		*active = to;
+		if let Some(metrics) = &self.metrics {
+			metrics.set_fallback_active(to != Active::Primary);
+		}
+	}
+
+	/// Whether the primary backend decides after it answered, or the secondary one has to.
+	fn answered(&self, answered: bool) -> bool {
+		let mut active = self.active.lock().unwrap();
+		let to = match (*active, answered) {
+			(Active::Primary, true) => Active::Primary,
+			(_, false) => Active::Secondary { reachable_since: None },
+			(Active::Secondary { reachable_since: None }, true) =>
+				Active::Secondary { reachable_since: Some(Instant::now()) },
+			(Active::Secondary { reachable_since: Some(since) }, true) =>
+				if since.elapsed() >= self.hysteresis {
+					Active::Primary
+				} else {
+					Active::Secondary { reachable_since: Some(since) }
+				},
+		};
+		self.switch(&mut active, to);
+		to == Active::Primary
+	}
+
+	async fn resolve<P, S>(&self, key: Key, primary: P, secondary: S) -> bool
+	where
+		P: Future<Output = Result<bool, ResolveError>>,
+		S: Future<Output = bool>,
+	{
+		let (answered, permission) = match primary.await {
+			Ok(permission) => (true, Some(permission)),
+			Err(e) if e.is_connectivity() => {
+				debug!(
+					target: "permission-resolver",
+					"Could not reach the primary backend for {} permission, reason: {}",
+					key.as_str(),
+					e,
+				);
+				(false, None)
+			},
+			// reached, the secondary backend is no better at it
+			Err(e) => {
+				warn!(
+					target: "permission-resolver",
+					"Denying {} permission, the primary backend failed, reason: {}", key.as_str(), e
+				);
+				(true, Some(false))
+			},
+		};
+		match (self.answered(answered), permission) {
+			(true, Some(permission)) => permission,
+			// the hysteresis delays trusting the grants alone
+			(false, Some(false)) => false,
+			_ => secondary.await,
+		}
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for FallbackPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		if !self.duties.slot {
+			return self.primary.resolve_slot(slot).await
+		}
+		self.resolve(
+			Key::SLOT,
+			self.primary.try_resolve_slot(slot),
+			self.secondary.resolve_slot(slot),
+		)
+		.await
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		if !self.duties.round {
+			return self.primary.resolve_round(round).await
+		}
+		self.resolve(
+			Key::ROUND,
+			self.primary.try_resolve_round(round),
+			self.secondary.resolve_round(round),
+		)
+		.await
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		if !self.duties.session {
+			return self.primary.resolve_session(session_index).await
+		}
+		self.resolve(
+			Key::SESSION,
+			self.primary.try_resolve_session(session_index),
+			self.secondary.resolve_session(session_index),
+		)
+		.await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::InMemoryTiKVClient, KeyNamespace, RemoteAuthorityPermissionResolver};
+	use std::sync::atomic::Ordering;
+
+	/// Secondary backend granting every duty, or denying it.
+	struct MockedSecondary(bool);
+
+	#[async_trait]
+	impl PermissionResolver for MockedSecondary {
+		async fn resolve_slot(&self, _: Slot) -> bool {
+			self.0
+		}
+
+		async fn resolve_round(&self, _: u64) -> bool {
+			self.0
+		}
+
+		async fn resolve_session(&self, _: u32) -> bool {
+			self.0
+		}
+	}
+
+	async fn replica(client: &InMemoryTiKVClient) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy).await
+	}
+
+	async fn fallback(client: &InMemoryTiKVClient, secondary: bool) -> FallbackPermissionResolver {
+		FallbackPermissionResolver::new(
+			Box::new(replica(client).await),
+			Box::new(MockedSecondary(secondary)),
+			FallbackDuties { session: true, ..Default::default() },
+			Duration::from_secs(60),
+		)
+	}
+
+	#[test]
+	fn test_tells_connectivity_errors_apart() {
+		assert!(ResolveError::Disconnected.is_connectivity());
+		assert!(ResolveError::Timeout(Duration::from_secs(1)).is_connectivity());
+		assert!(!ResolveError::InvalidDuty("Price Feed".into()).is_connectivity());
+		assert!(!ResolveError::Corrupt {
+			key: "slot".into(),
+			reason: "malformed",
+			value: Vec::new()
+		}
+		.is_connectivity());
+	}
+
+	#[tokio::test]
+	async fn test_primary_denial_is_final() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = fallback(&client, true).await;
+		assert!(alice.resolve_session(1).await);
+		assert!(!bob.resolve_session(1).await);
+	}
+
+	#[tokio::test]
+	async fn test_falls_back_on_connectivity_errors() {
+		let client = InMemoryTiKVClient::default();
+		let alice = fallback(&client, true).await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(alice.resolve_session(1).await);
+		assert!(alice.resolve_session(2).await);
+	}
+
+	#[tokio::test]
+	async fn test_slots_do_not_fall_back() {
+		let client = InMemoryTiKVClient::default();
+		let alice = fallback(&client, true).await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(!alice.resolve_slot(1.into()).await);
+		assert!(!alice.resolve_round(1).await);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_moves_back_after_hysteresis() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = fallback(&client, true).await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(bob.resolve_session(1).await);
+
+		// the primary answers again, but not for long enough, its denials are final all the same
+		client.unavailable.store(false, Ordering::SeqCst);
+		assert!(alice.resolve_session(2).await);
+		assert!(!bob.resolve_session(2).await);
+		tokio::time::advance(Duration::from_secs(30)).await;
+		assert!(bob.resolve_session(3).await);
+		assert!(matches!(*bob.active.lock().unwrap(), Active::Secondary { .. }));
+
+		// a flap restarts the hysteresis
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(bob.resolve_session(4).await);
+		client.unavailable.store(false, Ordering::SeqCst);
+		assert!(bob.resolve_session(5).await);
+		tokio::time::advance(Duration::from_secs(60)).await;
+
+		assert!(alice.resolve_session(6).await);
+		assert!(!bob.resolve_session(6).await);
+		assert_eq!(*bob.active.lock().unwrap(), Active::Primary);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_secondary_decides_grants_during_hysteresis() {
+		let client = InMemoryTiKVClient::default();
+		let alice = fallback(&client, false).await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(!alice.resolve_session(1).await);
+
+		client.unavailable.store(false, Ordering::SeqCst);
+		assert!(!alice.resolve_session(2).await);
+		tokio::time::advance(Duration::from_secs(60)).await;
+		assert!(alice.resolve_session(3).await);
+	}
+}
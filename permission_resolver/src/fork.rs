@@ -0,0 +1,151 @@
+/// View of the chain of this replica, so that a slot claimed on a block since orphaned can be
+/// taken over in the same slot.
+pub trait ForkView: Send + Sync {
+	/// Hash of the block the block of the slot is built on, as chosen by the slot worker. The slot
+	/// is claimed without a parent if it isn't known.
+	fn parent(&self, slot: u64) -> Option<Vec<u8>>;
+	/// Whether the block is known to be off the finalized chain of this replica. Blocks that may
+	/// still be finalized aren't orphaned, even if off the best chain, since the best chains of
+	/// the replicas may differ for a while.
+	fn is_orphaned(&self, block: &[u8]) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cas::CasClient, claim::ClaimRecord, testing::InMemoryTiKVClient, Backend, Key,
+		KeyNamespace, RemoteAuthorityPermissionResolver,
+	};
+	use sp_authority_permission::PermissionResolver;
+	use std::sync::{Arc, Mutex};
+
+	/// Chain of a replica, built on `best` with the `orphaned` blocks off it.
+	#[derive(Default)]
+	struct MockedForkView {
+		best: Mutex<Option<Vec<u8>>>,
+		orphaned: Mutex<Vec<Vec<u8>>>,
+	}
+
+	impl MockedForkView {
+		fn on(best: u8) -> Arc<MockedForkView> {
+			let view = MockedForkView::default();
+			*view.best.lock().unwrap() = Some(vec![best; 32]);
+			Arc::new(view)
+		}
+
+		fn orphan(&self, block: u8) {
+			self.orphaned.lock().unwrap().push(vec![block; 32]);
+		}
+	}
+
+	impl ForkView for MockedForkView {
+		fn parent(&self, _: u64) -> Option<Vec<u8>> {
+			self.best.lock().unwrap().clone()
+		}
+
+		fn is_orphaned(&self, block: &[u8]) -> bool {
+			self.orphaned.lock().unwrap().iter().any(|orphaned| orphaned == block)
+		}
+	}
+
+	async fn replica(
+		client: &InMemoryTiKVClient,
+		fork_view: Arc<MockedForkView>,
+	) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+			.await
+			.with_fork_view(fork_view)
+	}
+
+	fn stored_slot(client: &InMemoryTiKVClient) -> ClaimRecord<u64> {
+		let stored = client.data.lock().unwrap().get(Key::SLOT.as_str()).cloned().unwrap();
+		ClaimRecord::deserialize(&stored).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_records_parent_with_slot() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, MockedForkView::on(1)).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert_eq!(stored_slot(&client).parents, vec![vec![1; 32]]);
+	}
+
+	#[tokio::test]
+	async fn test_takes_over_slot_built_on_orphaned_parent() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, MockedForkView::on(1)).await;
+		let bob_view = MockedForkView::on(2);
+		let bob = replica(&client, bob_view.clone()).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(!bob.resolve_slot(5.into()).await);
+
+		bob_view.orphan(1);
+		assert!(bob.resolve_slot(5.into()).await);
+		let record = stored_slot(&client);
+		assert_eq!(record.parents, vec![vec![1; 32], vec![2; 32]]);
+		assert_eq!(record.fencing_token, 2);
+		assert!(!alice.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_never_claims_twice_on_same_parent() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, MockedForkView::on(1)).await;
+		let bob_view = MockedForkView::on(2);
+		bob_view.orphan(1);
+		let bob = replica(&client, bob_view).await;
+		// sees the block of bob orphaned, but alice claimed on its parent already
+		let charlie_view = MockedForkView::on(1);
+		charlie_view.orphan(2);
+		let charlie = replica(&client, charlie_view).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(5.into()).await);
+		assert!(!charlie.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_claims_without_unknown_parent() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, Arc::new(MockedForkView::default())).await;
+		let bob_view = MockedForkView::on(2);
+		bob_view.orphan(1);
+		let bob = replica(&client, bob_view).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(stored_slot(&client).parents.is_empty());
+		assert!(!bob.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_keeps_claims_without_parents() {
+		let client = InMemoryTiKVClient::default();
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		let bob_view = MockedForkView::on(2);
+		bob_view.orphan(1);
+		let bob = replica(&client, bob_view).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(!bob.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_takes_over_with_compare_and_swap() {
+		let client = InMemoryTiKVClient::default();
+		let replica = |fork_view: Arc<MockedForkView>| {
+			RemoteAuthorityPermissionResolver::with_backend(
+				Backend::CompareAndSwap(CasClient::new(Box::new(client.clone()))),
+				KeyNamespace::Legacy,
+			)
+			.with_fork_view(fork_view)
+		};
+		let alice = replica(MockedForkView::on(1));
+		let bob_view = MockedForkView::on(2);
+		let bob = replica(bob_view.clone());
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(!bob.resolve_slot(5.into()).await);
+		bob_view.orphan(1);
+		assert!(bob.resolve_slot(5.into()).await);
+		assert!(!alice.resolve_slot(5.into()).await);
+	}
+}
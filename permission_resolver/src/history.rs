@@ -0,0 +1,250 @@
+use crate::{
+	claim::{ClaimRecord, ClaimValue},
+	metrics::BackendMetrics,
+	ConnectionState, Key, KeyNamespace, ResolveError, RoundIndex, TiKVClient,
+};
+use log::{debug, warn};
+
+/// Number of history entries pruned in a single transaction.
+const PRUNE_BATCH: u32 = 256;
+
+/// Key of the history entry of the claimed index.
+pub(crate) fn entry_key<V: ClaimValue>(namespace: &KeyNamespace, key: &Key, index: &V) -> String {
+	namespace.key(&format!("claims/{}/{}", key.as_str(), index.history_key()))
+}
+
+/// Key the corrupt history entry of the duty is moved to, so that it's kept out of the way of the
+/// pruning.
+fn corrupt_key(namespace: &KeyNamespace, key: &Key, index: &str) -> String {
+	namespace.key(&format!("corrupt/claims/{}/{}", key.as_str(), index))
+}
+
+/// Range of the keys of the history entries of the duty, the end is exclusive.
+fn duty_range(namespace: &KeyNamespace, key: &Key) -> (String, String) {
+	(
+		namespace.key(&format!("claims/{}/", key.as_str())),
+		// '0' follows '/', so the range covers every key under the prefix
+		namespace.key(&format!("claims/{}0", key.as_str())),
+	)
+}
+
+/// History of the claims of every duty, kept for the post-incident forensics. The claims are
+/// recorded by the resolver, this prunes the old ones.
+pub struct ClaimHistory {
+	pub(crate) client: Box<dyn TiKVClient>,
+	pub(crate) namespace: KeyNamespace,
+	pub(crate) metrics: Option<BackendMetrics>,
+}
+
+impl ClaimHistory {
+	/// Removes the claims made before the given wall-clock time in milliseconds since the UNIX
+	/// epoch, returns the number of removed entries.
+	pub async fn prune(&self, before_ms: u64) -> Result<usize, ResolveError> {
+		Ok(self.prune_duty::<u64>(Key::SLOT, before_ms).await? +
+			self.prune_duty::<RoundIndex>(Key::ROUND, before_ms).await? +
+			self.prune_duty::<u64>(Key::SESSION, before_ms).await?)
+	}
+
+	/// Entries are visited in the order of the indexes, so pruning stops at the first entry that
+	/// is recent enough. Corrupt entries are logged and moved under the `corrupt/` prefix, as their
+	/// time can't be told, they aren't counted as pruned.
+	async fn prune_duty<V: ClaimValue>(
+		&self,
+		key: Key,
+		before_ms: u64,
+	) -> Result<usize, ResolveError> {
+		if self.client.connection_state() == ConnectionState::Connecting {
+			return Err(ResolveError::Disconnected)
+		}
+		let (start, end) = duty_range(&self.namespace, &key);
+		let mut pruned = 0;
+		loop {
+			let mut txn = self.client.begin_optimistic().await.map_err(ResolveError::Begin)?;
+			let entries = match txn.scan(start.clone(), end.clone(), PRUNE_BATCH).await {
+				Ok(entries) => entries,
+				Err(source) => {
+					txn.rollback().await.map_err(ResolveError::Rollback)?;
+					return Err(ResolveError::Read { key: key.as_str().to_owned(), source })
+				},
+			};
+			let scanned = entries.len();
+			let (mut stale, mut corrupt) = (Vec::new(), Vec::new());
+			for (entry, value) in entries {
+				let record = match ClaimRecord::<V>::deserialize(&value) {
+					Ok(record) => record,
+					Err(reason) => {
+						let index = entry[start.len()..].to_owned();
+						let e = ResolveError::Corrupt {
+							key: entry.clone(),
+							reason,
+							value: value.clone(),
+						};
+						warn!(target: "permission-resolver", "{}, moving it out of the history", e);
+						if let Some(metrics) = &self.metrics {
+							metrics.observe_corrupt_value(key.label());
+						}
+						stale.push(entry);
+						corrupt.push((corrupt_key(&self.namespace, &key, &index), value));
+						continue
+					},
+				};
+				if record.timestamp_ms >= before_ms {
+					break
+				}
+				stale.push(entry);
+			}
+			let complete = stale.len() < scanned || scanned < PRUNE_BATCH as usize;
+			if stale.is_empty() {
+				txn.rollback().await.map_err(ResolveError::Rollback)?;
+				return Ok(pruned)
+			}
+			let (count, moved) = (stale.len() - corrupt.len(), corrupt.len());
+			for (entry, value) in corrupt {
+				if let Err(source) = txn.put(entry, value).await {
+					txn.rollback().await.map_err(ResolveError::Rollback)?;
+					return Err(ResolveError::Write { key: key.as_str().to_owned(), source })
+				}
+			}
+			for entry in stale {
+				if let Err(source) = txn.delete(entry).await {
+					txn.rollback().await.map_err(ResolveError::Rollback)?;
+					return Err(ResolveError::Write { key: key.as_str().to_owned(), source })
+				}
+			}
+			txn.commit().await.map_err(ResolveError::Commit)?;
+			pruned += count;
+			debug!(
+				target: "permission-resolver",
+				"Pruned {} and moved {} corrupt {} claim(s) from the history",
+				count,
+				moved,
+				key.as_str(),
+			);
+			if complete {
+				return Ok(pruned)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::InMemoryTiKVClient, RemoteAuthorityPermissionResolver};
+	use sp_authority_permission::PermissionResolver;
+
+	async fn replica(client: &InMemoryTiKVClient) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+			.await
+			.with_replica("alice".to_owned(), "4.0.0-dev".to_owned())
+			.with_history(true)
+	}
+
+	fn history(client: &InMemoryTiKVClient) -> ClaimHistory {
+		ClaimHistory {
+			client: Box::new(client.clone()),
+			namespace: KeyNamespace::Legacy,
+			metrics: None,
+		}
+	}
+
+	fn entries(client: &InMemoryTiKVClient, key: Key) -> Vec<String> {
+		let (start, end) = duty_range(&KeyNamespace::Legacy, &key);
+		let data = client.data.lock().unwrap();
+		let mut entries: Vec<_> =
+			data.keys().filter(|entry| **entry >= start && **entry < end).cloned().collect();
+		entries.sort();
+		entries
+	}
+
+	#[tokio::test]
+	async fn test_records_every_claim() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(alice.resolve_slot(6.into()).await);
+		assert!(!alice.resolve_slot(6.into()).await);
+		assert!(alice.resolve_round(1).await);
+
+		let slot_entry = |slot: u64| entry_key(&KeyNamespace::Legacy, &Key::SLOT, &slot);
+		assert_eq!(entries(&client, Key::SLOT), vec![slot_entry(5), slot_entry(6)]);
+		assert_eq!(entries(&client, Key::ROUND).len(), 1);
+		let data = client.data.lock().unwrap();
+		let entry = &data[&slot_entry(6)];
+		let record = ClaimRecord::<u64>::deserialize(entry).unwrap();
+		assert_eq!(record.replica_id, "alice");
+		assert_eq!(record.fencing_token, 2);
+	}
+
+	#[tokio::test]
+	async fn test_does_not_record_without_history() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await.with_history(false);
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(entries(&client, Key::SLOT).is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_prunes_claims_made_before_given_time() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		for slot in 1..=300u64 {
+			assert!(alice.resolve_slot(slot.into()).await);
+		}
+		assert!(alice.resolve_session(1).await);
+
+		assert_eq!(history(&client).prune(0).await.unwrap(), 0);
+		assert_eq!(history(&client).prune(u64::MAX).await.unwrap(), 301);
+		assert!(entries(&client, Key::SLOT).is_empty());
+		assert!(entries(&client, Key::SESSION).is_empty());
+		assert!(client.data.lock().unwrap().contains_key(Key::SLOT.as_str()));
+	}
+
+	#[tokio::test]
+	async fn test_keeps_recent_claims() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let old = ClaimRecord { timestamp_ms: 10, ..ClaimRecord::new(1u64, "alice", "", 1) };
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(entry_key(&KeyNamespace::Legacy, &Key::SLOT, &1u64), old.serialize());
+		assert!(alice.resolve_slot(2.into()).await);
+
+		assert_eq!(history(&client).prune(11).await.unwrap(), 1);
+		assert_eq!(
+			entries(&client, Key::SLOT),
+			vec![entry_key(&KeyNamespace::Legacy, &Key::SLOT, &2u64)]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_moves_corrupt_claims_out_of_history() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		assert!(alice.resolve_slot(1.into()).await);
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(entry_key(&KeyNamespace::Legacy, &Key::SLOT, &2u64), b"corrupt".to_vec());
+		assert!(alice.resolve_slot(3.into()).await);
+
+		let registry = prometheus_endpoint::Registry::new();
+		let history = ClaimHistory {
+			metrics: Some(BackendMetrics::new(&registry, None).unwrap()),
+			..history(&client)
+		};
+		assert_eq!(history.prune(u64::MAX).await.unwrap(), 2);
+		assert!(entries(&client, Key::SLOT).is_empty());
+		let moved = corrupt_key(&KeyNamespace::Legacy, &Key::SLOT, &2u64.history_key());
+		assert_eq!(client.data.lock().unwrap()[&moved], b"corrupt".to_vec());
+		let corrupt_values = registry
+			.gather()
+			.into_iter()
+			.find(|family| family.get_name() == "substrate_authority_permission_corrupt_values")
+			.unwrap();
+		assert_eq!(corrupt_values.get_metric()[0].get_counter().get_value(), 1.0);
+	}
+}
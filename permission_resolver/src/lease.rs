@@ -0,0 +1,474 @@
+use crate::{
+	abort,
+	duty::{invalid_duty, DutyClaims},
+	is_write_conflict,
+	metrics::BackendMetrics,
+	permission_or_fail_policy,
+	policy::Contention,
+	timeout::Deadline,
+	ConnectionState, DutyTimeouts, FailPolicies, Key, KeyNamespace, ResolveError, TiKVClient,
+	TryPermissionResolver,
+};
+use async_trait::async_trait;
+use codec::{Decode, DecodeAll, Encode};
+use log::{debug, info, warn};
+use sp_authority_permission::PermissionResolver;
+use sp_consensus_slots::Slot;
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long the lease of the leading replica lasts and when it's renewed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeasePolicy {
+	/// Time the lease is valid for after it's taken or renewed.
+	pub duration: Duration,
+	/// Time before the expiry at which the leader renews the lease, has to be longer than the
+	/// time between the duties so that the leader gets the chance to renew it.
+	pub renew_before: Duration,
+	/// Maximum difference between the clocks of the replicas. The leader stops using the lease
+	/// this long before its expiry, the standbys take it over only this long after it.
+	pub max_clock_drift: Duration,
+}
+
+impl LeasePolicy {
+	/// Lease lasting ten slots, renewed with five slots left and tolerating clocks a slot apart.
+	pub fn within_slots(slot_duration: Duration) -> LeasePolicy {
+		LeasePolicy {
+			duration: slot_duration * 10,
+			renew_before: slot_duration * 5,
+			max_clock_drift: slot_duration,
+		}
+	}
+}
+
+/// Lease stored in TiKV.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub(crate) struct LeaseRecord {
+	/// Replica holding the lease.
+	pub holder: String,
+	/// Incremented every time the lease changes hands.
+	pub term: u64,
+	/// Wall-clock time of the expiry in milliseconds since the UNIX epoch, by the holder's clock.
+	pub expires_at_ms: u64,
+}
+
+#[derive(Encode, Decode)]
+enum VersionedLeaseRecord {
+	#[codec(index = 1)]
+	V1(LeaseRecord),
+}
+
+impl LeaseRecord {
+	pub(crate) fn serialize(&self) -> Vec<u8> {
+		VersionedLeaseRecord::V1(self.clone()).encode()
+	}
+
+	pub(crate) fn deserialize(mut value: &[u8]) -> Result<LeaseRecord, &'static str> {
+		match VersionedLeaseRecord::decode_all(&mut value) {
+			Ok(VersionedLeaseRecord::V1(record)) => Ok(record),
+			Err(_) => Err("malformed lease record"),
+		}
+	}
+}
+
+/// Source of the wall-clock time in milliseconds since the UNIX epoch.
+pub(crate) type WallClock = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+pub(crate) fn system_clock() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Permission resolver letting a single replica perform every duty while it holds a lease in
+/// TiKV, instead of racing the other replicas for each of them. The standbys take the lease over
+/// once it expires.
+pub struct LeaseAuthorityPermissionResolver {
+	client: Box<dyn TiKVClient>,
+	namespace: KeyNamespace,
+	replica_id: String,
+	policy: LeasePolicy,
+	clock: WallClock,
+	/// Expiry of the lease held by this replica.
+	held_until_ms: Mutex<Option<u64>>,
+	fail_policies: FailPolicies,
+	contention: Contention,
+	timeouts: DutyTimeouts,
+	metrics: Option<BackendMetrics>,
+}
+
+impl LeaseAuthorityPermissionResolver {
+	/// Replicas competing for the lease have to use distinct ids.
+	pub fn new(
+		client: Box<dyn TiKVClient>,
+		namespace: KeyNamespace,
+		replica_id: String,
+		policy: LeasePolicy,
+	) -> LeaseAuthorityPermissionResolver {
+		LeaseAuthorityPermissionResolver {
+			client,
+			namespace,
+			replica_id,
+			policy,
+			clock: Arc::new(system_clock),
+			held_until_ms: Mutex::new(None),
+			fail_policies: FailPolicies::default(),
+			contention: Contention::default(),
+			timeouts: DutyTimeouts::default(),
+			metrics: None,
+		}
+	}
+
+	/// Decide duties with the given policies when the lease can't be checked.
+	pub fn with_fail_policies(
+		mut self,
+		fail_policies: FailPolicies,
+	) -> LeaseAuthorityPermissionResolver {
+		self.fail_policies = fail_policies;
+		self
+	}
+
+	/// Give up checking the lease for each duty after the given time.
+	pub fn with_timeouts(mut self, timeouts: DutyTimeouts) -> LeaseAuthorityPermissionResolver {
+		self.timeouts = timeouts;
+		self
+	}
+
+	pub(crate) fn with_metrics(
+		mut self,
+		metrics: Option<BackendMetrics>,
+	) -> LeaseAuthorityPermissionResolver {
+		self.metrics = metrics;
+		self
+	}
+
+	#[cfg(test)]
+	fn with_clock(mut self, clock: WallClock) -> LeaseAuthorityPermissionResolver {
+		self.clock = clock;
+		self
+	}
+
+	/// Whether this replica leads, taking or renewing the lease if needed.
+	async fn lead(&self, key: Key, timeout: Option<Duration>) -> Result<bool, ResolveError> {
+		let now = (self.clock)();
+		let held_until = *self.held_until_ms.lock().unwrap();
+		if held_until.map_or(false, |until| now + millis(self.policy.renew_before) < until) {
+			return Ok(true)
+		}
+		let result = self.acquire(now, Deadline::after(timeout)).await;
+		let leads = match result {
+			Ok(acquired) => acquired,
+			// the lease is still ours although it couldn't be renewed
+			Err(e) if held_until.map_or(false, |until| now < until) => {
+				warn!(
+					target: "permission-resolver",
+					"Could not renew the lease for {}, reason: {}", key.as_str(), e
+				);
+				true
+			},
+			Err(e) => {
+				self.set_held_until(None);
+				return Err(e)
+			},
+		};
+		self.contention.record(leads);
+		Ok(leads)
+	}
+
+	/// Takes the lease if it's free or expired, renews it if it's already ours.
+	async fn acquire(&self, now: u64, deadline: Deadline) -> Result<bool, ResolveError> {
+		if self.client.connection_state() == ConnectionState::Connecting {
+			return Err(ResolveError::Disconnected)
+		}
+		let key = Key::LEASE;
+		let path = self.namespace.key(key.as_str());
+		let mut txn = deadline
+			.run(self.client.begin_optimistic())
+			.await?
+			.map_err(ResolveError::Begin)?;
+		let stored = match deadline.run(txn.get_for_update(path.clone())).await {
+			Ok(Ok(Some(stored))) => match LeaseRecord::deserialize(&stored) {
+				Ok(record) => Some(record),
+				Err(reason) => {
+					let e = ResolveError::Corrupt {
+						key: key.as_str().to_owned(),
+						reason,
+						value: stored,
+					};
+					return Err(abort(txn, e))
+				},
+			},
+			Ok(Ok(None)) => None,
+			Ok(Err(source)) =>
+				return Err(abort(txn, ResolveError::Read { key: key.as_str().to_owned(), source })),
+			Err(e) => return Err(abort(txn, e)),
+		};
+		let term = match stored {
+			None => 1,
+			Some(record) if record.holder == self.replica_id => record.term,
+			Some(record) if now > record.expires_at_ms + millis(self.policy.max_clock_drift) =>
+				record.term + 1,
+			Some(record) => {
+				debug!(
+					target: "permission-resolver",
+					"Lease is held by {} until {} ms", record.holder, record.expires_at_ms
+				);
+				self.set_held_until(None);
+				match deadline.run(txn.rollback()).await {
+					Ok(result) => result.map_err(ResolveError::Rollback)?,
+					Err(e) => return Err(abort(txn, e)),
+				}
+				return Ok(false)
+			},
+		};
+		let expires_at_ms = now + millis(self.policy.duration);
+		let record = LeaseRecord { holder: self.replica_id.clone(), term, expires_at_ms };
+		match deadline.run(txn.put(path, record.serialize())).await {
+			Ok(Ok(())) => {},
+			Ok(Err(source)) =>
+				return Err(abort(txn, ResolveError::Write { key: key.as_str().to_owned(), source })),
+			Err(e) => return Err(abort(txn, e)),
+		}
+		match deadline.run(txn.commit()).await {
+			Ok(Ok(_)) => {},
+			// another standby took the lease first, or a concurrent duty of this replica renewed it
+			Ok(Err(e)) if is_write_conflict(&e) => return Ok(self.holds(now)),
+			Ok(Err(e)) => return Err(ResolveError::Commit(e)),
+			Err(e) => return Err(abort(txn, e)),
+		}
+		let renewed = self.held_until_ms.lock().unwrap().is_some();
+		if !renewed {
+			info!(target: "permission-resolver", "Took the lease in term {}", term);
+		}
+		self.set_held_until(Some(expires_at_ms - millis(self.policy.max_clock_drift)));
+		Ok(true)
+	}
+
+	fn holds(&self, now: u64) -> bool {
+		self.held_until_ms.lock().unwrap().map_or(false, |until| now < until)
+	}
+
+	fn set_held_until(&self, held_until_ms: Option<u64>) {
+		*self.held_until_ms.lock().unwrap() = held_until_ms;
+		if let Some(metrics) = &self.metrics {
+			metrics.set_leader(held_until_ms.is_some());
+		}
+	}
+}
+
+pub(crate) fn millis(duration: Duration) -> u64 {
+	duration.as_millis() as u64
+}
+
+#[async_trait]
+impl TryPermissionResolver for LeaseAuthorityPermissionResolver {
+	async fn try_resolve_slot(&self, _: Slot) -> Result<bool, ResolveError> {
+		self.lead(Key::SLOT, self.timeouts.slot).await
+	}
+
+	async fn try_resolve_round(&self, _: u64) -> Result<bool, ResolveError> {
+		self.lead(Key::ROUND, self.timeouts.round).await
+	}
+
+	async fn try_resolve_session(&self, _: u32) -> Result<bool, ResolveError> {
+		self.lead(Key::SESSION, self.timeouts.session).await
+	}
+
+	async fn try_resolve_duty(&self, duty: &str, _: u64) -> Result<bool, ResolveError> {
+		self.lead(Key::duty(duty)?, self.timeouts.duty).await
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for LeaseAuthorityPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		let result = self.try_resolve_slot(slot).await;
+		permission_or_fail_policy(Key::SLOT, self.fail_policies.slot, &self.contention, result)
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		let result = self.try_resolve_round(round).await;
+		permission_or_fail_policy(Key::ROUND, self.fail_policies.round, &self.contention, result)
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		let result = self.try_resolve_session(session_index).await;
+		permission_or_fail_policy(
+			Key::SESSION,
+			self.fail_policies.session,
+			&self.contention,
+			result,
+		)
+	}
+}
+
+/// Every duty is performed by the lease holder.
+#[async_trait]
+impl DutyClaims for LeaseAuthorityPermissionResolver {
+	async fn claim(&self, duty: &str, index: u64) -> bool {
+		let key = match Key::duty(duty) {
+			Ok(key) => key,
+			Err(e) => return invalid_duty(e),
+		};
+		let result = self.try_resolve_duty(duty, index).await;
+		permission_or_fail_policy(key, self.fail_policies.duty, &self.contention, result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::InMemoryTiKVClient;
+	use std::sync::atomic::{AtomicU64, Ordering};
+
+	const SLOT: Duration = Duration::from_secs(6);
+
+	fn clock(now: &Arc<AtomicU64>) -> WallClock {
+		let now = now.clone();
+		Arc::new(move || now.load(Ordering::SeqCst))
+	}
+
+	fn replica(
+		client: &InMemoryTiKVClient,
+		replica_id: &str,
+		now: &Arc<AtomicU64>,
+	) -> LeaseAuthorityPermissionResolver {
+		LeaseAuthorityPermissionResolver::new(
+			Box::new(client.clone()),
+			KeyNamespace::Legacy,
+			replica_id.to_owned(),
+			LeasePolicy::within_slots(SLOT),
+		)
+		.with_clock(clock(now))
+	}
+
+	fn stored_lease(client: &InMemoryTiKVClient) -> LeaseRecord {
+		let data = client.data.lock().unwrap();
+		LeaseRecord::deserialize(data.get(Key::LEASE.as_str()).unwrap()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_only_leader_performs_duties() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		let bob = replica(&client, "bob", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+		assert!(!bob.resolve_slot(1.into()).await);
+		assert!(alice.resolve_round(1).await);
+		assert!(!bob.resolve_round(1).await);
+		assert!(alice.resolve_session(1).await);
+		assert!(!bob.resolve_session(1).await);
+		assert!(alice.claim("price-feed", 1).await);
+		assert!(!bob.claim("price-feed", 1).await);
+	}
+
+	#[tokio::test]
+	async fn test_leader_does_not_ask_tikv_while_lease_is_fresh() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+		now.fetch_add(millis(SLOT), Ordering::SeqCst);
+		assert!(alice.resolve_slot(2.into()).await);
+		assert_eq!(client.begins.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_standby_takes_over_expired_lease() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		let bob = replica(&client, "bob", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+
+		// expired, but not by more than the clock drift
+		now.fetch_add(millis(SLOT * 11), Ordering::SeqCst);
+		assert!(!bob.resolve_slot(2.into()).await);
+
+		now.fetch_add(millis(SLOT), Ordering::SeqCst);
+		assert!(bob.resolve_slot(3.into()).await);
+		assert_eq!(stored_lease(&client).holder, "bob");
+		assert_eq!(stored_lease(&client).term, 2);
+		assert!(!alice.resolve_slot(3.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_leader_renews_lease_before_expiry() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		let bob = replica(&client, "bob", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+		for slot in 2..30 {
+			now.fetch_add(millis(SLOT), Ordering::SeqCst);
+			assert!(alice.resolve_slot(slot.into()).await);
+			assert!(!bob.resolve_slot(slot.into()).await);
+		}
+		assert_eq!(stored_lease(&client).term, 1);
+	}
+
+	#[tokio::test]
+	async fn test_leader_stops_before_standby_may_take_over() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+		let expires_at = stored_lease(&client).expires_at_ms;
+
+		client.unavailable.store(true, Ordering::SeqCst);
+		now.store(expires_at - millis(SLOT) - 1, Ordering::SeqCst);
+		assert!(alice.resolve_slot(2.into()).await);
+		now.store(expires_at - millis(SLOT), Ordering::SeqCst);
+		assert!(!alice.resolve_slot(3.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_single_standby_wins_racing_takeover() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		let bob = replica(&client, "bob", &now);
+		let carol = replica(&client, "carol", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+
+		now.fetch_add(millis(SLOT * 20), Ordering::SeqCst);
+		let (bob_leads, carol_leads) =
+			tokio::join!(bob.try_resolve_slot(2.into()), carol.try_resolve_slot(2.into()));
+		assert!(bob_leads.unwrap() ^ carol_leads.unwrap());
+		assert_eq!(stored_lease(&client).term, 2);
+	}
+
+	#[tokio::test]
+	async fn test_renewal_racing_takeover_keeps_single_leader() {
+		let client = InMemoryTiKVClient::default();
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		let bob = replica(&client, "bob", &now);
+		assert!(alice.resolve_slot(1.into()).await);
+
+		// alice renews late, while bob finds the lease expired
+		now.fetch_add(millis(SLOT * 12), Ordering::SeqCst);
+		let (alice_leads, bob_leads) =
+			tokio::join!(alice.try_resolve_slot(2.into()), bob.try_resolve_slot(2.into()));
+		assert!(alice_leads.unwrap() ^ bob_leads.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_rejects_malformed_lease() {
+		let client = InMemoryTiKVClient::default();
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(Key::LEASE.as_str().to_owned(), vec![1, 2, 3]);
+		let now = Arc::new(AtomicU64::new(1_000_000));
+		let alice = replica(&client, "alice", &now);
+		assert!(matches!(
+			alice.try_resolve_slot(1.into()).await,
+			Err(ResolveError::Corrupt { key, .. }) if key == "lease"
+		));
+	}
+}
@@ -25,50 +25,286 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
-use crate::cache::PermissionResolverCache;
+use crate::{
+	cache::{DutyClaimsCache, PermissionResolverCache},
+	cas::CasClient,
+	claim::{ClaimRecord, ClaimValue},
+	connection::ReconnectingTiKVClient,
+	discovery::Discovery,
+	metrics::BackendMetrics,
+	policy::Contention,
+	query::TiKVDutyQuery,
+	retry::Attempt,
+	timeout::Deadline,
+};
+pub use crate::{
+	claim::{AuthoritySetIdProvider, RoundIndex},
+	connection::{ConnectionState, SharedTiKVClients},
+	discovery::{DiscoveryError, PdEndpoints},
+	duty::DutyClaims,
+	error::{FactoryError, ResolveError},
+	fallback::{Fallback, FallbackDuties, FallbackPermissionResolver},
+	fork::ForkView,
+	history::ClaimHistory,
+	lease::{LeaseAuthorityPermissionResolver, LeasePolicy},
+	namespace::{KeyNamespace, KeyScope},
+	plugin::{
+		PluginEndpoint, PluginPermissionResolver, PluginPermissionResolverFactory,
+		PLUGIN_PROTOCOL_VERSION,
+	},
+	policy::{FailPolicies, FailPolicy},
+	preclaim::{PreClaimingPermissionResolver, SlotSchedule},
+	priority::{PriorityPermissionResolver, ReplicaPriority},
+	query::{Duty, DutyHolder, DutyQuery},
+	quorum::{
+		majority, QuorumDutyClaims, QuorumPermissionResolver, QuorumPermissionResolverFactory,
+	},
+	retry::RetryPolicy,
+	security::{TlsConfig, TlsConfigError},
+	shadow::{ShadowDutyClaims, ShadowPermissionResolver},
+	skew::ClockSkewGuard,
+	timeout::DutyTimeouts,
+};
 use async_trait::async_trait;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
 use sp_consensus_slots::Slot;
-use tikv_client::{transaction::Client, Error, Timestamp, Transaction, TransactionClient, Value};
+use sp_core::traits::SpawnNamed;
+use std::{
+	future::Future,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tikv_client::{
+	transaction::Client, Config, Error, KvPair, RawClient, Timestamp, Transaction,
+	TransactionClient, Value,
+};
 
 mod cache;
+mod cas;
+mod claim;
+mod connection;
+mod discovery;
+mod duty;
+mod error;
+mod fallback;
+mod fork;
+mod history;
+mod lease;
 mod metrics;
+mod namespace;
+mod plugin;
+mod policy;
+mod preclaim;
+mod priority;
+mod query;
+mod quorum;
+mod retry;
+mod security;
+mod shadow;
+mod skew;
+#[cfg(test)]
+mod testing;
+mod timeout;
 
+#[derive(Clone)]
 enum Key {
 	SLOT,
 	SESSION,
 	ROUND,
+	LEASE,
+	/// Named duty, see `Key::duty`.
+	DUTY(Arc<str>),
 }
 
 impl Key {
-	fn as_str(&self) -> &'static str {
+	fn as_str(&self) -> &str {
+		match self {
+			Key::SLOT => "slot",
+			Key::SESSION => "session",
+			Key::ROUND => "round",
+			Key::LEASE => "lease",
+			Key::DUTY(key) => key,
+		}
+	}
+
+	/// Label of the key in the metrics, the named duties share theirs so that the names don't
+	/// make up new time series.
+	fn label(&self) -> &'static str {
 		match self {
+			Key::DUTY(_) => "duty",
 			Key::SLOT => "slot",
 			Key::SESSION => "session",
 			Key::ROUND => "round",
+			Key::LEASE => "lease",
 		}
 	}
 }
 
+/// Permission resolver telling backend failures apart from denials, which its fail policies
+/// decide when it's asked as a `PermissionResolver`.
+#[async_trait]
+pub trait TryPermissionResolver: PermissionResolver {
+	async fn try_resolve_slot(&self, slot: Slot) -> Result<bool, ResolveError>;
+	async fn try_resolve_round(&self, round: u64) -> Result<bool, ResolveError>;
+	async fn try_resolve_session(&self, session_index: u32) -> Result<bool, ResolveError>;
+	async fn try_resolve_duty(&self, duty: &str, index: u64) -> Result<bool, ResolveError>;
+}
+
 #[async_trait]
 pub trait TiKVClient: Send + Sync {
 	async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error>;
+	/// Transaction locking the keys read with `get_for_update` until it ends.
+	async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error>;
+
+	fn connection_state(&self) -> ConnectionState {
+		ConnectionState::Connected
+	}
 }
 
 #[async_trait]
 pub trait TiKVTransaction: Send {
+	async fn get(&mut self, key: String) -> Result<Option<Value>, Error>;
 	async fn get_for_update(&mut self, key: String) -> Result<Option<Value>, Error>;
 	async fn put(&mut self, key: String, value: Vec<u8>) -> Result<(), Error>;
+	async fn delete(&mut self, key: String) -> Result<(), Error>;
+	/// Up to `limit` entries with keys from `start` inclusive to `end` exclusive, in key order.
+	async fn scan(
+		&mut self,
+		start: String,
+		end: String,
+		limit: u32,
+	) -> Result<Vec<(String, Value)>, Error>;
 	async fn commit(&mut self) -> Result<Option<Timestamp>, Error>;
 	async fn rollback(&mut self) -> Result<(), Error>;
 }
 
-async fn create_remote_authority_provider(
-	pd_addresses: Vec<String>,
-) -> RemoteAuthorityPermissionResolver {
-	let client = TransactionClient::new(pd_addresses).await.expect("Could not create client");
-	RemoteAuthorityPermissionResolver::new(Box::new(TiKVClientProxy { inner: client })).await
+/// RawKV client with the atomic operations, keys written by it aren't visible to the
+/// transactions.
+#[async_trait]
+pub trait TiKVRawClient: Send + Sync {
+	async fn get(&self, key: String) -> Result<Option<Value>, Error>;
+	/// Writes the value if the stored one is `previous`, returns the value stored before and
+	/// whether it was swapped.
+	async fn compare_and_swap(
+		&self,
+		key: String,
+		previous: Option<Value>,
+		value: Value,
+	) -> Result<(Option<Value>, bool), Error>;
+
+	fn connection_state(&self) -> ConnectionState {
+		ConnectionState::Connected
+	}
+}
+
+/// How the claims are written to TiKV.
+pub(crate) enum Backend {
+	/// Optimistic transaction per claim.
+	Transaction(Box<dyn TiKVClient>),
+	/// Atomic compare-and-swap of the raw value, a single round trip per uncontended claim.
+	CompareAndSwap(CasClient),
+}
+
+impl Backend {
+	fn connection_state(&self) -> ConnectionState {
+		match self {
+			Backend::Transaction(client) => client.connection_state(),
+			Backend::CompareAndSwap(cas) => cas.client.connection_state(),
+		}
+	}
+}
+
+fn connect(
+	discovery: Discovery,
+	tls: Option<&TlsConfig>,
+	metrics: Option<BackendMetrics>,
+	spawner: Option<&dyn SpawnNamed>,
+) -> ReconnectingTiKVClient {
+	let config = tls.map_or_else(Config::default, TlsConfig::client_config);
+	ReconnectingTiKVClient::spawn(
+		discovery,
+		move |pd_addresses| {
+			let config = config.clone();
+			async move {
+				let client = TransactionClient::new_with_config(pd_addresses, config).await?;
+				Ok(Box::new(TiKVClientProxy { inner: client }) as Box<dyn TiKVClient>)
+			}
+		},
+		metrics,
+		spawner,
+	)
+}
+
+fn connect_raw(
+	discovery: Discovery,
+	tls: Option<&TlsConfig>,
+	metrics: Option<BackendMetrics>,
+	spawner: Option<&dyn SpawnNamed>,
+) -> ReconnectingTiKVClient<dyn TiKVRawClient> {
+	let config = tls.map_or_else(Config::default, TlsConfig::client_config);
+	ReconnectingTiKVClient::spawn(
+		discovery,
+		move |pd_addresses| {
+			let config = config.clone();
+			async move {
+				let client = RawClient::new_with_config(pd_addresses, config).await?;
+				Ok(Box::new(TiKVRawClientProxy { inner: client.with_atomic_for_cas() })
+					as Box<dyn TiKVRawClient>)
+			}
+		},
+		metrics,
+		spawner,
+	)
+}
+
+/// Runs the background task on the spawner, or on the tokio runtime if not given.
+fn spawn(
+	spawner: Option<&dyn SpawnNamed>,
+	name: &'static str,
+	task: impl Future<Output = ()> + Send + 'static,
+) {
+	match spawner {
+		Some(spawner) => spawner.spawn(name, None, Box::pin(task)),
+		None => {
+			tokio::spawn(task);
+		},
+	}
+}
+
+#[async_trait]
+impl<C: TiKVClient + ?Sized> TiKVClient for Arc<C> {
+	async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		(**self).begin_optimistic().await
+	}
+
+	async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		(**self).begin_pessimistic().await
+	}
+
+	fn connection_state(&self) -> ConnectionState {
+		(**self).connection_state()
+	}
+}
+
+#[async_trait]
+impl<C: TiKVRawClient + ?Sized> TiKVRawClient for Arc<C> {
+	async fn get(&self, key: String) -> Result<Option<Value>, Error> {
+		(**self).get(key).await
+	}
+
+	async fn compare_and_swap(
+		&self,
+		key: String,
+		previous: Option<Value>,
+		value: Value,
+	) -> Result<(Option<Value>, bool), Error> {
+		(**self).compare_and_swap(key, previous, value).await
+	}
+
+	fn connection_state(&self) -> ConnectionState {
+		(**self).connection_state()
+	}
 }
 
 struct TiKVClientProxy {
@@ -80,6 +316,10 @@ impl TiKVClient for TiKVClientProxy {
 	async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
 		Ok(Box::new(TiKVTransactionProxy { inner: self.inner.begin_optimistic().await? }))
 	}
+
+	async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		Ok(Box::new(TiKVTransactionProxy { inner: self.inner.begin_pessimistic().await? }))
+	}
 }
 
 struct TiKVTransactionProxy {
@@ -88,6 +328,10 @@ struct TiKVTransactionProxy {
 
 #[async_trait]
 impl TiKVTransaction for TiKVTransactionProxy {
+	async fn get(&mut self, key: String) -> Result<Option<Value>, Error> {
+		self.inner.get(key).await
+	}
+
 	async fn get_for_update(&mut self, key: String) -> Result<Option<Value>, Error> {
 		self.inner.get_for_update(key).await
 	}
@@ -96,6 +340,24 @@ impl TiKVTransaction for TiKVTransactionProxy {
 		self.inner.put(key, value).await
 	}
 
+	async fn delete(&mut self, key: String) -> Result<(), Error> {
+		self.inner.delete(key).await
+	}
+
+	async fn scan(
+		&mut self,
+		start: String,
+		end: String,
+		limit: u32,
+	) -> Result<Vec<(String, Value)>, Error> {
+		let pairs = self.inner.scan(start..end, limit).await?;
+		Ok(pairs
+			.map(|KvPair(key, value)| {
+				(String::from_utf8_lossy(&Vec::<u8>::from(key)).into_owned(), value)
+			})
+			.collect())
+	}
+
 	async fn commit(&mut self) -> Result<Option<Timestamp>, Error> {
 		self.inner.commit().await
 	}
@@ -105,119 +367,948 @@ impl TiKVTransaction for TiKVTransactionProxy {
 	}
 }
 
+struct TiKVRawClientProxy {
+	inner: RawClient,
+}
+
+#[async_trait]
+impl TiKVRawClient for TiKVRawClientProxy {
+	async fn get(&self, key: String) -> Result<Option<Value>, Error> {
+		self.inner.get(key).await
+	}
+
+	async fn compare_and_swap(
+		&self,
+		key: String,
+		previous: Option<Value>,
+		value: Value,
+	) -> Result<(Option<Value>, bool), Error> {
+		self.inner.compare_and_swap(key, previous, value).await
+	}
+}
+
+const MISSING_SCOPE: &str = "Key scope is required unless legacy keys are used";
+const MISSING_FORK_VIEW: &str = "Fork view is required in the fork-aware mode";
+const MISSING_SLOT_SCHEDULE: &str = "Slot schedule is required to pre-claim the slots";
+const MISSING_SLOT_DURATION: &str = "Slot duration is required in the lease mode";
+const MISSING_REPLICA_ID: &str = "Replica id is required in the lease mode";
+
 pub struct RemoteAuthorityPermissionResolverFactory {
+	/// Addresses of the PD servers, or a single DNS SRV name or PD URL they are discovered by.
 	pub remote_urls: Vec<String>,
+	/// Time between the refreshes of the discovered PD servers.
+	pub discovery_refresh: Duration,
 	pub cached: bool,
+	/// Certificates securing the connections, plaintext is used if not given.
+	pub tls: Option<TlsConfig>,
+	/// Use the flat keys shared by every validator and chain on the cluster.
+	pub legacy_keys: bool,
+	/// Scope of the keys, has to be set unless `legacy_keys` is used.
+	pub scope: Option<KeyScope>,
+	/// Write the raw indexes read by the replicas that don't know the claim records yet.
+	pub legacy_values: bool,
+	/// Name of this replica recorded with its claims.
+	pub replica_id: Option<String>,
+	/// Version of the node recorded with the claims.
+	pub node_version: String,
+	/// Current GRANDPA authority set id, rounds are treated as rounds of the set 0 if not given.
+	pub authority_set_id: Option<Arc<dyn AuthoritySetIdProvider>>,
+	/// How duties are decided when TiKV can't be asked.
+	pub fail_policies: FailPolicies,
+	/// How transient TiKV failures are retried.
+	pub retry_policy: RetryPolicy,
+	/// Time limits of resolving the permission of each duty.
+	pub timeouts: DutyTimeouts,
+	/// Registry for the metrics of the TiKV backend.
+	pub prometheus_registry: Option<prometheus_endpoint::Registry>,
+	/// Label of the metrics of this cluster, has to be set when the validator asks several
+	/// clusters, so that their metrics can be registered with the same registry.
+	pub cluster: Option<String>,
+	/// Duration of a slot, slot claims aren't checked against the local clock if not given.
+	pub slot_duration: Option<Duration>,
+	/// Number of slots a claim may be away from the slot of the local clock.
+	pub max_slot_drift: u64,
+	/// Overwrite stored slots too far ahead of the local clock.
+	pub clear_skewed_slots: bool,
+	/// Let the replica holding a lease perform every duty instead of racing for each of them,
+	/// the lease is sized by `slot_duration`.
+	pub lease: bool,
+	/// Keep the history of the claims, not kept in the lease mode.
+	pub history: bool,
+	/// Claims younger than this are kept in the history even if finalized.
+	pub history_retention: Option<Duration>,
+	/// Claim with the RawKV compare-and-swap instead of the transactions. The keys aren't shared
+	/// with the replicas using the transactions, the history isn't kept and the lease mode
+	/// ignores it.
+	pub compare_and_swap: bool,
+	/// Lock the claimed key when reading it, so that the replicas losing the race don't write.
+	pub pessimistic: bool,
+	/// Hold back the slot claims of this replica behind the replicas of higher priority, needs
+	/// `slot_duration` and isn't used in the lease mode.
+	pub priority: Option<ReplicaPriority>,
+	/// Claim the next slot of `slot_schedule` in the background once this replica won a slot.
+	/// Replicas of lower priority don't, so that the replica of the highest priority takes the
+	/// slots back. Can't be used with `legacy_keys`, whose slot key is shared by the authorities.
+	pub pre_claim: bool,
+	/// Slots authored by this replica, the slots are only pre-claimed with it.
+	pub slot_schedule: Option<Arc<dyn SlotSchedule>>,
+	/// Record the parent block with the slot claims, so that a slot claimed on a block since
+	/// orphaned can be taken over. Needs `fork_view`, the slots aren't pre-claimed and the lease
+	/// mode ignores it.
+	pub fork_aware: bool,
+	/// Chain of this replica, used in the fork-aware mode.
+	pub fork_view: Option<Arc<dyn ForkView>>,
+	/// Resolver deciding the permissions instead of the claims, which are still made and recorded
+	/// in the background and compared with its decisions. The named duties are all granted then.
+	pub shadow: Option<Arc<dyn PermissionResolverFactory>>,
+	/// Backend some of the duties move to while TiKV can't be reached.
+	pub fallback: Option<Fallback>,
+	/// Runs the background tasks of the clients, so that they stop along with the node. The tokio
+	/// runtime is used if not given.
+	pub spawner: Option<Arc<dyn SpawnNamed>>,
+	/// Clients reused by everything the factory creates, `Default::default()` to start without
+	/// any.
+	pub clients: SharedTiKVClients,
+}
+
+impl RemoteAuthorityPermissionResolverFactory {
+	/// Checks that everything the configured mode needs is given, so that the node fails to
+	/// start instead of the factory falling back to what it can do.
+	pub fn validate(&self) -> Result<(), FactoryError> {
+		PdEndpoints::parse(&self.remote_urls)?.check_tls(self.tls.as_ref())?;
+		if let Some(tls) = &self.tls {
+			tls.validate()?;
+		}
+		let missing = if !self.legacy_keys && self.scope.is_none() {
+			Some(MISSING_SCOPE)
+		} else if self.fork_aware && self.fork_view.is_none() {
+			Some(MISSING_FORK_VIEW)
+		} else if self.pre_claim && self.slot_schedule.is_none() {
+			Some(MISSING_SLOT_SCHEDULE)
+		} else if self.lease && self.slot_duration.is_none() {
+			Some(MISSING_SLOT_DURATION)
+		} else if self.lease && self.replica_id.is_none() {
+			Some(MISSING_REPLICA_ID)
+		} else {
+			None
+		};
+		if let Some(missing) = missing {
+			return Err(FactoryError::Missing(missing))
+		}
+		if matches!(&self.priority, Some(priority) if !priority.is_within_slot()) {
+			return Err(FactoryError::Invalid(
+				"The priority delay has to be a non-negative fraction of the slot",
+			))
+		}
+		// the flat slot key only grows, a pre-claimed slot would deny the slots of the other
+		// authorities before it
+		if self.pre_claim && self.legacy_keys {
+			return Err(FactoryError::Invalid("Slots can't be pre-claimed with the legacy keys"))
+		}
+		// every replica of the validator would author and vote while the backend can't be reached
+		if matches!(&self.fallback, Some(fallback) if fallback.duties.slot || fallback.duties.round)
+		{
+			return Err(FactoryError::Invalid(
+				"Block authoring and GRANDPA voting can't fall back, only heartbeats can",
+			))
+		}
+		Ok(())
+	}
+
+	fn namespace(&self) -> Result<KeyNamespace, FactoryError> {
+		match &self.scope {
+			_ if self.legacy_keys => Ok(KeyNamespace::Legacy),
+			Some(scope) => Ok(KeyNamespace::Scoped(scope.clone())),
+			None => Err(FactoryError::Missing(MISSING_SCOPE)),
+		}
+	}
+
+	/// Metrics of the backend, registered with the first resolver.
+	fn metrics(&self) -> Option<BackendMetrics> {
+		let registry = self.prometheus_registry.as_ref()?;
+		self.clients.metrics(|| {
+			BackendMetrics::new(registry, self.cluster.as_deref())
+				.map_err(|e| {
+					warn!(
+						target: "permission-resolver",
+						"Could not register permission resolver metrics, reason: {}", e
+					)
+				})
+				.ok()
+		})
+	}
+
+	fn discovery(&self) -> Result<Discovery, FactoryError> {
+		let endpoints = PdEndpoints::parse(&self.remote_urls)?;
+		Ok(Discovery::new(endpoints, self.discovery_refresh).with_tls(self.tls.clone()))
+	}
+
+	fn transaction_client(&self) -> Result<Arc<ReconnectingTiKVClient>, FactoryError> {
+		let discovery = self.discovery()?;
+		Ok(self.clients.transaction(|| {
+			connect(discovery, self.tls.as_ref(), self.metrics(), self.spawner.as_deref())
+		}))
+	}
+
+	fn raw_client(&self) -> Result<Arc<ReconnectingTiKVClient<dyn TiKVRawClient>>, FactoryError> {
+		let discovery = self.discovery()?;
+		Ok(self.clients.raw(|| {
+			connect_raw(discovery, self.tls.as_ref(), self.metrics(), self.spawner.as_deref())
+		}))
+	}
+
+	fn backend(&self) -> Result<Backend, FactoryError> {
+		Ok(if self.compare_and_swap && !self.lease {
+			Backend::CompareAndSwap(CasClient::new(Box::new(self.raw_client()?)))
+		} else {
+			Backend::Transaction(Box::new(self.transaction_client()?))
+		})
+	}
+
+	/// Query of the duties claimed by the replicas configured like this one, it doesn't need a
+	/// resolver to be created, so it can be used by the tools working on the cluster as well.
+	pub fn create_query(&self) -> Result<Box<dyn DutyQuery>, FactoryError> {
+		Ok(Box::new(TiKVDutyQuery {
+			backend: self.backend()?,
+			namespace: self.namespace()?,
+			lease: self.lease,
+		}))
+	}
+
+	/// History of the claims, `None` if it isn't kept.
+	pub fn create_history(&self) -> Result<Option<ClaimHistory>, FactoryError> {
+		if !self.history || self.lease || self.compare_and_swap {
+			return Ok(None)
+		}
+		Ok(Some(ClaimHistory {
+			client: Box::new(self.transaction_client()?),
+			namespace: self.namespace()?,
+			metrics: self.metrics(),
+		}))
+	}
+
+	/// Claims of the named duties, made like the claims of the other duties. The claims can be
+	/// made by the node or by the offchain workers through the host functions.
+	pub fn create_duty_claims(&self) -> Result<Arc<dyn DutyClaims>, FactoryError> {
+		let claims: Box<dyn DutyClaims> =
+			if self.lease { Box::new(self.create_lease()?) } else { Box::new(self.create_race()?) };
+		let claims: Arc<dyn DutyClaims> =
+			if self.cached { Arc::new(DutyClaimsCache::new(claims)) } else { Arc::from(claims) };
+		if self.shadow.is_some() {
+			Ok(Arc::new(
+				ShadowDutyClaims::new(claims)
+					.with_spawner(self.spawner.clone())
+					.with_metrics(self.metrics()),
+			))
+		} else {
+			Ok(claims)
+		}
+	}
+
+	/// Resolver of the configured mode, or the reason it can't be created.
+	pub async fn try_create(&self) -> Result<Box<dyn PermissionResolver>, FactoryError> {
+		self.validate()?;
+		let primary: Box<dyn TryPermissionResolver> = match (self.priority, self.slot_duration) {
+			_ if self.lease => Box::new(self.create_lease()?),
+			(Some(priority), Some(slot_duration)) if priority.priority > 0 =>
+				Box::new(PriorityPermissionResolver::new(
+					Box::new(self.create_race()?),
+					priority,
+					slot_duration,
+				)),
+			_ if self.pre_claim && !self.fork_aware => {
+				let schedule = self
+					.slot_schedule
+					.clone()
+					.ok_or(FactoryError::Missing(MISSING_SLOT_SCHEDULE))?;
+				Box::new(
+					PreClaimingPermissionResolver::new(self.create_race()?, schedule)
+						.with_spawner(self.spawner.clone())
+						.with_metrics(self.metrics()),
+				)
+			},
+			_ => Box::new(self.create_race()?),
+		};
+		let resolver: Box<dyn PermissionResolver> = match &self.fallback {
+			Some(fallback) => Box::new(
+				FallbackPermissionResolver::new(
+					primary,
+					fallback.secondary.create().await,
+					fallback.duties,
+					fallback.hysteresis,
+				)
+				.with_metrics(self.metrics()),
+			),
+			None => Box::new(Primary(primary)),
+		};
+		let resolver: Box<dyn PermissionResolver> = match &self.shadow {
+			Some(inner) => Box::new(
+				ShadowPermissionResolver::new(resolver, inner.create().await)
+					.with_spawner(self.spawner.clone())
+					.with_metrics(self.metrics()),
+			),
+			None => resolver,
+		};
+		if self.cached {
+			let mut cache = PermissionResolverCache::new(resolver);
+			if let Some(authority_set_id) = &self.authority_set_id {
+				cache = cache.with_authority_set_id(authority_set_id.clone());
+			}
+			Ok(Box::new(cache))
+		} else {
+			Ok(resolver)
+		}
+	}
+
+	fn create_race(&self) -> Result<RemoteAuthorityPermissionResolver, FactoryError> {
+		let mut resolver =
+			RemoteAuthorityPermissionResolver::with_backend(self.backend()?, self.namespace()?)
+				.with_fail_policies(self.fail_policies)
+				.with_retry_policy(self.retry_policy)
+				.with_timeouts(self.timeouts)
+				.with_legacy_values(self.legacy_values)
+				.with_history(self.history)
+				.with_pessimistic(self.pessimistic);
+		resolver.metrics = self.metrics();
+		if let Some(slot_duration) = self.slot_duration {
+			resolver = resolver.with_skew_guard(ClockSkewGuard {
+				slot_duration,
+				max_drift: self.max_slot_drift,
+				clear_skewed: self.clear_skewed_slots,
+			});
+		}
+		if let Some(replica_id) = &self.replica_id {
+			resolver = resolver.with_replica(replica_id.clone(), self.node_version.clone());
+		}
+		if let Some(authority_set_id) = &self.authority_set_id {
+			resolver = resolver.with_authority_set_id(authority_set_id.clone());
+		}
+		if self.fork_aware {
+			let fork_view =
+				self.fork_view.clone().ok_or(FactoryError::Missing(MISSING_FORK_VIEW))?;
+			resolver = resolver.with_fork_view(fork_view);
+		}
+		Ok(resolver)
+	}
+
+	fn create_lease(&self) -> Result<LeaseAuthorityPermissionResolver, FactoryError> {
+		let slot_duration =
+			self.slot_duration.ok_or(FactoryError::Missing(MISSING_SLOT_DURATION))?;
+		let replica_id =
+			self.replica_id.clone().ok_or(FactoryError::Missing(MISSING_REPLICA_ID))?;
+		Ok(LeaseAuthorityPermissionResolver::new(
+			Box::new(self.transaction_client()?),
+			self.namespace()?,
+			replica_id,
+			LeasePolicy::within_slots(slot_duration),
+		)
+		.with_fail_policies(self.fail_policies)
+		.with_timeouts(self.timeouts)
+		.with_metrics(self.metrics()))
+	}
 }
 
 #[async_trait]
 impl PermissionResolverFactory for RemoteAuthorityPermissionResolverFactory {
+	/// Resolver of the configured mode, one denying every duty if the factory is invalid, so
+	/// `validate` should be checked first.
 	async fn create(&self) -> Box<dyn PermissionResolver> {
-		let resolver = create_remote_authority_provider(self.remote_urls.clone()).await;
-		if self.cached {
-			Box::new(PermissionResolverCache::new(Box::new(resolver)))
-		} else {
-			Box::new(resolver)
+		match self.try_create().await {
+			Ok(resolver) => resolver,
+			Err(e) => {
+				error!(
+					target: "permission-resolver",
+					"Could not create the permission resolver, denying every duty, reason: {}", e
+				);
+				Box::new(Denying)
+			},
 		}
 	}
 }
 
+/// Resolver denying every duty, in place of a resolver that couldn't be created.
+struct Denying;
+
+#[async_trait]
+impl PermissionResolver for Denying {
+	async fn resolve_slot(&self, _: Slot) -> bool {
+		false
+	}
+
+	async fn resolve_round(&self, _: u64) -> bool {
+		false
+	}
+
+	async fn resolve_session(&self, _: u32) -> bool {
+		false
+	}
+}
+
+/// Resolver of the permissions by the primary backend alone, its fail policies included.
+struct Primary(Box<dyn TryPermissionResolver>);
+
+#[async_trait]
+impl PermissionResolver for Primary {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		self.0.resolve_slot(slot).await
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		self.0.resolve_round(round).await
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		self.0.resolve_session(session_index).await
+	}
+}
+
 pub struct RemoteAuthorityPermissionResolver {
-	client: Box<dyn TiKVClient>,
+	backend: Backend,
+	namespace: KeyNamespace,
+	authority_set_id: Arc<dyn AuthoritySetIdProvider>,
+	fail_policies: FailPolicies,
+	contention: Contention,
+	retry_policy: RetryPolicy,
+	timeouts: DutyTimeouts,
+	metrics: Option<BackendMetrics>,
+	replica_id: String,
+	node_version: String,
+	legacy_values: bool,
+	skew_guard: Option<ClockSkewGuard>,
+	history: bool,
+	pessimistic: bool,
+	fork_view: Option<Arc<dyn ForkView>>,
 }
 
 impl RemoteAuthorityPermissionResolver {
-	async fn new(client: Box<dyn TiKVClient>) -> RemoteAuthorityPermissionResolver {
-		RemoteAuthorityPermissionResolver { client }
+	#[cfg(test)]
+	async fn new(
+		client: Box<dyn TiKVClient>,
+		namespace: KeyNamespace,
+	) -> RemoteAuthorityPermissionResolver {
+		Self::with_backend(Backend::Transaction(client), namespace)
+	}
+
+	fn with_backend(
+		backend: Backend,
+		namespace: KeyNamespace,
+	) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver {
+			backend,
+			namespace,
+			authority_set_id: Arc::new(|| 0),
+			fail_policies: FailPolicies::default(),
+			contention: Contention::default(),
+			retry_policy: RetryPolicy::default(),
+			timeouts: DutyTimeouts::default(),
+			metrics: None,
+			replica_id: String::new(),
+			node_version: String::new(),
+			legacy_values: false,
+			skew_guard: None,
+			history: false,
+			pessimistic: false,
+			fork_view: None,
+		}
+	}
+
+	/// Retry transient TiKV failures according to the given policy.
+	pub fn with_retry_policy(
+		mut self,
+		retry_policy: RetryPolicy,
+	) -> RemoteAuthorityPermissionResolver {
+		self.retry_policy = retry_policy;
+		self
+	}
+
+	/// Give up resolving the permission of each duty after the given time.
+	pub fn with_timeouts(mut self, timeouts: DutyTimeouts) -> RemoteAuthorityPermissionResolver {
+		self.timeouts = timeouts;
+		self
+	}
+
+	/// Decide duties with the given policies when TiKV can't be asked.
+	pub fn with_fail_policies(
+		mut self,
+		fail_policies: FailPolicies,
+	) -> RemoteAuthorityPermissionResolver {
+		self.fail_policies = fail_policies;
+		self
+	}
+
+	/// Use the given source of the GRANDPA authority set id when resolving rounds.
+	pub fn with_authority_set_id(
+		mut self,
+		authority_set_id: Arc<dyn AuthoritySetIdProvider>,
+	) -> RemoteAuthorityPermissionResolver {
+		self.authority_set_id = authority_set_id;
+		self
+	}
+
+	/// Record the given replica and node version with the claims.
+	pub fn with_replica(
+		mut self,
+		replica_id: String,
+		node_version: String,
+	) -> RemoteAuthorityPermissionResolver {
+		self.replica_id = replica_id;
+		self.node_version = node_version;
+		self
+	}
+
+	/// Write raw indexes instead of claim records, until every replica reads the records.
+	pub fn with_legacy_values(mut self, legacy_values: bool) -> RemoteAuthorityPermissionResolver {
+		self.legacy_values = legacy_values;
+		self
+	}
+
+	/// Refuse slot claims too far from the local clock and report stored slots too far ahead of
+	/// it.
+	pub fn with_skew_guard(
+		mut self,
+		skew_guard: ClockSkewGuard,
+	) -> RemoteAuthorityPermissionResolver {
+		self.skew_guard = Some(skew_guard);
+		self
+	}
+
+	/// Keep the record of every claim under `claims/<duty>/<index>`, besides the latest claim.
+	/// Not kept with the compare-and-swap, since the history is written in the claim transaction.
+	pub fn with_history(mut self, history: bool) -> RemoteAuthorityPermissionResolver {
+		self.history = history;
+		self
+	}
+
+	/// Claim in pessimistic transactions, the replicas contending for a claim wait for the lock of
+	/// the key and see the claim of the winner instead of failing at commit. Not used with the
+	/// compare-and-swap.
+	pub fn with_pessimistic(mut self, pessimistic: bool) -> RemoteAuthorityPermissionResolver {
+		self.pessimistic = pessimistic;
+		self
+	}
+
+	/// Record the block each slot is claimed on, so that a slot claimed on a block this replica
+	/// sees orphaned is taken over instead of being missed. A slot is never claimed twice on the
+	/// same block.
+	pub fn with_fork_view(
+		mut self,
+		fork_view: Arc<dyn ForkView>,
+	) -> RemoteAuthorityPermissionResolver {
+		self.fork_view = Some(fork_view);
+		self
+	}
+
+	/// Value written for the granted claim. Raw indexes left by older replicas are replaced with
+	/// claim records this way, one claim at a time.
+	fn claim_value<V: ClaimValue>(&self, record: &ClaimRecord<V>) -> Vec<u8> {
+		if self.legacy_values {
+			record.index.serialize()
+		} else {
+			record.serialize()
+		}
+	}
+
+	/// Value of the claim telling it apart from the claims of the other replicas, which the raw
+	/// indexes don't.
+	fn own_claim_value<V: ClaimValue>(&self, record: &ClaimRecord<V>) -> Option<Value> {
+		(!self.legacy_values).then(|| record.serialize())
+	}
+
+	/// Values written for the granted claim, along with its history entry if it's kept.
+	fn claim_writes<V: ClaimValue>(
+		&self,
+		key: &Key,
+		path: String,
+		record: &ClaimRecord<V>,
+	) -> Vec<(String, Vec<u8>)> {
+		let mut writes = vec![(path, self.claim_value(record))];
+		// the history of the named duties isn't pruned, so it isn't kept
+		if self.history && !key.is_duty() {
+			writes.push((
+				history::entry_key(&self.namespace, key, &record.index),
+				record.serialize(),
+			));
+		}
+		writes
+	}
+
+	/// Resolves the permission, retrying transient failures until the deadline of the retry
+	/// policy, within the time limit of the duty.
+	async fn resolve_with_retries<V: ClaimValue>(
+		&self,
+		key: Key,
+		value: V,
+		latest_plausible: Option<V>,
+		parent: Option<&[u8]>,
+		timeout: Option<Duration>,
+	) -> Result<bool, ResolveError> {
+		let started = Instant::now();
+		let mut attempt = Attempt { deadline: Deadline::after(timeout), unconfirmed: None };
+		let mut attempts = 1;
+		loop {
+			let result = match &self.backend {
+				Backend::Transaction(client) =>
+					self.do_resolve(&**client, &key, value, latest_plausible, parent, &mut attempt)
+						.await,
+				Backend::CompareAndSwap(cas) =>
+					self.do_compare_and_swap(
+						cas,
+						&key,
+						value,
+						latest_plausible,
+						parent,
+						&mut attempt,
+					)
+					.await,
+			};
+			if let Err(e) = &result {
+				let backoff = self.retry_policy.backoff(attempts);
+				if e.is_retryable() &&
+					started.elapsed() + backoff < self.retry_policy.deadline &&
+					attempt.deadline.allows(backoff)
+				{
+					warn!(
+						target: "permission-resolver",
+						"Attempt {} to resolve {} {} permission failed, retrying in {:?}, reason: {}",
+						attempts,
+						key.as_str(),
+						value,
+						backoff,
+						e,
+					);
+					tokio::time::sleep(backoff).await;
+					attempts += 1;
+					continue
+				}
+			}
+			debug!(
+				target: "permission-resolver",
+				"Resolved {} {} permission in {} attempt(s)", key.as_str(), value, attempts
+			);
+			if let Some(metrics) = &self.metrics {
+				metrics.observe_attempts(key.label(), attempts);
+			}
+			return result
+		}
 	}
 
 	///Tries to optimistically update the value if it's less than current,
 	/// if the operation is successful we treat it as permission granted.
-	async fn do_resolve(&self, key: Key, value: u64) -> Result<bool, String> {
+	/// Stored values after `latest_plausible` are reported as skewed.
+	async fn do_resolve<V: ClaimValue>(
+		&self,
+		client: &dyn TiKVClient,
+		key: &Key,
+		value: V,
+		latest_plausible: Option<V>,
+		parent: Option<&[u8]>,
+		attempt: &mut Attempt,
+	) -> Result<bool, ResolveError> {
+		let deadline = attempt.deadline;
 		debug!(target: "permission-resolver", "Checking {} {} permission...", key.as_str(), value);
-		let mut txn = self
-			.client
-			.begin_optimistic()
-			.await
-			.map_err(|e| format!("Could not start transaction, reason: {}", e))?;
-		let can = txn
-			.get_for_update(key.as_str().to_owned())
-			.await
-			.map_err(|e| format!("Could not get {} value for update, reason: {}", key.as_str(), e))?
-			.map_or(true, |v| value > deserialize_u64(v));
-		if can {
-			txn.put(key.as_str().to_owned(), u64::to_be_bytes(value).to_vec())
-				.await
-				.map_err(|e| format!("Could not put {} value, reason {}", key.as_str(), e))?;
-			match txn.commit().await {
-				Ok(_) => {},
-				Err(ref e) => {
-					match e {
-						Error::KeyError(inner_e) => {
-							if inner_e.conflict.is_some() {
-								//conflict indicates that somebody was faster reserving
-								// slot/session/round
-								return Ok(false)
-							} else {
-								return Err(format!("Could not commit transaction, reason {}", e))
-							}
-						},
-						e => return Err(format!("Could not commit transaction, reason {}", e)),
-					}
+		if client.connection_state() == ConnectionState::Connecting {
+			return Err(ResolveError::Disconnected)
+		}
+		let path = self.namespace.key(key.as_str());
+		let begin =
+			if self.pessimistic { client.begin_pessimistic() } else { client.begin_optimistic() };
+		let mut txn = deadline.run(begin).await?.map_err(ResolveError::Begin)?;
+		let claim = match deadline.run(txn.get_for_update(path.clone())).await {
+			Ok(Ok(stored)) =>
+				match self.check_claim(key, value, latest_plausible, parent, stored, attempt) {
+					Ok(checked) => checked,
+					Err(e) => return Err(abort(txn, e)),
 				},
+			Ok(Err(e)) if is_write_conflict(&e) => {
+				//a faster replica claimed while the pessimistic transaction waited for the lock
+				debug!(
+					target: "permission-resolver",
+					"Denied {} {} permission, claimed meanwhile", key.as_str(), value
+				);
+				None
+			},
+			Ok(Err(source)) =>
+				return Err(abort(txn, ResolveError::Read { key: key.as_str().to_owned(), source })),
+			Err(e) => return Err(abort(txn, e)),
+		};
+		let can = claim.is_some();
+		if let Some(record) = claim {
+			for (path, claim) in self.claim_writes(key, path, &record) {
+				match deadline.run(txn.put(path, claim)).await {
+					Ok(Ok(())) => {},
+					Ok(Err(source)) =>
+						return Err(abort(
+							txn,
+							ResolveError::Write { key: key.as_str().to_owned(), source },
+						)),
+					Err(e) => return Err(abort(txn, e)),
+				}
 			}
+			match deadline.run(txn.commit()).await {
+				Ok(Ok(_)) => {},
+				Ok(Err(e)) if is_write_conflict(&e) => {
+					//conflict indicates that somebody was faster reserving
+					// slot/session/round
+					self.contention.record(false);
+					return Ok(false)
+				},
+				Ok(Err(e)) => {
+					attempt.unconfirmed = self.own_claim_value(&record);
+					return Err(ResolveError::Commit(e))
+				},
+				Err(e) => {
+					// the commit may still land, so it isn't rolled back, the transaction is left
+					// to the lock resolution of TiKV
+					attempt.unconfirmed = self.own_claim_value(&record);
+					return Err(e)
+				},
+			}
+			debug!(
+				target: "permission-resolver",
+				"Granted {} {} permission with fencing token {}",
+				key.as_str(),
+				value,
+				record.fencing_token,
+			);
 		} else {
-			txn.rollback()
-				.await
-				.map_err(|e| format!("Could not rollback transaction, reason {}", e))?;
+			match deadline.run(txn.rollback()).await {
+				Ok(result) => result.map_err(ResolveError::Rollback)?,
+				Err(e) => return Err(abort(txn, e)),
+			}
 		}
+		self.contention.record(can);
 		Ok(can)
 	}
+
+	/// Claim to write over the stored claim, `None` if the value may not be claimed. A claim
+	/// built on the given parent records it after the parents the holder claimed on.
+	fn check_claim<V: ClaimValue>(
+		&self,
+		key: &Key,
+		value: V,
+		latest_plausible: Option<V>,
+		parent: Option<&[u8]>,
+		stored: Option<Value>,
+		attempt: &Attempt,
+	) -> Result<Option<ClaimRecord<V>>, ResolveError> {
+		let claim = |fencing_token, parents| {
+			let record =
+				ClaimRecord::new(value, &self.replica_id, &self.node_version, fencing_token);
+			match parent {
+				Some(parent) => record.on_parent(parents, parent),
+				None => record,
+			}
+		};
+		let stored = match stored {
+			Some(stored) => stored,
+			None => return Ok(Some(claim(1, Vec::new()))),
+		};
+		let holder = ClaimRecord::<V>::deserialize(&stored)
+			.map_err(|reason| self.corrupt(key, reason, stored.clone()))?;
+		if attempt.unconfirmed.as_ref() == Some(&stored) {
+			// written again as it is
+			debug!(
+				target: "permission-resolver",
+				"Claim of {} {} landed before the attempt failed", key.as_str(), holder
+			);
+			return Ok(Some(holder))
+		}
+		let can = if latest_plausible.map_or(false, |latest| holder.index > latest) {
+			self.skewed(key, &holder)
+		} else {
+			value > holder.index
+		};
+		if can {
+			return Ok(Some(claim(holder.fencing_token + 1, Vec::new())))
+		}
+		if value == holder.index && self.is_orphaned_claim(parent, &holder) {
+			info!(
+				target: "permission-resolver",
+				"Taking over {} {} built on an orphaned block", key.as_str(), holder
+			);
+			return Ok(Some(claim(holder.fencing_token + 1, holder.parents)))
+		}
+		debug!(
+			target: "permission-resolver",
+			"Denied {} {} permission, claimed {}", key.as_str(), value, holder
+		);
+		Ok(None)
+	}
+
+	/// Whether the claim of the holder was built on a block this replica sees orphaned, and it may
+	/// be claimed again on the given parent. No parent is claimed on twice.
+	fn is_orphaned_claim<V: ClaimValue>(
+		&self,
+		parent: Option<&[u8]>,
+		holder: &ClaimRecord<V>,
+	) -> bool {
+		let (parent, fork_view) = match (parent, &self.fork_view) {
+			(Some(parent), Some(fork_view)) => (parent, fork_view),
+			_ => return false,
+		};
+		match holder.parents.last() {
+			Some(built_on) =>
+				!holder.parents.iter().any(|claimed| claimed == parent) &&
+					fork_view.is_orphaned(built_on),
+			None => false,
+		}
+	}
+
+	/// Reports a malformed stored value, it's never guessed at since the key has to be fixed by
+	/// hand.
+	fn corrupt(&self, key: &Key, reason: &'static str, value: Value) -> ResolveError {
+		let e = ResolveError::Corrupt { key: key.as_str().to_owned(), reason, value };
+		error!(
+			target: "permission-resolver",
+			"Corrupted permission value under {}: {}", self.namespace.key(key.as_str()), e
+		);
+		if let Some(metrics) = &self.metrics {
+			metrics.observe_corrupt_value(key.label());
+		}
+		e
+	}
+
+	/// Reports a stored value too far ahead of the local clock, returns whether it may be
+	/// overwritten.
+	fn skewed<V: ClaimValue>(&self, key: &Key, holder: &ClaimRecord<V>) -> bool {
+		let clear = self.skew_guard.map_or(false, |guard| guard.clear_skewed);
+		if let Some(metrics) = &self.metrics {
+			metrics.observe_skewed_value(key.label());
+		}
+		if clear {
+			warn!(
+				target: "permission-resolver",
+				"Clearing {} {} which is too far ahead of the local clock", key.as_str(), holder
+			);
+		} else {
+			error!(
+				target: "permission-resolver",
+				"Stored {} {} is too far ahead of the local clock, a replica with a skewed clock \
+				may have claimed it. Once the clocks are fixed, restart one replica with \
+				--remote-authority-clear-skewed-slots to clear it",
+				key.as_str(),
+				holder,
+			);
+		}
+		clear
+	}
+}
+
+/// Falls back to the fail policy of the duty if the permission could not be resolved.
+fn permission_or_fail_policy(
+	key: Key,
+	policy: FailPolicy,
+	contention: &Contention,
+	result: Result<bool, ResolveError>,
+) -> bool {
+	match result {
+		Ok(permission) => permission,
+		Err(e) => {
+			let permission = policy.permission(contention);
+			error!(
+				target: "permission-resolver",
+				"Could not resolve {} permission, reason: {}, {} it according to {:?} policy",
+				key.as_str(),
+				e,
+				if permission { "granting" } else { "denying" },
+				policy,
+			);
+			permission
+		},
+	}
+}
+
+/// Whether the commit failed because another transaction wrote the key first.
+fn is_write_conflict(e: &Error) -> bool {
+	matches!(e, Error::KeyError(key_error) if key_error.conflict.is_some())
+}
+
+/// Rolls back the failed transaction in the background, so that it isn't left behind and the
+/// caller doesn't wait for a backend that may be stuck.
+fn abort(mut txn: Box<dyn TiKVTransaction>, e: ResolveError) -> ResolveError {
+	tokio::spawn(async move {
+		if let Err(rollback_e) = txn.rollback().await {
+			debug!(
+				target: "permission-resolver",
+				"Could not rollback transaction, reason: {}", rollback_e
+			);
+		}
+	});
+	e
 }
 
-fn deserialize_u64(value: Value) -> u64 {
-	let mut buf = [0u8; 8];
-	let len = 8.min(value.len());
-	buf[..len].copy_from_slice(&value[..len]);
-	u64::from_be_bytes(buf)
+#[async_trait]
+impl TryPermissionResolver for RemoteAuthorityPermissionResolver {
+	async fn try_resolve_slot(&self, slot: Slot) -> Result<bool, ResolveError> {
+		let slot = slot.into();
+		let mut latest_plausible = None;
+		if let Some(guard) = &self.skew_guard {
+			let local_slot = guard.local_slot();
+			if !guard.is_plausible(slot, local_slot) {
+				warn!(
+					target: "permission-resolver",
+					"Refusing to claim slot {} which is too far from slot {} of the local clock",
+					slot,
+					local_slot,
+				);
+				return Ok(false)
+			}
+			latest_plausible = Some(guard.latest_plausible(local_slot));
+		}
+		let parent = self.fork_view.as_ref().and_then(|fork_view| fork_view.parent(slot));
+		self.resolve_with_retries(
+			Key::SLOT,
+			slot,
+			latest_plausible,
+			parent.as_deref(),
+			self.timeouts.slot,
+		)
+		.await
+	}
+
+	async fn try_resolve_round(&self, round: u64) -> Result<bool, ResolveError> {
+		let index = RoundIndex { set_id: self.authority_set_id.set_id(), round };
+		self.resolve_with_retries(Key::ROUND, index, None, None, self.timeouts.round)
+			.await
+	}
+
+	async fn try_resolve_session(&self, session_index: u32) -> Result<bool, ResolveError> {
+		self.resolve_with_retries::<u64>(
+			Key::SESSION,
+			session_index.into(),
+			None,
+			None,
+			self.timeouts.session,
+		)
+		.await
+	}
+
+	async fn try_resolve_duty(&self, duty: &str, index: u64) -> Result<bool, ResolveError> {
+		self.resolve_with_retries(Key::duty(duty)?, index, None, None, self.timeouts.duty)
+			.await
+	}
 }
 
 #[async_trait]
 impl PermissionResolver for RemoteAuthorityPermissionResolver {
 	async fn resolve_slot(&self, slot: Slot) -> bool {
-		match self.do_resolve(Key::SLOT, slot.into()).await {
-			Ok(result) => result,
-			Err(e) => {
-				error!(
-                target: "permission-resolver",
-                "Could not resolve slot permission, reason: {}", e);
-				false
-			},
-		}
+		let result = self.try_resolve_slot(slot).await;
+		permission_or_fail_policy(Key::SLOT, self.fail_policies.slot, &self.contention, result)
 	}
 
 	async fn resolve_round(&self, round: u64) -> bool {
-		match self.do_resolve(Key::ROUND, round).await {
-			Ok(result) => result,
-			Err(e) => {
-				error!(
-                target: "permission-resolver",
-                "Could not resolve round permission, reason: {}", e);
-				false
-			},
-		}
+		let result = self.try_resolve_round(round).await;
+		permission_or_fail_policy(Key::ROUND, self.fail_policies.round, &self.contention, result)
 	}
 
 	async fn resolve_session(&self, session_index: u32) -> bool {
-		match self.do_resolve(Key::SESSION, session_index.into()).await {
-			Ok(result) => result,
-			Err(e) => {
-				error!(
-                target: "permission-resolver",
-                "Could not resolve session permission, reason: {}", e);
-				false
-			},
-		}
+		let result = self.try_resolve_session(session_index).await;
+		permission_or_fail_policy(
+			Key::SESSION,
+			self.fail_policies.session,
+			&self.contention,
+			result,
+		)
 	}
 }
 
@@ -225,8 +1316,15 @@ impl PermissionResolver for RemoteAuthorityPermissionResolver {
 mod tests {
 
 	use super::*;
+	use crate::testing::InMemoryTiKVClient;
 	use sp_authority_permission::PermissionResolver;
-	use std::sync::Arc;
+	use std::{
+		sync::{
+			atomic::{AtomicU64, Ordering},
+			Arc,
+		},
+		time::Duration,
+	};
 
 	struct MockedTiKVClient {
 		slot: Option<Slot>,
@@ -243,6 +1341,10 @@ mod tests {
 				session: *&self.session,
 			}))
 		}
+
+		async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+			self.begin_optimistic().await
+		}
 	}
 
 	struct MockedTiKVTransaction {
@@ -253,6 +1355,10 @@ mod tests {
 
 	#[async_trait]
 	impl TiKVTransaction for MockedTiKVTransaction {
+		async fn get(&mut self, key: String) -> Result<Option<Value>, Error> {
+			self.get_for_update(key).await
+		}
+
 		async fn get_for_update(&mut self, key: String) -> Result<Option<Value>, Error> {
 			if key == Key::SLOT.as_str() {
 				Ok(self.slot.map(|s| u64::to_be_bytes(s.into()).to_vec()))
@@ -269,6 +1375,19 @@ mod tests {
 			Ok(())
 		}
 
+		async fn delete(&mut self, _: String) -> Result<(), Error> {
+			Ok(())
+		}
+
+		async fn scan(
+			&mut self,
+			_: String,
+			_: String,
+			_: u32,
+		) -> Result<Vec<(String, Value)>, Error> {
+			Ok(Vec::new())
+		}
+
 		async fn commit(&mut self) -> Result<Option<Timestamp>, Error> {
 			Ok(Some(Timestamp::default()))
 		}
@@ -278,66 +1397,637 @@ mod tests {
 		}
 	}
 
+	fn scoped(authority: u8) -> KeyNamespace {
+		KeyNamespace::Scoped(KeyScope { genesis_hash: vec![0; 32], authority: vec![authority; 32] })
+	}
+
+	#[tokio::test]
+	async fn test_scoped_resolvers_do_not_share_claims() {
+		let client = InMemoryTiKVClient::default();
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), scoped(1)).await;
+		let bob = RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), scoped(2)).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(5.into()).await);
+		assert!(!alice.resolve_slot(5.into()).await);
+		assert!(!bob.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_legacy_resolvers_share_claims() {
+		let client = InMemoryTiKVClient::default();
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		let bob =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(!bob.resolve_slot(5.into()).await);
+	}
+
+	fn shared_set_id(set_id: &Arc<AtomicU64>) -> Arc<dyn AuthoritySetIdProvider> {
+		let set_id = set_id.clone();
+		Arc::new(move || set_id.load(Ordering::SeqCst))
+	}
+
+	#[tokio::test]
+	async fn test_permits_round_after_set_change() {
+		let set_id = Arc::new(AtomicU64::new(0));
+		let resolver = RemoteAuthorityPermissionResolver::new(
+			Box::new(InMemoryTiKVClient::default()),
+			KeyNamespace::Legacy,
+		)
+		.await
+		.with_authority_set_id(shared_set_id(&set_id));
+		assert!(resolver.resolve_round(100).await);
+		assert!(!resolver.resolve_round(1).await);
+
+		set_id.store(1, Ordering::SeqCst);
+		assert!(resolver.resolve_round(1).await);
+		assert!(!resolver.resolve_round(1).await);
+		assert!(resolver.resolve_round(2).await);
+	}
+
+	#[tokio::test]
+	async fn test_denies_round_of_previous_set() {
+		let set_id = Arc::new(AtomicU64::new(2));
+		let resolver = RemoteAuthorityPermissionResolver::new(
+			Box::new(InMemoryTiKVClient::default()),
+			KeyNamespace::Legacy,
+		)
+		.await
+		.with_authority_set_id(shared_set_id(&set_id));
+		assert!(resolver.resolve_round(1).await);
+
+		set_id.store(1, Ordering::SeqCst);
+		assert!(!resolver.resolve_round(50).await);
+	}
+
+	#[tokio::test]
+	async fn test_permits_round_of_new_set_over_legacy_value() {
+		let client = MockedTiKVClient { slot: None, round: Some(500), session: None };
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy)
+				.await
+				.with_authority_set_id(Arc::new(|| 1));
+		assert!(resolver.resolve_round(1).await)
+	}
+
+	#[tokio::test]
+	async fn test_records_replica_of_claim() {
+		let client = InMemoryTiKVClient::default();
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_replica("alice-1".to_owned(), "4.0.0-dev".to_owned());
+		assert!(resolver.resolve_slot(5.into()).await);
+
+		let stored = client.data.lock().unwrap().get(Key::SLOT.as_str()).cloned().unwrap();
+		let record = ClaimRecord::<u64>::deserialize(&stored).unwrap();
+		assert_eq!(record.index, 5);
+		assert_eq!(record.replica_id, "alice-1");
+		assert_eq!(record.node_version, "4.0.0-dev");
+	}
+
+	#[tokio::test]
+	async fn test_replaces_raw_value_with_claim_record() {
+		let client = InMemoryTiKVClient::default();
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(Key::SLOT.as_str().to_owned(), u64::to_be_bytes(5).to_vec());
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_replica("alice-1".to_owned(), "4.0.0-dev".to_owned());
+		assert!(!resolver.resolve_slot(5.into()).await);
+		assert_eq!(
+			client.data.lock().unwrap().get(Key::SLOT.as_str()),
+			Some(&u64::to_be_bytes(5).to_vec())
+		);
+
+		assert!(resolver.resolve_slot(6.into()).await);
+		let stored = client.data.lock().unwrap().get(Key::SLOT.as_str()).cloned().unwrap();
+		assert_eq!(ClaimRecord::<u64>::deserialize(&stored).unwrap().replica_id, "alice-1");
+	}
+
+	#[tokio::test]
+	async fn test_writes_raw_value_with_legacy_values() {
+		let client = InMemoryTiKVClient::default();
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_legacy_values(true);
+		assert!(resolver.resolve_slot(5.into()).await);
+		assert_eq!(
+			client.data.lock().unwrap().get(Key::SLOT.as_str()),
+			Some(&u64::to_be_bytes(5).to_vec())
+		);
+	}
+
+	fn skew_guard(clear_skewed: bool) -> ClockSkewGuard {
+		ClockSkewGuard { slot_duration: Duration::from_secs(6), max_drift: 10, clear_skewed }
+	}
+
+	#[tokio::test]
+	async fn test_refuses_slot_too_far_from_local_clock() {
+		let client = InMemoryTiKVClient::default();
+		let guard = skew_guard(false);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_skew_guard(guard);
+		let local_slot = guard.local_slot();
+		assert!(!resolver.try_resolve_slot((local_slot + 1000).into()).await.unwrap());
+		assert!(client.data.lock().unwrap().is_empty());
+		assert!(resolver.try_resolve_slot(local_slot.into()).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_denies_slot_behind_skewed_value() {
+		let client = InMemoryTiKVClient::default();
+		let guard = skew_guard(false);
+		let skewed = u64::to_be_bytes(guard.local_slot() + 1000).to_vec();
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(Key::SLOT.as_str().to_owned(), skewed.clone());
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_skew_guard(guard);
+		assert!(!resolver.try_resolve_slot(guard.local_slot().into()).await.unwrap());
+		assert_eq!(client.data.lock().unwrap().get(Key::SLOT.as_str()), Some(&skewed));
+	}
+
+	#[tokio::test]
+	async fn test_clears_skewed_value_when_approved() {
+		let client = InMemoryTiKVClient::default();
+		let guard = skew_guard(true);
+		let skewed = u64::to_be_bytes(guard.local_slot() + 1000).to_vec();
+		client.data.lock().unwrap().insert(Key::SLOT.as_str().to_owned(), skewed);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_skew_guard(guard);
+		let local_slot = guard.local_slot();
+		assert!(resolver.try_resolve_slot(local_slot.into()).await.unwrap());
+		assert!(!resolver.try_resolve_slot(local_slot.into()).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_falls_back_to_fail_policy_on_corrupt_value() {
+		let client = InMemoryTiKVClient::default();
+		client.data.lock().unwrap().insert(Key::SLOT.as_str().to_owned(), vec![0, 0, 5]);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_fail_policies(FailPolicies { slot: FailPolicy::Grant, ..Default::default() });
+		assert!(matches!(
+			resolver.try_resolve_slot(6.into()).await,
+			Err(ResolveError::Corrupt { key, .. }) if key == "slot"
+		));
+		assert!(resolver.resolve_slot(6.into()).await);
+		assert_eq!(client.data.lock().unwrap().get(Key::SLOT.as_str()), Some(&vec![0, 0, 5]));
+	}
+
+	#[tokio::test]
+	async fn test_reports_backend_error_apart_from_denial() {
+		let client = InMemoryTiKVClient::default();
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		assert!(resolver.try_resolve_slot(1.into()).await.unwrap());
+		assert!(!resolver.try_resolve_slot(1.into()).await.unwrap());
+
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(matches!(resolver.try_resolve_slot(2.into()).await, Err(ResolveError::Begin(_))));
+	}
+
+	#[tokio::test]
+	async fn test_applies_fail_policy_of_each_duty() {
+		let client = InMemoryTiKVClient::default();
+		client.unavailable.store(true, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy)
+				.await
+				.with_fail_policies(FailPolicies {
+					slot: FailPolicy::Deny,
+					round: FailPolicy::GrantIfAlone,
+					session: FailPolicy::Grant,
+					duty: FailPolicy::Deny,
+				});
+		assert!(!resolver.resolve_slot(1.into()).await);
+		assert!(!resolver.resolve_round(1).await);
+		assert!(resolver.resolve_session(1).await);
+		assert!(!resolver.claim("price-feed", 1).await);
+	}
+
+	#[tokio::test]
+	async fn test_grants_if_alone_only_without_competing_replicas() {
+		let client = InMemoryTiKVClient::default();
+		let policies = FailPolicies { slot: FailPolicy::GrantIfAlone, ..Default::default() };
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_fail_policies(policies);
+		let bob =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_fail_policies(policies);
+		assert!(alice.resolve_slot(1.into()).await);
+		assert!(!bob.resolve_slot(1.into()).await);
+		assert!(bob.resolve_slot(2.into()).await);
+
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(alice.resolve_slot(3.into()).await);
+		assert!(!bob.resolve_slot(3.into()).await);
+	}
+
+	async fn race_for_slot(client: &InMemoryTiKVClient, pessimistic: bool) -> (bool, bool) {
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_pessimistic(pessimistic);
+		let bob =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_pessimistic(pessimistic);
+		tokio::join!(alice.resolve_slot(1.into()), bob.resolve_slot(1.into()))
+	}
+
+	#[tokio::test]
+	async fn test_optimistic_loser_fails_at_commit() {
+		let client = InMemoryTiKVClient::default();
+		let (alice_won, bob_won) = race_for_slot(&client, false).await;
+		assert!(alice_won ^ bob_won);
+		assert_eq!(client.commit_conflicts.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_pessimistic_loser_sees_claim_of_winner() {
+		let client = InMemoryTiKVClient::default();
+		let (alice_won, bob_won) = race_for_slot(&client, true).await;
+		assert!(alice_won ^ bob_won);
+		assert_eq!(client.commit_conflicts.load(Ordering::SeqCst), 0);
+		assert_eq!(client.rollbacks.load(Ordering::SeqCst), 1);
+		assert!(client.locks.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_pessimistic_lock_is_released_on_timeout() {
+		let client = InMemoryTiKVClient::default();
+		let timeouts = DutyTimeouts { slot: Some(Duration::from_millis(50)), ..Default::default() };
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_pessimistic(true)
+				.with_timeouts(timeouts);
+		client.stalled_commits.store(true, Ordering::SeqCst);
+		assert!(resolver.try_resolve_slot(1.into()).await.is_err());
+
+		client.stalled_commits.store(false, Ordering::SeqCst);
+		assert!(resolver.resolve_slot(1.into()).await);
+	}
+
+	fn retry_policy() -> RetryPolicy {
+		RetryPolicy {
+			min_backoff: Duration::from_millis(1),
+			max_backoff: Duration::from_millis(5),
+			deadline: Duration::from_secs(1),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_retries_transient_errors() {
+		let client = InMemoryTiKVClient::default();
+		client.transient_failures.store(2, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(retry_policy());
+		assert!(resolver.try_resolve_slot(1.into()).await.unwrap());
+		assert_eq!(client.begins.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test]
+	async fn test_grants_claim_landed_before_commit_failed() {
+		let client = InMemoryTiKVClient::default();
+		client.lost_replies.store(1, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(retry_policy());
+		let bob =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		assert!(resolver.try_resolve_slot(1.into()).await.unwrap());
+		assert_eq!(client.begins.load(Ordering::SeqCst), 2);
+		assert!(!bob.resolve_slot(1.into()).await);
+		// only the attempt that lost its reply is granted the stored claim
+		assert!(!resolver.resolve_slot(1.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_does_not_grant_landed_claim_of_legacy_values() {
+		let client = InMemoryTiKVClient::default();
+		client.lost_replies.store(1, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(retry_policy())
+				.with_legacy_values(true);
+		assert!(!resolver.try_resolve_slot(1.into()).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_does_not_retry_without_retry_policy() {
+		let client = InMemoryTiKVClient::default();
+		client.transient_failures.store(1, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		assert!(resolver.try_resolve_slot(1.into()).await.is_err());
+		assert_eq!(client.begins.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_does_not_retry_permanent_errors() {
+		let client = InMemoryTiKVClient::default();
+		client.unavailable.store(true, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(retry_policy());
+		assert!(resolver.try_resolve_slot(1.into()).await.is_err());
+		assert_eq!(client.begins.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_stops_retrying_at_deadline() {
+		let client = InMemoryTiKVClient::default();
+		client.transient_failures.store(u32::MAX, Ordering::SeqCst);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(RetryPolicy {
+					deadline: Duration::from_millis(50),
+					..retry_policy()
+				});
+		let started = Instant::now();
+		assert!(resolver.try_resolve_slot(1.into()).await.is_err());
+		assert!(started.elapsed() < Duration::from_millis(100));
+		assert!(client.begins.load(Ordering::SeqCst) > 1);
+	}
+
+	#[tokio::test]
+	async fn test_times_out_stuck_commit_without_rolling_back() {
+		let client = InMemoryTiKVClient::default();
+		client.stalled_commits.store(true, Ordering::SeqCst);
+		let timeout = Duration::from_millis(20);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_retry_policy(retry_policy())
+				.with_timeouts(DutyTimeouts { slot: Some(timeout), ..Default::default() });
+		let result = resolver.try_resolve_slot(1.into()).await;
+		assert!(matches!(result, Err(ResolveError::Timeout(t)) if t == timeout));
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		assert_eq!(client.rollbacks.load(Ordering::SeqCst), 0);
+		assert!(client.data.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_timeout_falls_back_to_fail_policy() {
+		let client = InMemoryTiKVClient::default();
+		client.stalled_commits.store(true, Ordering::SeqCst);
+		let timeout = Some(Duration::from_millis(20));
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_timeouts(DutyTimeouts {
+					slot: timeout,
+					round: timeout,
+					session: timeout,
+					duty: timeout,
+				})
+				.with_fail_policies(FailPolicies {
+					session: FailPolicy::Grant,
+					..Default::default()
+				});
+		assert!(!resolver.resolve_slot(1.into()).await);
+		assert!(resolver.resolve_session(1).await);
+	}
+
+	#[tokio::test]
+	async fn test_reports_disconnected_until_connected() {
+		let client = ReconnectingTiKVClient::spawn(
+			Discovery::new(
+				PdEndpoints::Static(vec!["127.0.0.1:2379".to_owned()]),
+				Duration::from_secs(30),
+			),
+			|_| async { Err(Error::StringError("PD is unreachable".to_owned())) },
+			None,
+			None,
+		);
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
+		assert!(matches!(
+			resolver.try_resolve_slot(1.into()).await,
+			Err(ResolveError::Disconnected)
+		));
+		assert!(!resolver.resolve_slot(1.into()).await);
+	}
+
 	#[tokio::test]
 	async fn test_permits_round_if_higher() {
 		let client = MockedTiKVClient { slot: None, round: Some(1), session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(resolver.resolve_round(2).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_round_if_equal() {
 		let client = MockedTiKVClient { slot: None, round: Some(1), session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_round(1).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_round_if_lower() {
 		let client = MockedTiKVClient { slot: None, round: Some(1), session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_round(0).await)
 	}
 
 	#[tokio::test]
 	async fn test_permits_session_if_higher() {
 		let client = MockedTiKVClient { slot: None, round: None, session: Some(1) };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(resolver.resolve_session(2).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_session_if_equal() {
 		let client = MockedTiKVClient { slot: None, round: None, session: Some(1) };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_session(1).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_session_if_lower() {
 		let client = MockedTiKVClient { slot: None, round: None, session: Some(1) };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_session(0).await)
 	}
 
 	#[tokio::test]
 	async fn test_permits_slot_if_higher() {
 		let client = MockedTiKVClient { slot: Some(1.into()), round: None, session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(resolver.resolve_slot(2.into()).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_slot_if_equal() {
 		let client = MockedTiKVClient { slot: Some(1.into()), round: None, session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_slot(1.into()).await)
 	}
 
 	#[tokio::test]
 	async fn test_denies_slot_if_lower() {
 		let client = MockedTiKVClient { slot: Some(1.into()), round: None, session: None };
-		let resolver = RemoteAuthorityPermissionResolver::new(Box::new(client)).await;
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client), KeyNamespace::Legacy).await;
 		assert!(!resolver.resolve_slot(0.into()).await)
 	}
+
+	fn factory() -> RemoteAuthorityPermissionResolverFactory {
+		RemoteAuthorityPermissionResolverFactory {
+			remote_urls: vec!["127.0.0.1:2379".to_owned()],
+			discovery_refresh: Duration::from_secs(30),
+			cached: false,
+			tls: None,
+			legacy_keys: true,
+			scope: None,
+			legacy_values: false,
+			replica_id: None,
+			node_version: String::new(),
+			authority_set_id: None,
+			fail_policies: Default::default(),
+			retry_policy: Default::default(),
+			timeouts: Default::default(),
+			prometheus_registry: None,
+			cluster: None,
+			slot_duration: None,
+			max_slot_drift: 0,
+			clear_skewed_slots: false,
+			lease: false,
+			history: false,
+			history_retention: None,
+			compare_and_swap: false,
+			pessimistic: false,
+			priority: None,
+			pre_claim: false,
+			slot_schedule: None,
+			fork_aware: false,
+			fork_view: None,
+			shadow: None,
+			fallback: None,
+			spawner: None,
+			clients: Default::default(),
+		}
+	}
+
+	#[test]
+	fn test_rejects_factory_missing_what_its_mode_needs() {
+		assert!(factory().validate().is_ok());
+		let missing = |factory: RemoteAuthorityPermissionResolverFactory| match factory.validate() {
+			Err(FactoryError::Missing(missing)) => missing,
+			result => panic!("Unexpected validation result {:?}", result),
+		};
+		let scoped = RemoteAuthorityPermissionResolverFactory { legacy_keys: false, ..factory() };
+		assert_eq!(missing(scoped), MISSING_SCOPE);
+		let fork_aware = RemoteAuthorityPermissionResolverFactory { fork_aware: true, ..factory() };
+		assert_eq!(missing(fork_aware), MISSING_FORK_VIEW);
+		let pre_claim = RemoteAuthorityPermissionResolverFactory { pre_claim: true, ..factory() };
+		assert_eq!(missing(pre_claim), MISSING_SLOT_SCHEDULE);
+		struct EverySlot;
+		impl SlotSchedule for EverySlot {
+			fn next_slot(&self, slot: u64) -> Option<u64> {
+				Some(slot + 1)
+			}
+		}
+		let pre_claim = RemoteAuthorityPermissionResolverFactory {
+			pre_claim: true,
+			slot_schedule: Some(Arc::new(EverySlot)),
+			..factory()
+		};
+		assert!(matches!(pre_claim.validate(), Err(FactoryError::Invalid(_))));
+		let scoped_pre_claim = RemoteAuthorityPermissionResolverFactory {
+			legacy_keys: false,
+			scope: Some(KeyScope { genesis_hash: vec![1], authority: vec![2] }),
+			..pre_claim
+		};
+		assert!(scoped_pre_claim.validate().is_ok());
+		let lease = RemoteAuthorityPermissionResolverFactory { lease: true, ..factory() };
+		assert_eq!(missing(lease), MISSING_SLOT_DURATION);
+		let lease = RemoteAuthorityPermissionResolverFactory {
+			lease: true,
+			slot_duration: Some(Duration::from_secs(6)),
+			..factory()
+		};
+		assert_eq!(missing(lease), MISSING_REPLICA_ID);
+		let fallback = |duties| RemoteAuthorityPermissionResolverFactory {
+			fallback: Some(Fallback {
+				secondary: Arc::new(sp_authority_permission::AlwaysPermissionGrantedFactory {}),
+				duties,
+				hysteresis: Duration::from_secs(60),
+			}),
+			..factory()
+		};
+		let heartbeats = FallbackDuties { session: true, ..Default::default() };
+		assert!(fallback(heartbeats).validate().is_ok());
+		let votes = FallbackDuties { round: true, ..heartbeats };
+		assert!(matches!(fallback(votes).validate(), Err(FactoryError::Invalid(_))));
+		let blocks = FallbackDuties { slot: true, ..heartbeats };
+		assert!(matches!(fallback(blocks).validate(), Err(FactoryError::Invalid(_))));
+		let unaddressed =
+			RemoteAuthorityPermissionResolverFactory { remote_urls: vec![], ..factory() };
+		assert!(matches!(
+			unaddressed.validate(),
+			Err(FactoryError::Discovery(DiscoveryError::Empty))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_fails_to_create_what_the_mode_misses() {
+		let scoped = RemoteAuthorityPermissionResolverFactory { legacy_keys: false, ..factory() };
+		assert!(matches!(scoped.create_query(), Err(FactoryError::Missing(MISSING_SCOPE))));
+		let lease = RemoteAuthorityPermissionResolverFactory { lease: true, ..factory() };
+		assert!(matches!(
+			lease.create_duty_claims(),
+			Err(FactoryError::Missing(MISSING_SLOT_DURATION))
+		));
+		assert!(matches!(
+			lease.try_create().await,
+			Err(FactoryError::Missing(MISSING_SLOT_DURATION))
+		));
+		// denies instead of racing for the duties
+		let resolver = lease.create().await;
+		assert!(!resolver.resolve_slot(1.into()).await);
+		assert!(!resolver.resolve_session(1).await);
+	}
 }
@@ -1,8 +1,10 @@
 use async_trait::async_trait;
-use prometheus_endpoint::{register, Counter, U64};
+use prometheus_endpoint::{
+	register, Counter, CounterVec, Gauge, Histogram, HistogramOpts, Opts, U64,
+};
 use sp_authority_permission::PermissionResolver;
 use sp_consensus_slots::Slot;
-use std::sync::Mutex;
+use std::{sync::Mutex, time::Duration};
 
 struct DoubleCounter {
 	current: Counter<U64>,
@@ -85,9 +87,191 @@ impl PermissionResolver for PermissionResolverMetrics {
 	}
 }
 
+/// Metrics of the TiKV backend of the permission resolver.
+#[derive(Clone)]
+pub(crate) struct BackendMetrics {
+	attempts: CounterVec<U64>,
+	connected: Gauge<U64>,
+	corrupt_values: CounterVec<U64>,
+	skewed_values: CounterVec<U64>,
+	leader: Gauge<U64>,
+	pre_claims: CounterVec<U64>,
+	pre_claim_saved: Histogram,
+	shadow_disagreements: CounterVec<U64>,
+	fallback_active: Gauge<U64>,
+}
+
+/// Options of a backend metric, labelled by the cluster when the validator asks several of them.
+fn opts(name: &str, help: &str, cluster: Option<&str>) -> Opts {
+	let opts = Opts::new(name, help);
+	match cluster {
+		Some(cluster) => opts.const_label("cluster", cluster),
+		None => opts,
+	}
+}
+
+impl BackendMetrics {
+	pub fn new(
+		registry: &prometheus_endpoint::Registry,
+		cluster: Option<&str>,
+	) -> Result<Self, Error> {
+		Ok(Self {
+			attempts: register(
+				CounterVec::new(
+					opts(
+						"substrate_authority_permission_attempts",
+						"Number of TiKV attempts made to resolve the authority permission.",
+						cluster,
+					),
+					&["duty"],
+				)?,
+				registry,
+			)?,
+			connected: register(
+				Gauge::with_opts(opts(
+					"substrate_authority_permission_connected",
+					"Whether the permission resolver is connected to TiKV.",
+					cluster,
+				))?,
+				registry,
+			)?,
+			corrupt_values: register(
+				CounterVec::new(
+					opts(
+						"substrate_authority_permission_corrupt_values",
+						"Number of malformed values read from TiKV.",
+						cluster,
+					),
+					&["duty"],
+				)?,
+				registry,
+			)?,
+			skewed_values: register(
+				CounterVec::new(
+					opts(
+						"substrate_authority_permission_skewed_values",
+						"Number of values read from TiKV too far ahead of the local clock.",
+						cluster,
+					),
+					&["duty"],
+				)?,
+				registry,
+			)?,
+			leader: register(
+				Gauge::with_opts(opts(
+					"substrate_authority_permission_leader",
+					"Whether the replica holds the lease in the lease mode.",
+					cluster,
+				))?,
+				registry,
+			)?,
+			pre_claims: register(
+				CounterVec::new(
+					opts(
+						"substrate_authority_permission_pre_claims",
+						"Number of slots claimed ahead of time, by whether the claim was used.",
+						cluster,
+					),
+					&["outcome"],
+				)?,
+				registry,
+			)?,
+			pre_claim_saved: register(
+				Histogram::with_opts(
+					HistogramOpts::from(opts(
+						"substrate_authority_permission_pre_claim_saved_seconds",
+						"Claim latency taken out of the slot by the used pre-claims.",
+						cluster,
+					))
+					.buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+				)?,
+				registry,
+			)?,
+			shadow_disagreements: register(
+				CounterVec::new(
+					opts(
+						"substrate_authority_permission_shadow_disagreements",
+						"Number of shadow claims resolved differently than the permission acted upon, \
+						by whether TiKV granted them.",
+						cluster,
+					),
+					&["duty", "remote"],
+				)?,
+				registry,
+			)?,
+			fallback_active: register(
+				Gauge::with_opts(opts(
+					"substrate_authority_permission_fallback_active",
+					"Whether the fallback duties are resolved by the secondary backend.",
+					cluster,
+				))?,
+				registry,
+			)?,
+		})
+	}
+
+	pub fn set_connected(&self, connected: bool) {
+		self.connected.set(connected.into());
+	}
+
+	pub fn set_leader(&self, leader: bool) {
+		self.leader.set(leader.into());
+	}
+
+	pub fn observe_attempts(&self, duty: &str, attempts: u32) {
+		self.attempts.with_label_values(&[duty]).inc_by(attempts.into());
+	}
+
+	pub fn observe_corrupt_value(&self, duty: &str) {
+		self.corrupt_values.with_label_values(&[duty]).inc();
+	}
+
+	pub fn observe_skewed_value(&self, duty: &str) {
+		self.skewed_values.with_label_values(&[duty]).inc();
+	}
+
+	pub fn observe_pre_claim(&self, outcome: &str) {
+		self.pre_claims.with_label_values(&[outcome]).inc();
+	}
+
+	pub fn observe_pre_claim_saved(&self, saved: Duration) {
+		self.pre_claim_saved.observe(saved.as_secs_f64());
+	}
+
+	pub fn set_fallback_active(&self, active: bool) {
+		self.fallback_active.set(active.into());
+	}
+
+	pub fn observe_shadow_disagreement(&self, duty: &str, remote: bool) {
+		let remote = if remote { "granted" } else { "denied" };
+		self.shadow_disagreements.with_label_values(&[duty, remote]).inc();
+	}
+}
+
 /// Error type for the authority discovery module.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
 	#[error("Failed to register Prometheus metric.")]
 	Prometheus(#[from] prometheus_endpoint::PrometheusError),
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_registers_metrics_of_every_cluster() {
+		let registry = prometheus_endpoint::Registry::new();
+		let first = BackendMetrics::new(&registry, Some("0")).unwrap();
+		let second = BackendMetrics::new(&registry, Some("1")).unwrap();
+		first.set_connected(true);
+		second.set_connected(false);
+		let connected = registry
+			.gather()
+			.into_iter()
+			.find(|family| family.get_name() == "substrate_authority_permission_connected")
+			.unwrap();
+		assert_eq!(connected.get_metric().len(), 2);
+		assert!(BackendMetrics::new(&registry, Some("1")).is_err());
+	}
+}
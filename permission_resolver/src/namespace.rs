@@ -0,0 +1,61 @@
+/// Identity of the replica group the claims are made for. Replicas of the same validator share
+/// it, while other validators and other chains get their own keys on the same TiKV cluster.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyScope {
+	/// Hash of the genesis block of the chain.
+	pub genesis_hash: Vec<u8>,
+	/// Public key of the local Aura (or GRANDPA) authority.
+	pub authority: Vec<u8>,
+}
+
+/// Layout of the permission keys in TiKV.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyNamespace {
+	/// Flat `slot`/`round`/`session` keys shared by everybody using the cluster.
+	Legacy,
+	/// Keys prefixed with the genesis hash and the authority public key.
+	Scoped(KeyScope),
+}
+
+impl KeyNamespace {
+	/// Full TiKV key for the given permission name.
+	pub fn key(&self, name: &str) -> String {
+		match self {
+			KeyNamespace::Legacy => name.to_owned(),
+			KeyNamespace::Scoped(scope) =>
+				format!("{}/{}/{}", to_hex(&scope.genesis_hash), to_hex(&scope.authority), name),
+		}
+	}
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn scope(genesis_hash: u8, authority: u8) -> KeyNamespace {
+		KeyNamespace::Scoped(KeyScope {
+			genesis_hash: vec![genesis_hash; 2],
+			authority: vec![authority; 2],
+		})
+	}
+
+	#[test]
+	fn test_legacy_keys_are_flat() {
+		assert_eq!(KeyNamespace::Legacy.key("slot"), "slot");
+	}
+
+	#[test]
+	fn test_scoped_keys_are_prefixed() {
+		assert_eq!(scope(0xab, 0x01).key("round"), "abab/0101/round");
+	}
+
+	#[test]
+	fn test_scoped_keys_differ_between_authorities_and_chains() {
+		assert_ne!(scope(0, 1).key("slot"), scope(0, 2).key("slot"));
+		assert_ne!(scope(0, 1).key("slot"), scope(1, 1).key("slot"));
+	}
+}
@@ -0,0 +1,695 @@
+//! Permission resolver asking a plugin process, so that the coordination logic can be written in
+//! any language without changing the node.
+//!
+//! The node and the plugin exchange JSON objects, one per line, over a Unix socket the plugin
+//! listens on or over the stdin and stdout of a plugin process started by the node. Every request
+//! carries the protocol version and an id, which the plugin echoes in its response. The responses
+//! may come in any order.
+//!
+//! ```text
+//! -> {"version":2,"id":1,"method":"ping"}
+//! <- {"id":1,"version":2}
+//! -> {"version":2,"id":2,"method":"resolve","duty":"slot","index":42}
+//! <- {"id":2,"granted":true}
+//! -> {"version":2,"id":3,"method":"resolve","duty":"round","index":5,"set_id":3}
+//! <- {"id":3,"granted":false}
+//! -> {"version":2,"id":4,"method":"resolve","duty":"session","index":7}
+//! <- {"id":4,"error":"no quorum"}
+//! ```
+//!
+//! The duties are `slot`, `round`, `session` and `duty/<name>` for the named duties. The GRANDPA
+//! round numbers restart with every authority set, so the resolve requests of the rounds carry the
+//! `set_id` of the current set as well, which the version 1 of the protocol didn't. A ping opens
+//! every connection, the plugin answers it with the version it speaks, and it's repeated as a
+//! health check. A plugin that doesn't answer a ping in time is disconnected, and connected again,
+//! or started again, with the next request. An error, a missing answer or a broken connection
+//! falls back to the fail policy of the duty.
+
+use crate::{
+	permission_or_fail_policy, policy::Contention, timeout::Deadline, AuthoritySetIdProvider,
+	DutyTimeouts, FailPolicies, Key, ResolveError, TryPermissionResolver,
+};
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
+use sp_consensus_slots::Slot;
+use sp_core::traits::SpawnNamed;
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+	process::Stdio,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex, Weak,
+	},
+	time::Duration,
+};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+	net::UnixStream,
+	process::{Child, Command},
+	sync::oneshot,
+};
+
+/// Version of the protocol spoken with the plugins.
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Where the plugin is reached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PluginEndpoint {
+	/// Unix socket the plugin listens on.
+	Socket(PathBuf),
+	/// Program started by the node along with its arguments, spoken to over its stdin and stdout.
+	Command(Vec<String>),
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+	version: u32,
+	id: u64,
+	#[serde(flatten)]
+	method: Method<'a>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Method<'a> {
+	Ping,
+	Resolve {
+		duty: &'a str,
+		index: u64,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		set_id: Option<u64>,
+	},
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+	id: u64,
+	#[serde(default)]
+	version: Option<u32>,
+	#[serde(default)]
+	granted: Option<bool>,
+	#[serde(default)]
+	error: Option<String>,
+}
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>>;
+
+/// Forgets the request once it's answered or given up on.
+struct PendingRequest<'a> {
+	pending: &'a Pending,
+	id: u64,
+}
+
+impl Drop for PendingRequest<'_> {
+	fn drop(&mut self) {
+		self.pending.lock().unwrap().remove(&self.id);
+	}
+}
+
+/// Connection to the plugin, the responses are read by a background task and handed to the
+/// requests waiting for them.
+struct Connection {
+	writer: tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+	pending: Pending,
+	closed: Arc<AtomicBool>,
+	/// Plugin process, killed along with the connection.
+	_child: Option<Child>,
+}
+
+impl Connection {
+	async fn open(
+		endpoint: &PluginEndpoint,
+		spawner: Option<&dyn SpawnNamed>,
+	) -> Result<Connection, ResolveError> {
+		let (reader, writer, child): (
+			Box<dyn AsyncRead + Send + Unpin>,
+			Box<dyn AsyncWrite + Send + Unpin>,
+			_,
+		) = match endpoint {
+			PluginEndpoint::Socket(path) => {
+				let (reader, writer) =
+					UnixStream::connect(path).await.map_err(ResolveError::PluginIo)?.into_split();
+				(Box::new(reader), Box::new(writer), None)
+			},
+			PluginEndpoint::Command(command) => {
+				let (program, args) = command
+					.split_first()
+					.ok_or_else(|| ResolveError::Plugin("empty plugin command".to_owned()))?;
+				let mut child = Command::new(program)
+					.args(args)
+					.stdin(Stdio::piped())
+					.stdout(Stdio::piped())
+					.kill_on_drop(true)
+					.spawn()
+					.map_err(ResolveError::PluginIo)?;
+				let (reader, writer) = match (child.stdout.take(), child.stdin.take()) {
+					(Some(stdout), Some(stdin)) => (stdout, stdin),
+					_ => return Err(ResolveError::Plugin("plugin process has no stdio".to_owned())),
+				};
+				(Box::new(reader), Box::new(writer), Some(child))
+			},
+		};
+		let pending = Pending::default();
+		let closed = Arc::new(AtomicBool::new(false));
+		crate::spawn(
+			spawner,
+			"permission-plugin-responses",
+			read_responses(reader, pending.clone(), closed.clone()),
+		);
+		Ok(Connection { writer: tokio::sync::Mutex::new(writer), pending, closed, _child: child })
+	}
+
+	fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::SeqCst)
+	}
+
+	async fn call(&self, request: &Request<'_>) -> Result<Response, ResolveError> {
+		let mut line = serde_json::to_vec(request)
+			.map_err(|e| ResolveError::Plugin(format!("could not encode request: {}", e)))?;
+		line.push(b'\n');
+		let (sender, receiver) = oneshot::channel();
+		self.pending.lock().unwrap().insert(request.id, sender);
+		let _pending = PendingRequest { pending: &self.pending, id: request.id };
+		// closed meanwhile, the request would never be answered
+		if self.is_closed() {
+			return Err(ResolveError::Disconnected)
+		}
+		let mut writer = self.writer.lock().await;
+		let written = match writer.write_all(&line).await {
+			Ok(()) => writer.flush().await,
+			Err(e) => Err(e),
+		};
+		drop(writer);
+		if let Err(e) = written {
+			self.closed.store(true, Ordering::SeqCst);
+			return Err(ResolveError::PluginIo(e))
+		}
+		receiver.await.map_err(|_| ResolveError::Disconnected)
+	}
+}
+
+/// Hands the responses to the requests until the plugin closes the connection, the requests
+/// still waiting are failed then.
+async fn read_responses(
+	reader: Box<dyn AsyncRead + Send + Unpin>,
+	pending: Pending,
+	closed: Arc<AtomicBool>,
+) {
+	let mut lines = BufReader::new(reader).lines();
+	loop {
+		match lines.next_line().await {
+			Ok(Some(line)) => match serde_json::from_str::<Response>(&line) {
+				Ok(response) =>
+					if let Some(sender) = pending.lock().unwrap().remove(&response.id) {
+						let _ = sender.send(response);
+					},
+				Err(e) => warn!(
+					target: "permission-resolver",
+					"Ignoring malformed permission plugin response {:?}, reason: {}", line, e
+				),
+			},
+			Ok(None) => {
+				warn!(target: "permission-resolver", "Permission plugin closed the connection");
+				break
+			},
+			Err(e) => {
+				warn!(
+					target: "permission-resolver",
+					"Could not read from the permission plugin, reason: {}", e
+				);
+				break
+			},
+		}
+	}
+	closed.store(true, Ordering::SeqCst);
+	pending.lock().unwrap().clear();
+}
+
+/// Client of the plugin, connecting to it when asked first and again after the connection broke.
+struct PluginClient {
+	endpoint: PluginEndpoint,
+	connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+	next_id: AtomicU64,
+	spawner: Option<Arc<dyn SpawnNamed>>,
+}
+
+impl PluginClient {
+	fn new(endpoint: PluginEndpoint, spawner: Option<Arc<dyn SpawnNamed>>) -> PluginClient {
+		PluginClient {
+			endpoint,
+			connection: tokio::sync::Mutex::new(None),
+			next_id: AtomicU64::new(1),
+			spawner,
+		}
+	}
+
+	async fn connection(&self) -> Result<Arc<Connection>, ResolveError> {
+		let mut connection = self.connection.lock().await;
+		if let Some(open) = connection.as_ref().filter(|open| !open.is_closed()) {
+			return Ok(open.clone())
+		}
+		*connection = None;
+		debug!(target: "permission-resolver", "Connecting to permission plugin {:?}", self.endpoint);
+		let open = Arc::new(Connection::open(&self.endpoint, self.spawner.as_deref()).await?);
+		let version = self.ask(&open, Method::Ping).await?.version;
+		if version != Some(PLUGIN_PROTOCOL_VERSION) {
+			return Err(ResolveError::Plugin(format!(
+				"plugin speaks protocol version {:?}, expected {}",
+				version, PLUGIN_PROTOCOL_VERSION
+			)))
+		}
+		*connection = Some(open.clone());
+		Ok(open)
+	}
+
+	async fn ask(
+		&self,
+		connection: &Connection,
+		method: Method<'_>,
+	) -> Result<Response, ResolveError> {
+		let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+		let request = Request { version: PLUGIN_PROTOCOL_VERSION, id, method };
+		let response = connection.call(&request).await?;
+		match response.error {
+			Some(error) => Err(ResolveError::Plugin(error)),
+			None => Ok(response),
+		}
+	}
+
+	/// Drops the connection, the plugin process is stopped along with it.
+	async fn disconnect(&self) {
+		*self.connection.lock().await = None;
+	}
+}
+
+/// Pings the plugin until the resolver is dropped, disconnecting it if it doesn't answer in time.
+async fn check_health(client: Weak<PluginClient>, interval: Duration) {
+	loop {
+		tokio::time::sleep(interval).await;
+		let client = match client.upgrade() {
+			Some(client) => client,
+			None => return,
+		};
+		let connection = match client.connection.lock().await.clone() {
+			Some(connection) => connection,
+			None => continue,
+		};
+		let ping = tokio::time::timeout(interval, client.ask(&connection, Method::Ping)).await;
+		let e = match ping {
+			Ok(Ok(_)) => continue,
+			Ok(Err(e)) => e.to_string(),
+			Err(_) => format!("no answer within {:?}", interval),
+		};
+		warn!(
+			target: "permission-resolver",
+			"Permission plugin failed the health check, disconnecting it, reason: {}", e
+		);
+		client.disconnect().await;
+	}
+}
+
+/// Factory of the resolvers asking a plugin process.
+pub struct PluginPermissionResolverFactory {
+	pub endpoint: PluginEndpoint,
+	/// How duties are decided when the plugin can't be asked.
+	pub fail_policies: FailPolicies,
+	/// Time limits of resolving the permission of each duty, connecting included.
+	pub timeouts: DutyTimeouts,
+	/// Time between the health checks of the plugin.
+	pub health_interval: Duration,
+	/// Current GRANDPA authority set id, rounds are asked as rounds of the set 0 if not given.
+	pub authority_set_id: Option<Arc<dyn AuthoritySetIdProvider>>,
+	/// Runs the connections and the health checks, the tokio runtime is used if not given.
+	pub spawner: Option<Arc<dyn SpawnNamed>>,
+}
+
+#[async_trait]
+impl PermissionResolverFactory for PluginPermissionResolverFactory {
+	async fn create(&self) -> Box<dyn PermissionResolver> {
+		let mut resolver =
+			PluginPermissionResolver::new(self.endpoint.clone(), self.health_interval)
+				.with_fail_policies(self.fail_policies)
+				.with_timeouts(self.timeouts)
+				.with_spawner(self.spawner.clone());
+		if let Some(authority_set_id) = &self.authority_set_id {
+			resolver = resolver.with_authority_set_id(authority_set_id.clone());
+		}
+		Box::new(resolver)
+	}
+}
+
+/// Permission resolver asking a plugin process over the protocol described in the module docs.
+pub struct PluginPermissionResolver {
+	client: Arc<PluginClient>,
+	health_interval: Duration,
+	health_checked: AtomicBool,
+	fail_policies: FailPolicies,
+	contention: Contention,
+	timeouts: DutyTimeouts,
+	authority_set_id: Arc<dyn AuthoritySetIdProvider>,
+}
+
+impl PluginPermissionResolver {
+	pub fn new(endpoint: PluginEndpoint, health_interval: Duration) -> PluginPermissionResolver {
+		PluginPermissionResolver {
+			client: Arc::new(PluginClient::new(endpoint, None)),
+			health_interval,
+			health_checked: AtomicBool::new(false),
+			fail_policies: FailPolicies::default(),
+			contention: Contention::default(),
+			timeouts: DutyTimeouts::default(),
+			authority_set_id: Arc::new(|| 0),
+		}
+	}
+
+	/// Run the connections and the health checks on the given spawner instead of the tokio
+	/// runtime. Has to be given before the resolver is first asked.
+	pub fn with_spawner(
+		mut self,
+		spawner: Option<Arc<dyn SpawnNamed>>,
+	) -> PluginPermissionResolver {
+		self.client = Arc::new(PluginClient::new(self.client.endpoint.clone(), spawner));
+		self
+	}
+
+	/// Ask for the rounds of the authority set given by the provider.
+	pub fn with_authority_set_id(
+		mut self,
+		authority_set_id: Arc<dyn AuthoritySetIdProvider>,
+	) -> PluginPermissionResolver {
+		self.authority_set_id = authority_set_id;
+		self
+	}
+
+	/// Decide the duties according to the given policies when the plugin can't be asked.
+	pub fn with_fail_policies(mut self, fail_policies: FailPolicies) -> PluginPermissionResolver {
+		self.fail_policies = fail_policies;
+		self
+	}
+
+	/// Give up resolving the permission of each duty after the given time.
+	pub fn with_timeouts(mut self, timeouts: DutyTimeouts) -> PluginPermissionResolver {
+		self.timeouts = timeouts;
+		self
+	}
+
+	async fn resolve(
+		&self,
+		key: Key,
+		index: u64,
+		set_id: Option<u64>,
+		timeout: Option<Duration>,
+	) -> Result<bool, ResolveError> {
+		let deadline = Deadline::after(timeout);
+		let permission = deadline
+			.run(async {
+				let connection = self.client.connection().await?;
+				if !self.health_checked.swap(true, Ordering::SeqCst) {
+					let client = Arc::downgrade(&self.client);
+					crate::spawn(
+						self.client.spawner.as_deref(),
+						"permission-plugin-health",
+						check_health(client, self.health_interval),
+					);
+				}
+				let method = Method::Resolve { duty: key.as_str(), index, set_id };
+				let response = self.client.ask(&connection, method).await?;
+				response.granted.ok_or_else(|| {
+					ResolveError::Plugin(format!("response {} has no permission", response.id))
+				})
+			})
+			.await??;
+		debug!(
+			target: "permission-resolver",
+			"Plugin {} {} {} permission",
+			if permission { "granted" } else { "denied" },
+			key.as_str(),
+			index,
+		);
+		self.contention.record(permission);
+		Ok(permission)
+	}
+}
+
+#[async_trait]
+impl TryPermissionResolver for PluginPermissionResolver {
+	async fn try_resolve_slot(&self, slot: Slot) -> Result<bool, ResolveError> {
+		self.resolve(Key::SLOT, slot.into(), None, self.timeouts.slot).await
+	}
+
+	async fn try_resolve_round(&self, round: u64) -> Result<bool, ResolveError> {
+		let set_id = self.authority_set_id.set_id();
+		self.resolve(Key::ROUND, round, Some(set_id), self.timeouts.round).await
+	}
+
+	async fn try_resolve_session(&self, session_index: u32) -> Result<bool, ResolveError> {
+		self.resolve(Key::SESSION, session_index.into(), None, self.timeouts.session)
+			.await
+	}
+
+	async fn try_resolve_duty(&self, duty: &str, index: u64) -> Result<bool, ResolveError> {
+		self.resolve(Key::duty(duty)?, index, None, self.timeouts.duty).await
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for PluginPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		let result = self.try_resolve_slot(slot).await;
+		permission_or_fail_policy(Key::SLOT, self.fail_policies.slot, &self.contention, result)
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		let result = self.try_resolve_round(round).await;
+		permission_or_fail_policy(Key::ROUND, self.fail_policies.round, &self.contention, result)
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		let result = self.try_resolve_session(session_index).await;
+		permission_or_fail_policy(
+			Key::SESSION,
+			self.fail_policies.session,
+			&self.contention,
+			result,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::CountingSpawner, FailPolicy};
+	use serde_json::{json, Value};
+	use std::sync::atomic::AtomicUsize;
+	use tokio::net::UnixListener;
+
+	/// Plugin listening on a socket, answering every request with the handler, or not at all.
+	struct MockedPlugin {
+		_dir: tempfile::TempDir,
+		path: PathBuf,
+		connections: Arc<AtomicUsize>,
+	}
+
+	impl MockedPlugin {
+		fn start<H>(handler: H) -> MockedPlugin
+		where
+			H: Fn(&Value) -> Option<Value> + Send + Sync + 'static,
+		{
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("plugin.sock");
+			let listener = UnixListener::bind(&path).unwrap();
+			let connections = Arc::new(AtomicUsize::new(0));
+			let (handler, accepted) = (Arc::new(handler), connections.clone());
+			tokio::spawn(async move {
+				while let Ok((stream, _)) = listener.accept().await {
+					accepted.fetch_add(1, Ordering::SeqCst);
+					let handler = handler.clone();
+					tokio::spawn(async move {
+						let (reader, mut writer) = stream.into_split();
+						let mut lines = BufReader::new(reader).lines();
+						while let Ok(Some(line)) = lines.next_line().await {
+							let request: Value = serde_json::from_str(&line).unwrap();
+							assert_eq!(request["version"], PLUGIN_PROTOCOL_VERSION);
+							if request["method"] == "ping" {
+								let pong = json!({ "id": request["id"], "version": PLUGIN_PROTOCOL_VERSION });
+								writer.write_all(format!("{}\n", pong).as_bytes()).await.unwrap();
+								continue
+							}
+							match handler(&request) {
+								Some(mut response) => {
+									response["id"] = request["id"].clone();
+									let response = format!("{}\n", response);
+									writer.write_all(response.as_bytes()).await.unwrap();
+								},
+								// hang up on the requests not answered
+								None if request["duty"] == "session" => return,
+								None => {},
+							}
+						}
+					});
+				}
+			});
+			MockedPlugin { _dir: dir, path, connections }
+		}
+
+		fn resolver(&self) -> PluginPermissionResolver {
+			PluginPermissionResolver::new(
+				PluginEndpoint::Socket(self.path.clone()),
+				Duration::from_secs(60),
+			)
+			.with_timeouts(DutyTimeouts {
+				slot: Some(Duration::from_millis(200)),
+				round: Some(Duration::from_millis(200)),
+				session: Some(Duration::from_millis(200)),
+				duty: Some(Duration::from_millis(200)),
+			})
+		}
+	}
+
+	/// Plugin granting the even indexes.
+	fn even(request: &Value) -> Option<Value> {
+		Some(json!({ "granted": request["index"].as_u64().unwrap() % 2 == 0 }))
+	}
+
+	#[tokio::test]
+	async fn test_resolves_with_plugin() {
+		let plugin = MockedPlugin::start(even);
+		let resolver = plugin.resolver();
+		assert!(resolver.resolve_slot(2.into()).await);
+		assert!(!resolver.resolve_slot(3.into()).await);
+		assert!(resolver.resolve_round(4).await);
+		assert!(!resolver.resolve_session(5).await);
+		assert!(resolver.try_resolve_duty("price-feed", 6).await.unwrap());
+		assert_eq!(plugin.connections.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_sends_duty_and_index() {
+		let plugin = MockedPlugin::start(|request| {
+			Some(
+				json!({ "granted": request["duty"] == "duty/price-feed" && request["index"] == 7 }),
+			)
+		});
+		let resolver = plugin.resolver();
+		assert!(resolver.try_resolve_duty("price-feed", 7).await.unwrap());
+		assert!(!resolver.try_resolve_slot(7.into()).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_sends_set_id_of_rounds() {
+		let plugin = MockedPlugin::start(|request| {
+			let granted = match request["duty"].as_str() {
+				Some("round") => request["set_id"] == 3,
+				_ => request.get("set_id").is_none(),
+			};
+			Some(json!({ "granted": granted }))
+		});
+		let resolver = plugin.resolver().with_authority_set_id(Arc::new(|| 3));
+		assert!(resolver.try_resolve_round(7).await.unwrap());
+		assert!(resolver.try_resolve_slot(7.into()).await.unwrap());
+		assert!(resolver.try_resolve_session(7).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_runs_background_tasks_on_spawner() {
+		let plugin = MockedPlugin::start(even);
+		let spawner = CountingSpawner::default();
+		let resolver = plugin.resolver().with_spawner(Some(Arc::new(spawner.clone())));
+		assert!(resolver.resolve_slot(2.into()).await);
+		// the responses are read and the health is checked on the spawner
+		assert_eq!(spawner.spawned.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn test_plugin_error_falls_back_to_fail_policy() {
+		let plugin = MockedPlugin::start(|_| Some(json!({ "error": "no quorum" })));
+		let resolver = plugin
+			.resolver()
+			.with_fail_policies(FailPolicies { round: FailPolicy::Grant, ..Default::default() });
+		assert!(matches!(
+			resolver.try_resolve_slot(1.into()).await,
+			Err(ResolveError::Plugin(e)) if e == "no quorum"
+		));
+		assert!(!resolver.resolve_slot(1.into()).await);
+		assert!(resolver.resolve_round(1).await);
+	}
+
+	#[tokio::test]
+	async fn test_times_out_without_answer() {
+		let plugin = MockedPlugin::start(|_| None);
+		let resolver = plugin.resolver();
+		assert!(matches!(resolver.try_resolve_slot(1.into()).await, Err(ResolveError::Timeout(_))));
+		assert!(resolver
+			.client
+			.connection
+			.lock()
+			.await
+			.as_ref()
+			.unwrap()
+			.pending
+			.lock()
+			.unwrap()
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_reconnects_after_plugin_hangs_up() {
+		let plugin = MockedPlugin::start(|request| {
+			(request["duty"] != "session").then(|| json!({ "granted": true }))
+		});
+		let resolver = plugin.resolver();
+		assert!(resolver.resolve_slot(1.into()).await);
+		assert!(matches!(resolver.try_resolve_session(1).await, Err(ResolveError::Disconnected)));
+		assert!(resolver.resolve_slot(2.into()).await);
+		assert_eq!(plugin.connections.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn test_fails_without_plugin() {
+		let resolver = PluginPermissionResolver::new(
+			PluginEndpoint::Socket("/nonexistent/plugin.sock".into()),
+			Duration::from_secs(60),
+		);
+		assert!(matches!(
+			resolver.try_resolve_slot(1.into()).await,
+			Err(ResolveError::PluginIo(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_rejects_other_protocol_version() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("plugin.sock");
+		let listener = UnixListener::bind(&path).unwrap();
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let (reader, mut writer) = stream.into_split();
+			let mut lines = BufReader::new(reader).lines();
+			let ping: Value =
+				serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+			// a plugin of the version 1, which doesn't know the set ids
+			let pong = json!({ "id": ping["id"], "version": 1 });
+			writer.write_all(format!("{}\n", pong).as_bytes()).await.unwrap();
+			let _ = lines.next_line().await;
+		});
+		let resolver =
+			PluginPermissionResolver::new(PluginEndpoint::Socket(path), Duration::from_secs(60));
+		assert!(matches!(resolver.try_resolve_slot(1.into()).await, Err(ResolveError::Plugin(_))));
+	}
+
+	#[tokio::test]
+	async fn test_talks_to_plugin_process() {
+		// answers every request with its id, granting it
+		let script = r#"sed -u 's/.*"id":\([0-9]*\).*/{"id":\1,"version":2,"granted":true}/'"#;
+		let resolver = PluginPermissionResolver::new(
+			PluginEndpoint::Command(vec!["sh".into(), "-c".into(), script.into()]),
+			Duration::from_secs(60),
+		)
+		.with_timeouts(DutyTimeouts { slot: Some(Duration::from_secs(5)), ..Default::default() });
+		assert!(resolver.try_resolve_slot(1.into()).await.unwrap());
+		assert!(resolver.try_resolve_slot(2.into()).await.unwrap());
+	}
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Decides about a duty when the backend could not be asked for the permission.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailPolicy {
+	/// Skip the duty, keeps safety.
+	#[default]
+	Deny,
+	/// Perform the duty, keeps liveness.
+	Grant,
+	/// Perform the duty only if this replica has been granted permissions before and has never
+	/// been denied one, i.e. no other replica has been seen claiming duties.
+	GrantIfAlone,
+}
+
+impl FailPolicy {
+	pub(crate) fn permission(&self, contention: &Contention) -> bool {
+		match self {
+			FailPolicy::Deny => false,
+			FailPolicy::Grant => true,
+			FailPolicy::GrantIfAlone => contention.is_alone(),
+		}
+	}
+}
+
+/// Fail policy of every duty.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FailPolicies {
+	pub slot: FailPolicy,
+	pub round: FailPolicy,
+	pub session: FailPolicy,
+	/// Policy of the claims of the named duties.
+	pub duty: FailPolicy,
+}
+
+/// What the replica has seen of the other replicas so far.
+#[derive(Default)]
+pub(crate) struct Contention {
+	granted: AtomicBool,
+	denied: AtomicBool,
+}
+
+impl Contention {
+	pub(crate) fn record(&self, permission: bool) {
+		if permission {
+			self.granted.store(true, Ordering::Relaxed);
+		} else {
+			self.denied.store(true, Ordering::Relaxed);
+		}
+	}
+
+	fn is_alone(&self) -> bool {
+		self.granted.load(Ordering::Relaxed) && !self.denied.load(Ordering::Relaxed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_grant_if_alone_denies_without_any_grant() {
+		assert!(!FailPolicy::GrantIfAlone.permission(&Contention::default()));
+	}
+
+	#[test]
+	fn test_grant_if_alone_grants_after_only_grants() {
+		let contention = Contention::default();
+		contention.record(true);
+		contention.record(true);
+		assert!(FailPolicy::GrantIfAlone.permission(&contention));
+	}
+
+	#[test]
+	fn test_grant_if_alone_denies_after_a_denial() {
+		let contention = Contention::default();
+		contention.record(true);
+		contention.record(false);
+		assert!(!FailPolicy::GrantIfAlone.permission(&contention));
+	}
+
+	#[test]
+	fn test_deny_and_grant_ignore_contention() {
+		let contention = Contention::default();
+		contention.record(false);
+		assert!(!FailPolicy::Deny.permission(&contention));
+		assert!(FailPolicy::Grant.permission(&contention));
+	}
+}
@@ -0,0 +1,364 @@
+use crate::{
+	metrics::BackendMetrics, RemoteAuthorityPermissionResolver, ResolveError, TryPermissionResolver,
+};
+use async_trait::async_trait;
+use log::{debug, warn};
+use sp_authority_permission::PermissionResolver;
+use sp_consensus_slots::Slot;
+use sp_core::traits::SpawnNamed;
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// Slots authored by this replica.
+pub trait SlotSchedule: Send + Sync {
+	/// First slot after the given one authored by this replica, `None` if it authors none.
+	fn next_slot(&self, slot: u64) -> Option<u64>;
+}
+
+/// Claim of a slot made ahead of time, the claim is aborted once it's dropped.
+struct PreClaim {
+	slot: u64,
+	/// Outcome of the claim along with the time it took.
+	outcome: oneshot::Receiver<(Result<bool, ResolveError>, Duration)>,
+	_abort: oneshot::Sender<()>,
+}
+
+/// Permission resolver claiming the next slot of this replica in the background once it won a
+/// slot, so that the claim latency is spent before that slot starts instead of inside it. Only a
+/// granted pre-claim is used, the slot is claimed again as usual if it was lost, failed or isn't
+/// done in time. Rounds and sessions aren't claimed ahead.
+pub struct PreClaimingPermissionResolver {
+	resolver: Arc<RemoteAuthorityPermissionResolver>,
+	schedule: Arc<dyn SlotSchedule>,
+	pre_claim: Mutex<Option<PreClaim>>,
+	spawner: Option<Arc<dyn SpawnNamed>>,
+	metrics: Option<BackendMetrics>,
+}
+
+impl PreClaimingPermissionResolver {
+	pub fn new(
+		resolver: RemoteAuthorityPermissionResolver,
+		schedule: Arc<dyn SlotSchedule>,
+	) -> PreClaimingPermissionResolver {
+		PreClaimingPermissionResolver {
+			resolver: Arc::new(resolver),
+			schedule,
+			pre_claim: Mutex::new(None),
+			spawner: None,
+			metrics: None,
+		}
+	}
+
+	/// Runs the pre-claims on the given spawner, or on the tokio runtime if not given.
+	pub fn with_spawner(
+		mut self,
+		spawner: Option<Arc<dyn SpawnNamed>>,
+	) -> PreClaimingPermissionResolver {
+		self.spawner = spawner;
+		self
+	}
+
+	pub(crate) fn with_metrics(
+		mut self,
+		metrics: Option<BackendMetrics>,
+	) -> PreClaimingPermissionResolver {
+		self.metrics = metrics;
+		self
+	}
+
+	fn observe(&self, outcome: &str) {
+		if let Some(metrics) = &self.metrics {
+			metrics.observe_pre_claim(outcome);
+		}
+	}
+
+	fn spawn_pre_claim(&self, slot: u64) {
+		let resolver = self.resolver.clone();
+		let (abort, aborted) = oneshot::channel();
+		let (done, outcome) = oneshot::channel();
+		let claim = async move {
+			let started = Instant::now();
+			tokio::select! {
+				result = resolver.try_resolve_slot(slot.into()) => {
+					let _ = done.send((result, started.elapsed()));
+				},
+				_ = aborted => {},
+			}
+		};
+		crate::spawn(self.spawner.as_deref(), "remote-authority-pre-claim", claim);
+		*self.pre_claim.lock().unwrap() = Some(PreClaim { slot, outcome, _abort: abort });
+	}
+
+	/// Pre-claims the slot of this replica following the won slot.
+	fn pre_claim_after(&self, slot: u64) {
+		if let Some(next) = self.schedule.next_slot(slot) {
+			self.spawn_pre_claim(next);
+		}
+	}
+
+	/// Permission of the pre-claimed slot, `None` if it has to be claimed again.
+	async fn pre_claimed(&self, slot: u64) -> Option<bool> {
+		let mut pre_claim = self.pre_claim.lock().unwrap().take()?;
+		if pre_claim.slot != slot {
+			debug!(
+				target: "permission-resolver",
+				"Slot {} pre-claimed in vain, claiming slot {}", pre_claim.slot, slot
+			);
+			self.observe("unused");
+			// dropping the pre-claim aborts it
+			return None
+		}
+		let waited = Instant::now();
+		match (&mut pre_claim.outcome).await {
+			Ok((Ok(true), took)) => {
+				let saved = took.saturating_sub(waited.elapsed());
+				debug!(
+					target: "permission-resolver",
+					"Using the pre-claim of slot {}, saved {:?}", slot, saved
+				);
+				self.observe("used");
+				if let Some(metrics) = &self.metrics {
+					metrics.observe_pre_claim_saved(saved);
+				}
+				Some(true)
+			},
+			// lost to another replica, or refused as too far from the local clock
+			Ok((Ok(false), _)) => {
+				self.observe("lost");
+				None
+			},
+			Ok((Err(e), _)) => {
+				warn!(
+					target: "permission-resolver",
+					"Could not pre-claim slot {}, claiming it again, reason: {}", slot, e
+				);
+				self.observe("failed");
+				None
+			},
+			Err(_) => {
+				warn!(
+					target: "permission-resolver",
+					"Pre-claim of slot {} did not finish, claiming it again", slot
+				);
+				self.observe("failed");
+				None
+			},
+		}
+	}
+}
+
+#[async_trait]
+impl TryPermissionResolver for PreClaimingPermissionResolver {
+	async fn try_resolve_slot(&self, slot: Slot) -> Result<bool, ResolveError> {
+		let slot = u64::from(slot);
+		let permission = match self.pre_claimed(slot).await {
+			Some(permission) => permission,
+			None => self.resolver.try_resolve_slot(slot.into()).await?,
+		};
+		if permission {
+			self.pre_claim_after(slot);
+		}
+		Ok(permission)
+	}
+
+	async fn try_resolve_round(&self, round: u64) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_round(round).await
+	}
+
+	async fn try_resolve_session(&self, session_index: u32) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_session(session_index).await
+	}
+
+	async fn try_resolve_duty(&self, duty: &str, index: u64) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_duty(duty, index).await
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for PreClaimingPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		let slot = u64::from(slot);
+		let permission = match self.pre_claimed(slot).await {
+			Some(permission) => permission,
+			None => self.resolver.resolve_slot(slot.into()).await,
+		};
+		if permission {
+			self.pre_claim_after(slot);
+		}
+		permission
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		self.resolver.resolve_round(round).await
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		self.resolver.resolve_session(session_index).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		testing::{CountingSpawner, InMemoryTiKVClient},
+		Key, KeyNamespace,
+	};
+	use std::sync::atomic::Ordering;
+
+	/// Authority taking every `count`th slot, starting from `index`.
+	struct Turns {
+		index: u64,
+		count: u64,
+	}
+
+	impl SlotSchedule for Turns {
+		fn next_slot(&self, slot: u64) -> Option<u64> {
+			let next = slot + 1;
+			Some(next + (self.index + self.count - next % self.count) % self.count)
+		}
+	}
+
+	async fn replica_of(
+		client: &InMemoryTiKVClient,
+		schedule: Turns,
+	) -> PreClaimingPermissionResolver {
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		PreClaimingPermissionResolver::new(resolver, Arc::new(schedule))
+	}
+
+	/// Replica of an authority taking every slot.
+	async fn replica(client: &InMemoryTiKVClient) -> PreClaimingPermissionResolver {
+		replica_of(client, Turns { index: 0, count: 1 }).await
+	}
+
+	/// Waits for the pending pre-claim to finish, as it would during the rest of the slot.
+	async fn settle(resolver: &PreClaimingPermissionResolver) {
+		let mut pre_claim = resolver.pre_claim.lock().unwrap().take().unwrap();
+		let done = (&mut pre_claim.outcome).await.unwrap();
+		let (sender, outcome) = oneshot::channel();
+		let _ = sender.send(done);
+		pre_claim.outcome = outcome;
+		*resolver.pre_claim.lock().unwrap() = Some(pre_claim);
+	}
+
+	#[tokio::test]
+	async fn test_winner_pre_claims_next_slot() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice).await;
+		client.begins.store(0, Ordering::SeqCst);
+		assert!(alice.resolve_slot(6.into()).await);
+		assert_eq!(client.begins.load(Ordering::SeqCst), 0);
+	}
+
+	#[tokio::test]
+	async fn test_pre_claims_next_slot_of_authority() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica_of(&client, Turns { index: 1, count: 3 }).await;
+		assert!(alice.resolve_slot(4.into()).await);
+		settle(&alice).await;
+		// the slots between belong to the other authorities
+		assert_eq!(alice.pre_claim.lock().unwrap().as_ref().unwrap().slot, 7);
+
+		client.begins.store(0, Ordering::SeqCst);
+		assert!(alice.resolve_slot(7.into()).await);
+		assert_eq!(client.begins.load(Ordering::SeqCst), 0);
+	}
+
+	#[tokio::test]
+	async fn test_loser_does_not_pre_claim() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice).await;
+		assert!(!bob.resolve_slot(5.into()).await);
+		assert!(bob.pre_claim.lock().unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn test_other_replica_keeps_pre_claimed_slot() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice).await;
+		assert!(!bob.resolve_slot(6.into()).await);
+		assert!(alice.resolve_slot(6.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_claims_again_when_other_slot_is_asked() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice).await;
+
+		// slot 6 was skipped
+		client.begins.store(0, Ordering::SeqCst);
+		assert!(alice.resolve_slot(8.into()).await);
+		assert_eq!(client.begins.load(Ordering::SeqCst), 1);
+		settle(&alice).await;
+		assert!(alice.resolve_slot(9.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_aborts_unused_pre_claim_on_spawner() {
+		let client = InMemoryTiKVClient::default();
+		let spawner = CountingSpawner::default();
+		let alice = replica(&client).await.with_spawner(Some(Arc::new(spawner.clone())));
+		assert!(alice.resolve_slot(5.into()).await);
+		assert_eq!(spawner.spawned.load(Ordering::SeqCst), 1);
+
+		// the pre-claim of slot 6 hangs on its commit
+		client.stalled_commits.store(true, Ordering::SeqCst);
+		while client.begins.load(Ordering::SeqCst) < 2 {
+			tokio::task::yield_now().await;
+		}
+		tokio::task::yield_now().await;
+		client.stalled_commits.store(false, Ordering::SeqCst);
+
+		assert!(alice.resolve_slot(8.into()).await);
+		settle(&alice).await;
+		assert_eq!(spawner.spawned.load(Ordering::SeqCst), 2);
+		let finished = async {
+			while spawner.finished.load(Ordering::SeqCst) < 2 {
+				tokio::task::yield_now().await;
+			}
+		};
+		assert!(tokio::time::timeout(Duration::from_secs(1), finished).await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_claims_again_after_failed_pre_claim() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		client.unavailable.store(true, Ordering::SeqCst);
+		settle(&alice).await;
+
+		client.unavailable.store(false, Ordering::SeqCst);
+		assert!(alice.resolve_slot(6.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_claims_again_after_lost_pre_claim() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client).await;
+		let bob = replica(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(7.into()).await);
+		settle(&alice).await;
+
+		// the operator cleared the slot claimed by mistake
+		client.data.lock().unwrap().remove(Key::SLOT.as_str());
+		assert!(alice.resolve_slot(6.into()).await);
+	}
+}
@@ -0,0 +1,225 @@
+use crate::{
+	lease::{millis, system_clock, WallClock},
+	ResolveError, TryPermissionResolver,
+};
+use async_trait::async_trait;
+use log::debug;
+use sp_authority_permission::PermissionResolver;
+use sp_consensus_slots::Slot;
+use std::{sync::Arc, time::Duration};
+
+/// Priority of a replica when claiming the slots, so that the replica close to the rest of the
+/// network authors while it's up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplicaPriority {
+	/// Priority of this replica, 0 is the highest.
+	pub priority: u32,
+	/// Fraction of the slot each priority level waits after the level above it before claiming.
+	pub delay_fraction: f64,
+}
+
+impl ReplicaPriority {
+	/// Whether the delay of this replica is a fraction of the slot, so that it still claims the
+	/// slots nobody above it claimed.
+	pub fn is_within_slot(&self) -> bool {
+		let fraction = self.delay_fraction * self.priority as f64;
+		self.delay_fraction >= 0.0 && (0.0..1.0).contains(&fraction)
+	}
+
+	/// Time after the slot start this replica waits before claiming the slot, none if the delay
+	/// isn't within the slot.
+	pub fn delay(&self, slot_duration: Duration) -> Duration {
+		if !self.is_within_slot() {
+			return Duration::ZERO
+		}
+		slot_duration.mul_f64(self.delay_fraction * self.priority as f64)
+	}
+}
+
+/// Permission resolver holding back the slot claims of a lower priority replica, so that a
+/// higher priority replica claims the slot first. A slot claimed meanwhile is denied by the
+/// wrapped resolver, since a slot is granted only once. Rounds and sessions aren't delayed.
+pub struct PriorityPermissionResolver {
+	resolver: Box<dyn TryPermissionResolver>,
+	priority: ReplicaPriority,
+	slot_duration: Duration,
+	clock: WallClock,
+}
+
+impl PriorityPermissionResolver {
+	pub fn new(
+		resolver: Box<dyn TryPermissionResolver>,
+		priority: ReplicaPriority,
+		slot_duration: Duration,
+	) -> PriorityPermissionResolver {
+		PriorityPermissionResolver {
+			resolver,
+			priority,
+			slot_duration,
+			clock: Arc::new(system_clock),
+		}
+	}
+
+	#[cfg(test)]
+	fn with_clock(mut self, clock: WallClock) -> PriorityPermissionResolver {
+		self.clock = clock;
+		self
+	}
+
+	/// Time left until this replica may claim the slot, none once the slot start is further in
+	/// the past than the delay.
+	fn wait(&self, slot: Slot) -> Duration {
+		let delay = self.priority.delay(self.slot_duration);
+		if delay.is_zero() {
+			return Duration::ZERO
+		}
+		let slot_start = u64::from(slot).saturating_mul(millis(self.slot_duration));
+		let claim_at = slot_start.saturating_add(millis(delay));
+		Duration::from_millis(claim_at.saturating_sub((self.clock)()))
+	}
+
+	/// Holds back the claim of the slot until this replica may claim it.
+	async fn hold_back(&self, slot: Slot) {
+		let wait = self.wait(slot);
+		if !wait.is_zero() {
+			debug!(
+				target: "permission-resolver",
+				"Waiting {:?} for the replicas of higher priority to claim slot {}",
+				wait,
+				u64::from(slot),
+			);
+			tokio::time::sleep(wait).await;
+		}
+	}
+}
+
+#[async_trait]
+impl TryPermissionResolver for PriorityPermissionResolver {
+	async fn try_resolve_slot(&self, slot: Slot) -> Result<bool, ResolveError> {
+		self.hold_back(slot).await;
+		self.resolver.try_resolve_slot(slot).await
+	}
+
+	async fn try_resolve_round(&self, round: u64) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_round(round).await
+	}
+
+	async fn try_resolve_session(&self, session_index: u32) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_session(session_index).await
+	}
+
+	async fn try_resolve_duty(&self, duty: &str, index: u64) -> Result<bool, ResolveError> {
+		self.resolver.try_resolve_duty(duty, index).await
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for PriorityPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		self.hold_back(slot).await;
+		self.resolver.resolve_slot(slot).await
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		self.resolver.resolve_round(round).await
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		self.resolver.resolve_session(session_index).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::InMemoryTiKVClient, KeyNamespace, RemoteAuthorityPermissionResolver};
+	use tokio::time::Instant;
+
+	const SLOT: Duration = Duration::from_secs(6);
+	/// Start of the slot 1000.
+	const SLOT_START_MS: u64 = 6_000_000;
+
+	/// Wall clock following the paused tokio time, starting at the given time.
+	fn clock(start_ms: u64) -> WallClock {
+		let started = Instant::now();
+		Arc::new(move || start_ms + millis(started.elapsed()))
+	}
+
+	async fn replica(
+		client: &InMemoryTiKVClient,
+		priority: u32,
+		clock: WallClock,
+	) -> PriorityPermissionResolver {
+		let resolver =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await;
+		let priority = ReplicaPriority { priority, delay_fraction: 0.1 };
+		PriorityPermissionResolver::new(Box::new(resolver), priority, SLOT).with_clock(clock)
+	}
+
+	#[test]
+	fn test_delay_grows_with_priority() {
+		let priority = |priority| ReplicaPriority { priority, delay_fraction: 0.1 };
+		assert_eq!(priority(0).delay(SLOT), Duration::ZERO);
+		assert_eq!(priority(1).delay(SLOT), Duration::from_millis(600));
+		assert_eq!(priority(2).delay(SLOT), Duration::from_millis(1200));
+	}
+
+	#[test]
+	fn test_rejects_delay_beyond_slot() {
+		let priority = |priority, delay_fraction| ReplicaPriority { priority, delay_fraction };
+		assert!(priority(9, 0.1).is_within_slot());
+		for invalid in
+			[priority(10, 0.1), priority(1, -0.1), priority(1, f64::NAN), priority(2, 1e308)]
+		{
+			assert!(!invalid.is_within_slot());
+			assert_eq!(invalid.delay(SLOT), Duration::ZERO);
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_primary_claims_without_waiting() {
+		let client = InMemoryTiKVClient::default();
+		let primary = replica(&client, 0, clock(SLOT_START_MS)).await;
+		let started = Instant::now();
+		assert!(primary.resolve_slot(1000.into()).await);
+		assert_eq!(started.elapsed(), Duration::ZERO);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_secondary_loses_slot_claimed_by_primary() {
+		let client = InMemoryTiKVClient::default();
+		let primary = replica(&client, 0, clock(SLOT_START_MS)).await;
+		let secondary = replica(&client, 1, clock(SLOT_START_MS)).await;
+		let started = Instant::now();
+		let (secondary_won, primary_won) =
+			tokio::join!(secondary.resolve_slot(1000.into()), primary.resolve_slot(1000.into()));
+		assert!(primary_won);
+		assert!(!secondary_won);
+		assert_eq!(started.elapsed(), Duration::from_millis(600));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_secondary_claims_after_delay_without_primary() {
+		let client = InMemoryTiKVClient::default();
+		let secondary = replica(&client, 1, clock(SLOT_START_MS + 100)).await;
+		let started = Instant::now();
+		assert!(secondary.resolve_slot(1000.into()).await);
+		assert_eq!(started.elapsed(), Duration::from_millis(500));
+
+		// the delay counts from the slot start, it's over already
+		let late = replica(&client, 2, clock(SLOT_START_MS + 6_000 + 2_000)).await;
+		let started = Instant::now();
+		assert!(late.resolve_slot(1001.into()).await);
+		assert_eq!(started.elapsed(), Duration::ZERO);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_rounds_are_not_delayed() {
+		let client = InMemoryTiKVClient::default();
+		let secondary = replica(&client, 3, clock(SLOT_START_MS)).await;
+		let started = Instant::now();
+		assert!(secondary.resolve_round(1).await);
+		assert_eq!(started.elapsed(), Duration::ZERO);
+	}
+}
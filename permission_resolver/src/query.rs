@@ -0,0 +1,206 @@
+use crate::{
+	claim::{ClaimRecord, ClaimValue},
+	lease::LeaseRecord,
+	Backend, ConnectionState, Key, KeyNamespace, ResolveError, RoundIndex,
+};
+use async_trait::async_trait;
+use tikv_client::Value;
+
+/// Duty of an authority, identified by its index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duty {
+	Slot(u64),
+	Round(RoundIndex),
+	Session(u32),
+}
+
+/// Replica holding a duty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DutyHolder {
+	/// Replica that claimed the duty, empty if it's unknown.
+	pub replica_id: String,
+	/// Grows with every claim of the duty, or with every change of the lease holder in the lease
+	/// mode. Side effects of the duty can be tagged with it, so that the ones made by a replica
+	/// that lost the duty are told apart. `None` if unknown, like for the raw values written with
+	/// the legacy values.
+	pub fencing_token: Option<u64>,
+	/// Wall-clock time of the claim in milliseconds since the UNIX epoch, zero if unknown.
+	pub claimed_at_ms: u64,
+}
+
+/// Tells who holds the duties, as recorded in the backend.
+#[async_trait]
+pub trait DutyQuery: Send + Sync {
+	/// Holder of the duty, `None` if it isn't claimed or the claim was superseded by a claim of
+	/// a later index.
+	async fn holder(&self, duty: Duty) -> Result<Option<DutyHolder>, ResolveError>;
+}
+
+/// Query of the duties claimed in TiKV by the replicas of a single authority.
+pub(crate) struct TiKVDutyQuery {
+	pub backend: Backend,
+	pub namespace: KeyNamespace,
+	/// Every duty is held by the lease holder.
+	pub lease: bool,
+}
+
+impl TiKVDutyQuery {
+	async fn read(&self, key: &Key) -> Result<Option<Value>, ResolveError> {
+		if self.backend.connection_state() == ConnectionState::Connecting {
+			return Err(ResolveError::Disconnected)
+		}
+		let path = self.namespace.key(key.as_str());
+		let value = match &self.backend {
+			Backend::Transaction(client) => {
+				let mut txn = client.begin_optimistic().await.map_err(ResolveError::Begin)?;
+				let value = txn.get(path).await;
+				txn.rollback().await.map_err(ResolveError::Rollback)?;
+				value
+			},
+			Backend::CompareAndSwap(cas) => cas.client.get(path).await,
+		};
+		value.map_err(|source| ResolveError::Read { key: key.as_str().to_owned(), source })
+	}
+
+	async fn claim_holder<V: ClaimValue>(
+		&self,
+		key: Key,
+		index: V,
+	) -> Result<Option<DutyHolder>, ResolveError> {
+		let value = match self.read(&key).await? {
+			Some(value) => value,
+			None => return Ok(None),
+		};
+		let record = ClaimRecord::<V>::deserialize(&value).map_err(|reason| {
+			ResolveError::Corrupt { key: key.as_str().to_owned(), reason, value: value.clone() }
+		})?;
+		Ok((record.index == index).then_some(DutyHolder {
+			replica_id: record.replica_id,
+			// the tokens start at 1, the records that don't know them read as 0
+			fencing_token: Some(record.fencing_token).filter(|token| *token > 0),
+			claimed_at_ms: record.timestamp_ms,
+		}))
+	}
+
+	async fn lease_holder(&self) -> Result<Option<DutyHolder>, ResolveError> {
+		let key = Key::LEASE;
+		let value = match self.read(&key).await? {
+			Some(value) => value,
+			None => return Ok(None),
+		};
+		let record = LeaseRecord::deserialize(&value).map_err(|reason| ResolveError::Corrupt {
+			key: key.as_str().to_owned(),
+			reason,
+			value: value.clone(),
+		})?;
+		Ok(Some(DutyHolder {
+			replica_id: record.holder,
+			fencing_token: Some(record.term),
+			claimed_at_ms: 0,
+		}))
+	}
+}
+
+#[async_trait]
+impl DutyQuery for TiKVDutyQuery {
+	async fn holder(&self, duty: Duty) -> Result<Option<DutyHolder>, ResolveError> {
+		if self.lease {
+			return self.lease_holder().await
+		}
+		match duty {
+			Duty::Slot(slot) => self.claim_holder(Key::SLOT, slot).await,
+			Duty::Round(round) => self.claim_holder(Key::ROUND, round).await,
+			Duty::Session(session_index) =>
+				self.claim_holder::<u64>(Key::SESSION, session_index.into()).await,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{testing::InMemoryTiKVClient, RemoteAuthorityPermissionResolver};
+	use sp_authority_permission::PermissionResolver;
+
+	async fn replica(client: &InMemoryTiKVClient, replica_id: &str) -> impl PermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+			.await
+			.with_replica(replica_id.to_owned(), "4.0.0-dev".to_owned())
+	}
+
+	fn query(client: &InMemoryTiKVClient) -> TiKVDutyQuery {
+		TiKVDutyQuery {
+			backend: Backend::Transaction(Box::new(client.clone())),
+			namespace: KeyNamespace::Legacy,
+			lease: false,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_tells_holder_of_claimed_duty() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(alice.resolve_round(2).await);
+
+		let holder = query(&client).holder(Duty::Slot(5)).await.unwrap().unwrap();
+		assert_eq!(holder.replica_id, "alice");
+		assert_eq!(holder.fencing_token, Some(1));
+		let round = RoundIndex { set_id: 0, round: 2 };
+		assert!(query(&client).holder(Duty::Round(round)).await.unwrap().is_some());
+		assert_eq!(query(&client).holder(Duty::Session(1)).await.unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_fencing_token_grows_with_every_claim() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		let bob = replica(&client, "bob").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(6.into()).await);
+		assert!(!alice.resolve_slot(6.into()).await);
+		assert!(alice.resolve_slot(7.into()).await);
+
+		let holder = query(&client).holder(Duty::Slot(7)).await.unwrap().unwrap();
+		assert_eq!(holder.replica_id, "alice");
+		assert_eq!(holder.fencing_token, Some(3));
+	}
+
+	#[tokio::test]
+	async fn test_legacy_values_have_no_fencing_token() {
+		let client = InMemoryTiKVClient::default();
+		let alice =
+			RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy)
+				.await
+				.with_legacy_values(true);
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(alice.resolve_slot(6.into()).await);
+
+		let holder = query(&client).holder(Duty::Slot(6)).await.unwrap().unwrap();
+		assert_eq!(holder.fencing_token, None);
+	}
+
+	#[tokio::test]
+	async fn test_superseded_claim_has_no_holder() {
+		let client = InMemoryTiKVClient::default();
+		let alice = replica(&client, "alice").await;
+		assert!(alice.resolve_slot(5.into()).await);
+		assert!(alice.resolve_slot(6.into()).await);
+		assert_eq!(query(&client).holder(Duty::Slot(5)).await.unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn test_lease_holder_holds_every_duty() {
+		let client = InMemoryTiKVClient::default();
+		let record = LeaseRecord { holder: "alice".to_owned(), term: 4, expires_at_ms: 1 };
+		client
+			.data
+			.lock()
+			.unwrap()
+			.insert(Key::LEASE.as_str().to_owned(), record.serialize());
+		let query = TiKVDutyQuery { lease: true, ..query(&client) };
+		let holder = query.holder(Duty::Session(1)).await.unwrap().unwrap();
+		assert_eq!(holder.replica_id, "alice");
+		assert_eq!(holder.fencing_token, Some(4));
+	}
+}
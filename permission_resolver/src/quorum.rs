@@ -0,0 +1,380 @@
+use crate::{DutyClaims, DutyTimeouts, Key};
+use async_trait::async_trait;
+use log::{debug, warn};
+use sp_authority_permission::{PermissionResolver, PermissionResolverFactory};
+use sp_consensus_slots::Slot;
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+/// Number of backends making up the majority of the given backends.
+pub fn majority(backends: usize) -> usize {
+	backends / 2 + 1
+}
+
+/// Factory of the resolvers asking several independent backends, each created by its own
+/// factory, so that a single backend isn't a failure domain of the whole validator group.
+pub struct QuorumPermissionResolverFactory {
+	pub backends: Vec<Box<dyn PermissionResolverFactory>>,
+	/// Number of backends that have to grant a duty, `majority` of the backends usually.
+	pub quorum: usize,
+	/// Time limits of waiting for the backends, a backend not answering in time denies the duty.
+	pub timeouts: DutyTimeouts,
+}
+
+#[async_trait]
+impl PermissionResolverFactory for QuorumPermissionResolverFactory {
+	async fn create(&self) -> Box<dyn PermissionResolver> {
+		let mut backends = Vec::with_capacity(self.backends.len());
+		for factory in &self.backends {
+			backends.push(Arc::from(factory.create().await));
+		}
+		Box::new(QuorumPermissionResolver::new(backends, self.quorum).with_timeouts(self.timeouts))
+	}
+}
+
+/// Permission resolver granting a duty only when a quorum of the backends grants it. The backends
+/// are asked at once and the permission is resolved as soon as the quorum is reached or can't be
+/// reached anymore, the other backends finish their claims in the background. A backend failing
+/// to resolve the permission counts as it decided with its fail policy, which should deny, so
+/// that the failed backends never make up a quorum of their own.
+pub struct QuorumPermissionResolver {
+	backends: Vec<Arc<dyn PermissionResolver>>,
+	quorum: usize,
+	timeouts: DutyTimeouts,
+}
+
+impl QuorumPermissionResolver {
+	pub fn new(
+		backends: Vec<Arc<dyn PermissionResolver>>,
+		quorum: usize,
+	) -> QuorumPermissionResolver {
+		check_quorum(quorum, backends.len());
+		QuorumPermissionResolver { backends, quorum, timeouts: DutyTimeouts::default() }
+	}
+
+	/// Give up waiting for the backends after the given time of each duty.
+	pub fn with_timeouts(mut self, timeouts: DutyTimeouts) -> QuorumPermissionResolver {
+		self.timeouts = timeouts;
+		self
+	}
+
+	async fn resolve<V, F, R>(
+		&self,
+		key: Key,
+		value: V,
+		timeout: Option<Duration>,
+		resolve: R,
+	) -> bool
+	where
+		V: fmt::Display,
+		F: Future<Output = bool> + Send + 'static,
+		R: Fn(Arc<dyn PermissionResolver>) -> F,
+	{
+		decide(&self.backends, self.quorum, key.as_str(), value, timeout, resolve).await
+	}
+}
+
+/// Checks that the quorum is a majority of the backends, so that two disjoint groups of them
+/// can't both grant the same duty.
+fn check_quorum(quorum: usize, backends: usize) {
+	assert!(
+		quorum >= majority(backends) && quorum <= backends,
+		"Quorum of {} out of {} backends isn't their majority or can't be reached",
+		quorum,
+		backends,
+	);
+}
+
+/// Asks every backend at once and decides as soon as the quorum is reached or can't be reached
+/// anymore.
+async fn decide<B, V, F, R>(
+	backends: &[Arc<B>],
+	quorum: usize,
+	duty: &str,
+	value: V,
+	timeout: Option<Duration>,
+	resolve: R,
+) -> bool
+where
+	B: ?Sized,
+	V: fmt::Display,
+	F: Future<Output = bool> + Send + 'static,
+	R: Fn(Arc<B>) -> F,
+{
+	let (sender, mut receiver) = mpsc::unbounded_channel();
+	for backend in backends {
+		let (sender, call) = (sender.clone(), resolve(backend.clone()));
+		tokio::spawn(async move {
+			let _ = sender.send(call.await);
+		});
+	}
+	drop(sender);
+
+	let (mut granted, mut denied) = (0, 0);
+	let decided = async {
+		while let Some(permission) = receiver.recv().await {
+			if permission {
+				granted += 1;
+			} else {
+				denied += 1;
+			}
+			if granted >= quorum {
+				return true
+			}
+			if denied > backends.len() - quorum {
+				return false
+			}
+		}
+		false
+	};
+	let decided = match timeout {
+		Some(timeout) => tokio::time::timeout(timeout, decided).await.ok(),
+		None => Some(decided.await),
+	};
+	let permission = match decided {
+		Some(permission) => permission,
+		None => {
+			warn!(
+				target: "permission-resolver",
+				"Denying {} {} permission, only {} of {} backend(s) answered in time",
+				duty,
+				value,
+				granted + denied,
+				backends.len(),
+			);
+			return false
+		},
+	};
+	debug!(
+		target: "permission-resolver",
+		"{} {} permission granted by {} and denied by {} of {} backend(s)",
+		duty,
+		value,
+		granted,
+		denied,
+		backends.len(),
+	);
+	permission
+}
+
+#[async_trait]
+impl PermissionResolver for QuorumPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		self.resolve(Key::SLOT, u64::from(slot), self.timeouts.slot, |backend| async move {
+			backend.resolve_slot(slot).await
+		})
+		.await
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		self.resolve(Key::ROUND, round, self.timeouts.round, |backend| async move {
+			backend.resolve_round(round).await
+		})
+		.await
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		self.resolve(Key::SESSION, session_index, self.timeouts.session, |backend| async move {
+			backend.resolve_session(session_index).await
+		})
+		.await
+	}
+}
+
+/// Claims of the named duties granted only when a quorum of the backends grants them, like the
+/// duties of `QuorumPermissionResolver`.
+pub struct QuorumDutyClaims {
+	backends: Vec<Arc<dyn DutyClaims>>,
+	quorum: usize,
+	timeout: Option<Duration>,
+}
+
+impl QuorumDutyClaims {
+	pub fn new(backends: Vec<Arc<dyn DutyClaims>>, quorum: usize) -> QuorumDutyClaims {
+		check_quorum(quorum, backends.len());
+		QuorumDutyClaims { backends, quorum, timeout: None }
+	}
+
+	/// Give up waiting for the backends after the given time.
+	pub fn with_timeout(mut self, timeout: Option<Duration>) -> QuorumDutyClaims {
+		self.timeout = timeout;
+		self
+	}
+}
+
+#[async_trait]
+impl DutyClaims for QuorumDutyClaims {
+	async fn claim(&self, duty: &str, index: u64) -> bool {
+		decide(&self.backends, self.quorum, duty, index, self.timeout, |backend| {
+			let duty = duty.to_owned();
+			async move { backend.claim(&duty, index).await }
+		})
+		.await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// Backend granting every duty, denying it, or never answering.
+	#[derive(Clone, Copy)]
+	enum Mocked {
+		Grant,
+		Deny,
+		Stuck,
+	}
+
+	struct MockedBackend {
+		answer: Mocked,
+		asked: Arc<AtomicUsize>,
+	}
+
+	impl MockedBackend {
+		async fn answer(&self) -> bool {
+			self.asked.fetch_add(1, Ordering::SeqCst);
+			match self.answer {
+				Mocked::Grant => true,
+				Mocked::Deny => false,
+				Mocked::Stuck => std::future::pending().await,
+			}
+		}
+	}
+
+	#[async_trait]
+	impl PermissionResolver for MockedBackend {
+		async fn resolve_slot(&self, _: Slot) -> bool {
+			self.answer().await
+		}
+
+		async fn resolve_round(&self, _: u64) -> bool {
+			self.answer().await
+		}
+
+		async fn resolve_session(&self, _: u32) -> bool {
+			self.answer().await
+		}
+	}
+
+	#[async_trait]
+	impl DutyClaims for MockedBackend {
+		async fn claim(&self, _: &str, _: u64) -> bool {
+			self.answer().await
+		}
+	}
+
+	struct MockedFactory {
+		answer: Mocked,
+		asked: Arc<AtomicUsize>,
+	}
+
+	#[async_trait]
+	impl PermissionResolverFactory for MockedFactory {
+		async fn create(&self) -> Box<dyn PermissionResolver> {
+			Box::new(MockedBackend { answer: self.answer, asked: self.asked.clone() })
+		}
+	}
+
+	async fn quorum(answers: &[Mocked], quorum: usize) -> Box<dyn PermissionResolver> {
+		QuorumPermissionResolverFactory {
+			backends: answers
+				.iter()
+				.map(|answer| {
+					Box::new(MockedFactory { answer: *answer, asked: Default::default() })
+						as Box<dyn PermissionResolverFactory>
+				})
+				.collect(),
+			quorum,
+			timeouts: DutyTimeouts {
+				slot: Some(Duration::from_secs(1)),
+				round: Some(Duration::from_secs(1)),
+				session: Some(Duration::from_secs(1)),
+				duty: None,
+			},
+		}
+		.create()
+		.await
+	}
+
+	#[test]
+	fn test_majority() {
+		assert_eq!([1, 2, 3, 4, 5].map(majority), [1, 2, 2, 3, 3]);
+	}
+
+	#[tokio::test]
+	async fn test_grants_with_majority() {
+		use Mocked::*;
+		assert!(quorum(&[Grant, Grant, Deny], 2).await.resolve_slot(1.into()).await);
+		assert!(!quorum(&[Grant, Deny, Deny], 2).await.resolve_round(1).await);
+		assert!(quorum(&[Grant, Deny, Grant], 2).await.resolve_session(1).await);
+		assert!(!quorum(&[Grant, Grant, Deny], 3).await.resolve_slot(1.into()).await);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_decides_without_stuck_backends() {
+		use Mocked::*;
+		assert!(quorum(&[Grant, Stuck, Grant], 2).await.resolve_slot(1.into()).await);
+		assert!(!quorum(&[Deny, Deny, Stuck], 2).await.resolve_slot(1.into()).await);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_denies_when_quorum_does_not_answer_in_time() {
+		use Mocked::*;
+		let resolver = quorum(&[Grant, Stuck, Stuck], 2).await;
+		let started = tokio::time::Instant::now();
+		assert!(!resolver.resolve_slot(1.into()).await);
+		assert_eq!(started.elapsed(), Duration::from_secs(1));
+	}
+
+	#[tokio::test]
+	async fn test_asks_every_backend() {
+		let asked = Arc::new(AtomicUsize::new(0));
+		let backends = (0..3)
+			.map(|_| {
+				Arc::new(MockedBackend { answer: Mocked::Grant, asked: asked.clone() })
+					as Arc<dyn PermissionResolver>
+			})
+			.collect();
+		let resolver = QuorumPermissionResolver::new(backends, 2);
+		assert!(resolver.resolve_round(7).await);
+		tokio::task::yield_now().await;
+		assert_eq!(asked.load(Ordering::SeqCst), 3);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_claims_duties_with_majority() {
+		use Mocked::*;
+		let claims = |answers: &[Mocked]| {
+			let backends = answers
+				.iter()
+				.map(|answer| {
+					Arc::new(MockedBackend { answer: *answer, asked: Default::default() })
+						as Arc<dyn DutyClaims>
+				})
+				.collect();
+			QuorumDutyClaims::new(backends, 2).with_timeout(Some(Duration::from_secs(1)))
+		};
+		assert!(claims(&[Grant, Stuck, Grant]).claim("cleanup", 1).await);
+		assert!(!claims(&[Grant, Deny, Deny]).claim("cleanup", 1).await);
+		assert!(!claims(&[Grant, Stuck, Stuck]).claim("cleanup", 1).await);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_rejects_unreachable_quorum() {
+		QuorumPermissionResolver::new(Vec::new(), 1);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_rejects_quorum_below_majority() {
+		let backends = (0..3)
+			.map(|_| {
+				Arc::new(MockedBackend { answer: Mocked::Grant, asked: Default::default() })
+					as Arc<dyn PermissionResolver>
+			})
+			.collect();
+		QuorumPermissionResolver::new(backends, 1);
+	}
+}
@@ -0,0 +1,117 @@
+use crate::{error::ResolveError, timeout::Deadline};
+use rand::Rng;
+use std::time::Duration;
+use tikv_client::{Error, Value};
+
+/// How transient TiKV failures are retried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+	/// Backoff before the first retry, doubled with every next one.
+	pub min_backoff: Duration,
+	/// Upper bound of the backoff.
+	pub max_backoff: Duration,
+	/// Time since the first attempt after which no more retries are made. Zero disables retries.
+	pub deadline: Duration,
+}
+
+impl RetryPolicy {
+	/// Retries within the first third of the slot, the rest of it is left for the block proposal.
+	pub fn within_slot(slot_duration: Duration) -> RetryPolicy {
+		let deadline = slot_duration / 3;
+		RetryPolicy { min_backoff: Duration::from_millis(10), max_backoff: deadline / 4, deadline }
+	}
+
+	/// Delay before the retry following the given number of attempts, with jitter applied.
+	pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+		let backoff = self
+			.min_backoff
+			.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+			.min(self.max_backoff);
+		rand::thread_rng().gen_range(backoff / 2..=backoff)
+	}
+}
+
+/// Attempt to resolve a permission, along with what's known from the attempts before it.
+pub(crate) struct Attempt {
+	pub deadline: Deadline,
+	/// Claim written by an attempt that failed after the write may have landed, like a commit
+	/// failing on the way back. It's granted if it's still stored, the claim of the attempt would
+	/// be denied by it otherwise.
+	pub unconfirmed: Option<Value>,
+}
+
+impl ResolveError {
+	/// Whether another attempt may succeed. Write conflicts are never retried, they mean that
+	/// another replica has been faster.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			ResolveError::Begin(e) |
+			ResolveError::Read { source: e, .. } |
+			ResolveError::Write { source: e, .. } |
+			ResolveError::Commit(e) => is_transient(e),
+			ResolveError::Disconnected |
+			ResolveError::Rollback(_) |
+			ResolveError::Timeout(_) |
+			ResolveError::Corrupt { .. } |
+			ResolveError::InvalidDuty(_) |
+			ResolveError::PluginIo(_) |
+			ResolveError::Plugin(_) => false,
+		}
+	}
+}
+
+pub(crate) fn is_transient(e: &Error) -> bool {
+	matches!(
+		e,
+		Error::Grpc { .. } |
+			Error::Io { .. } |
+			Error::RegionError { .. } |
+			Error::LeaderNotFound { .. }
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy() -> RetryPolicy {
+		RetryPolicy {
+			min_backoff: Duration::from_millis(10),
+			max_backoff: Duration::from_millis(50),
+			deadline: Duration::from_millis(100),
+		}
+	}
+
+	#[test]
+	fn test_backoff_grows_up_to_max() {
+		let policy = policy();
+		for _ in 0..100 {
+			let first = policy.backoff(1);
+			assert!(first >= Duration::from_millis(5) && first <= Duration::from_millis(10));
+			let second = policy.backoff(2);
+			assert!(second >= Duration::from_millis(10) && second <= Duration::from_millis(20));
+			let tenth = policy.backoff(10);
+			assert!(tenth >= Duration::from_millis(25) && tenth <= Duration::from_millis(50));
+		}
+	}
+
+	#[test]
+	fn test_within_slot_leaves_room_for_proposal() {
+		let policy = RetryPolicy::within_slot(Duration::from_secs(6));
+		assert_eq!(policy.deadline, Duration::from_secs(2));
+		assert!(policy.max_backoff < policy.deadline);
+	}
+
+	#[test]
+	fn test_transient_errors_are_retryable() {
+		let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+		assert!(ResolveError::Begin(Error::Io(io)).is_retryable());
+		assert!(ResolveError::Commit(Error::LeaderNotFound { region_id: 1 }).is_retryable());
+	}
+
+	#[test]
+	fn test_other_errors_are_not_retryable() {
+		assert!(!ResolveError::Commit(Error::StringError("failed".to_owned())).is_retryable());
+		assert!(!ResolveError::Commit(Error::KeyError(Default::default())).is_retryable());
+	}
+}
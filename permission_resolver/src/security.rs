@@ -0,0 +1,325 @@
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, SignatureScheme};
+use rustls_pemfile::Item;
+use std::{fs, io::BufReader, path::PathBuf, time::SystemTime};
+use tikv_client::Config;
+
+/// Algorithms the client certificate may be signed with.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+	&webpki::ECDSA_P256_SHA256,
+	&webpki::ECDSA_P384_SHA384,
+	&webpki::ED25519,
+	&webpki::RSA_PKCS1_2048_8192_SHA256,
+	&webpki::RSA_PKCS1_2048_8192_SHA384,
+	&webpki::RSA_PKCS1_2048_8192_SHA512,
+	&webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+];
+
+/// Message signed with the private key to check that it belongs to the client certificate.
+const KEY_CHECK_MESSAGE: &[u8] = b"remote authority client key check";
+
+/// Certificates securing the connections with PD and TiKV, both sides are authenticated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+	/// CA certificate the PD and TiKV certificates are verified with.
+	pub ca_path: PathBuf,
+	/// Client certificate presented to PD and TiKV.
+	pub cert_path: PathBuf,
+	/// Private key of the client certificate.
+	pub key_path: PathBuf,
+}
+
+/// Misconfigured certificates, reported at startup rather than on the first connection attempt.
+#[derive(Debug, thiserror::Error)]
+pub enum TlsConfigError {
+	#[error("Could not read {}, reason: {source}", path.display())]
+	Read { path: PathBuf, source: std::io::Error },
+	#[error("{} holds no PEM encoded certificate or key", .0.display())]
+	NotPem(PathBuf),
+	#[error("{} is not a valid certificate or key, reason: {reason}", path.display())]
+	Invalid { path: PathBuf, reason: String },
+	#[error("{} is not the private key of {}", key.display(), cert.display())]
+	KeyMismatch { cert: PathBuf, key: PathBuf },
+	#[error("{} is not signed by the CA {}, reason: {reason}", cert.display(), ca.display())]
+	Untrusted { cert: PathBuf, ca: PathBuf, reason: String },
+}
+
+impl TlsConfig {
+	/// Checks that the certificates and the key can be read, that the key belongs to the client
+	/// certificate and that the client certificate is signed by the CA, which signs the
+	/// certificates of the whole cluster.
+	pub fn validate(&self) -> Result<(), TlsConfigError> {
+		let ca_certs = read_certs(&self.ca_path)?;
+		let anchors = ca_certs
+			.iter()
+			.map(|cert| webpki::TrustAnchor::try_from_cert_der(&cert.0))
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|e| TlsConfigError::Invalid {
+				path: self.ca_path.clone(),
+				reason: e.to_string(),
+			})?;
+		let certs = self.certs()?;
+		let key = self.key()?;
+		// read_certs never returns an empty chain
+		let intermediates: Vec<_> = certs[1..].iter().map(|cert| cert.0.as_slice()).collect();
+		let cert = webpki::EndEntityCert::try_from(certs[0].0.as_slice()).map_err(|e| {
+			TlsConfigError::Invalid { path: self.cert_path.clone(), reason: e.to_string() }
+		})?;
+		let untrusted = |reason: String| TlsConfigError::Untrusted {
+			cert: self.cert_path.clone(),
+			ca: self.ca_path.clone(),
+			reason,
+		};
+		let now =
+			webpki::Time::try_from(SystemTime::now()).map_err(|e| untrusted(e.to_string()))?;
+		cert.verify_is_valid_tls_client_cert(
+			SIGNATURE_ALGORITHMS,
+			&webpki::TlsClientTrustAnchors(&anchors),
+			&intermediates,
+			now,
+		)
+		.map_err(|e| untrusted(e.to_string()))?;
+		self.check_key(&cert, &key)?;
+		self.rustls_config().map(drop)
+	}
+
+	pub(crate) fn client_config(&self) -> Config {
+		Config::default().with_security(
+			self.ca_path.clone(),
+			self.cert_path.clone(),
+			self.key_path.clone(),
+		)
+	}
+
+	/// Configuration of the TLS connections made by this crate rather than by the TiKV client.
+	pub(crate) fn rustls_config(&self) -> Result<ClientConfig, TlsConfigError> {
+		ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(self.roots()?)
+			.with_single_cert(self.certs()?, self.key()?)
+			.map_err(|e| TlsConfigError::Invalid {
+				path: self.key_path.clone(),
+				reason: e.to_string(),
+			})
+	}
+
+	fn roots(&self) -> Result<RootCertStore, TlsConfigError> {
+		let mut roots = RootCertStore::empty();
+		for cert in read_certs(&self.ca_path)? {
+			roots.add(&cert).map_err(|e| TlsConfigError::Invalid {
+				path: self.ca_path.clone(),
+				reason: e.to_string(),
+			})?;
+		}
+		Ok(roots)
+	}
+
+	fn certs(&self) -> Result<Vec<Certificate>, TlsConfigError> {
+		read_certs(&self.cert_path)
+	}
+
+	fn key(&self) -> Result<PrivateKey, TlsConfigError> {
+		let items = read_pem(&self.key_path)?;
+		items
+			.into_iter()
+			.find_map(|item| match item {
+				Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+				_ => None,
+			})
+			.ok_or_else(|| TlsConfigError::NotPem(self.key_path.clone()))
+	}
+
+	/// Signs a message with the key and verifies the signature with the public key of the
+	/// certificate.
+	fn check_key(
+		&self,
+		cert: &webpki::EndEntityCert,
+		key: &PrivateKey,
+	) -> Result<(), TlsConfigError> {
+		let invalid =
+			|reason: String| TlsConfigError::Invalid { path: self.key_path.clone(), reason };
+		let mismatch = || TlsConfigError::KeyMismatch {
+			cert: self.cert_path.clone(),
+			key: self.key_path.clone(),
+		};
+		let signer = rustls::sign::any_supported_type(key)
+			.map_err(|e| invalid(e.to_string()))?
+			.choose_scheme(&[
+				SignatureScheme::ECDSA_NISTP256_SHA256,
+				SignatureScheme::ECDSA_NISTP384_SHA384,
+				SignatureScheme::ED25519,
+				SignatureScheme::RSA_PSS_SHA256,
+			])
+			.ok_or_else(|| invalid("unsupported key type".into()))?;
+		let algorithm = match signer.scheme() {
+			SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+			SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+			SignatureScheme::ED25519 => &webpki::ED25519,
+			_ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+		};
+		let signature = signer.sign(KEY_CHECK_MESSAGE).map_err(|e| invalid(e.to_string()))?;
+		cert.verify_signature(algorithm, KEY_CHECK_MESSAGE, &signature)
+			.map_err(|_| mismatch())
+	}
+}
+
+fn read_pem(path: &PathBuf) -> Result<Vec<Item>, TlsConfigError> {
+	let content =
+		fs::read(path).map_err(|source| TlsConfigError::Read { path: path.clone(), source })?;
+	rustls_pemfile::read_all(&mut BufReader::new(content.as_slice()))
+		.map_err(|e| TlsConfigError::Invalid { path: path.clone(), reason: e.to_string() })
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<Certificate>, TlsConfigError> {
+	let certs: Vec<_> = read_pem(path)?
+		.into_iter()
+		.filter_map(|item| match item {
+			Item::X509Certificate(cert) => Some(Certificate(cert)),
+			_ => None,
+		})
+		.collect();
+	if certs.is_empty() {
+		return Err(TlsConfigError::NotPem(path.clone()))
+	}
+	Ok(certs)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use rcgen::{BasicConstraints, CertificateParams, IsCa};
+	use rustls::{server::AllowAnyAuthenticatedClient, ServerConfig};
+	use std::{path::Path, sync::Arc};
+	use tempfile::TempDir;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::{TcpListener, TcpStream},
+	};
+	use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+	/// CA signing the certificates of a cluster.
+	pub(crate) struct TestCa(rcgen::Certificate);
+
+	impl TestCa {
+		pub(crate) fn new() -> TestCa {
+			let mut params = CertificateParams::new(vec!["ca".to_owned()]);
+			params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+			TestCa(rcgen::Certificate::from_params(params).unwrap())
+		}
+
+		/// Certificate of the given name signed by the CA, along with its key.
+		fn sign(&self, name: &str) -> (Certificate, PrivateKey, rcgen::Certificate) {
+			let cert =
+				rcgen::Certificate::from_params(CertificateParams::new(vec![name.to_owned()]))
+					.unwrap();
+			let der = cert.serialize_der_with_signer(&self.0).unwrap();
+			(Certificate(der), PrivateKey(cert.serialize_private_key_der()), cert)
+		}
+
+		/// Writes the CA and a client certificate signed by it.
+		pub(crate) fn client(&self, dir: &Path) -> TlsConfig {
+			let (_, _, client) = self.sign("node");
+			let config = TlsConfig {
+				ca_path: dir.join("ca.pem"),
+				cert_path: dir.join("client.pem"),
+				key_path: dir.join("client-key.pem"),
+			};
+			fs::write(&config.ca_path, self.0.serialize_pem().unwrap()).unwrap();
+			fs::write(&config.cert_path, client.serialize_pem_with_signer(&self.0).unwrap())
+				.unwrap();
+			fs::write(&config.key_path, client.serialize_private_key_pem()).unwrap();
+			config
+		}
+
+		/// Server of `localhost` signed by the CA, accepting the clients signed by it.
+		pub(crate) fn server(&self) -> ServerConfig {
+			let mut roots = RootCertStore::empty();
+			roots.add(&Certificate(self.0.serialize_der().unwrap())).unwrap();
+			let (cert, key, _) = self.sign("localhost");
+			ServerConfig::builder()
+				.with_safe_defaults()
+				.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+				.with_single_cert(vec![cert], key)
+				.unwrap()
+		}
+	}
+
+	/// Whether a handshake of the client and the server succeeds over a loopback connection.
+	async fn handshake(client: ClientConfig, server: ServerConfig) -> bool {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let acceptor = TlsAcceptor::from(Arc::new(server));
+		let accepted = tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			let mut stream = acceptor.accept(stream).await.ok()?;
+			let mut ping = [0; 4];
+			stream.read_exact(&mut ping).await.ok()?;
+			stream.write_all(b"pong").await.ok()
+		});
+		let connector = TlsConnector::from(Arc::new(client));
+		let stream = TcpStream::connect(address).await.unwrap();
+		let name = "localhost".try_into().unwrap();
+		let exchanged = async {
+			let mut stream = connector.connect(name, stream).await.ok()?;
+			stream.write_all(b"ping").await.ok()?;
+			let mut pong = [0; 4];
+			stream.read_exact(&mut pong).await.ok()?;
+			Some(pong == *b"pong")
+		};
+		exchanged.await == Some(true) && accepted.await.unwrap().is_some()
+	}
+
+	#[test]
+	fn test_accepts_generated_certificates() {
+		let dir = TempDir::new().unwrap();
+		let config = TestCa::new().client(dir.path());
+		assert!(config.validate().is_ok());
+		assert_eq!(config.client_config().ca_path, Some(config.ca_path.clone()));
+	}
+
+	#[tokio::test]
+	async fn test_handshakes_with_generated_certificates() {
+		let dir = TempDir::new().unwrap();
+		let ca = TestCa::new();
+		let config = ca.client(dir.path());
+		assert!(handshake(config.rustls_config().unwrap(), ca.server()).await);
+	}
+
+	#[tokio::test]
+	async fn test_rejects_certificate_of_another_ca() {
+		let dir = TempDir::new().unwrap();
+		let ca = TestCa::new();
+		let config = TestCa::new().client(dir.path());
+		fs::write(&config.ca_path, ca.0.serialize_pem().unwrap()).unwrap();
+		assert!(matches!(config.validate(), Err(TlsConfigError::Untrusted { .. })));
+		assert!(!handshake(config.rustls_config().unwrap(), ca.server()).await);
+	}
+
+	#[test]
+	fn test_rejects_key_of_another_certificate() {
+		let (dir, other) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+		let ca = TestCa::new();
+		let config = ca.client(dir.path());
+		fs::copy(ca.client(other.path()).key_path, &config.key_path).unwrap();
+		assert!(matches!(config.validate(), Err(TlsConfigError::KeyMismatch { .. })));
+	}
+
+	#[test]
+	fn test_rejects_missing_key() {
+		let dir = TempDir::new().unwrap();
+		let config = TestCa::new().client(dir.path());
+		fs::remove_file(&config.key_path).unwrap();
+		assert!(
+			matches!(config.validate(), Err(TlsConfigError::Read { path, .. }) if path == config.key_path)
+		);
+	}
+
+	#[test]
+	fn test_rejects_certificate_not_in_pem() {
+		let dir = TempDir::new().unwrap();
+		let config = TestCa::new().client(dir.path());
+		fs::write(&config.cert_path, "not a certificate").unwrap();
+		assert!(
+			matches!(config.validate(), Err(TlsConfigError::NotPem(path)) if path == config.cert_path)
+		);
+	}
+}
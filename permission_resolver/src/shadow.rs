@@ -0,0 +1,283 @@
+use crate::{duty::DutyClaims, metrics::BackendMetrics, Key};
+use async_trait::async_trait;
+use log::{debug, warn};
+use sp_authority_permission::PermissionResolver;
+use sp_consensus_slots::Slot;
+use sp_core::traits::SpawnNamed;
+use std::{fmt, future::Future, sync::Arc};
+use tokio::sync::Semaphore;
+
+/// Most shadow claims made at once, the next ones are skipped while TiKV is slow to answer.
+const MAX_SHADOW_CLAIMS: usize = 64;
+
+/// Compares the claims made in the background with the permission acted upon, and reports the
+/// ones resolved differently.
+#[derive(Clone)]
+struct Comparison {
+	metrics: Option<BackendMetrics>,
+	spawner: Option<Arc<dyn SpawnNamed>>,
+	in_flight: Arc<Semaphore>,
+}
+
+impl Comparison {
+	fn new() -> Comparison {
+		Comparison {
+			metrics: None,
+			spawner: None,
+			in_flight: Arc::new(Semaphore::new(MAX_SHADOW_CLAIMS)),
+		}
+	}
+
+	fn spawn<V, F>(&self, key: Key, value: V, permission: bool, claim: F)
+	where
+		V: fmt::Display + Send + 'static,
+		F: Future<Output = bool> + Send + 'static,
+	{
+		let permit = match self.in_flight.clone().try_acquire_owned() {
+			Ok(permit) => permit,
+			Err(_) => {
+				warn!(
+					target: "permission-resolver",
+					"Skipping the shadow claim of {} {}, {} shadow claims are still made",
+					key.as_str(),
+					value,
+					MAX_SHADOW_CLAIMS,
+				);
+				return
+			},
+		};
+		let metrics = self.metrics.clone();
+		let comparing = async move {
+			let _permit = permit;
+			let remote = claim.await;
+			if remote == permission {
+				debug!(
+					target: "permission-resolver",
+					"Shadow claim of {} {} agreed with the permission", key.as_str(), value
+				);
+				return
+			}
+			warn!(
+				target: "permission-resolver",
+				"Shadow claim of {} {} was {} by TiKV, but {} by the permission acted upon",
+				key.as_str(),
+				value,
+				if remote { "granted" } else { "denied" },
+				if permission { "granted" } else { "denied" },
+			);
+			if let Some(metrics) = &metrics {
+				metrics.observe_shadow_disagreement(key.label(), remote);
+			}
+		};
+		crate::spawn(self.spawner.as_deref(), "remote-authority-shadow-claim", comparing);
+	}
+}
+
+/// Permission resolver claiming on TiKV in the shadow of another resolver, which decides the
+/// permissions alone. The claims are made and recorded like any other, in the background so that
+/// they don't delay the duties, and the disagreements are logged and counted, so that the cluster
+/// can be trusted before the replicas act on its claims.
+pub struct ShadowPermissionResolver {
+	remote: Arc<dyn PermissionResolver>,
+	inner: Box<dyn PermissionResolver>,
+	comparison: Comparison,
+}
+
+impl ShadowPermissionResolver {
+	pub fn new(
+		remote: Box<dyn PermissionResolver>,
+		inner: Box<dyn PermissionResolver>,
+	) -> ShadowPermissionResolver {
+		ShadowPermissionResolver { remote: Arc::from(remote), inner, comparison: Comparison::new() }
+	}
+
+	/// Makes the claims on the given spawner, or on the tokio runtime if not given.
+	pub fn with_spawner(
+		mut self,
+		spawner: Option<Arc<dyn SpawnNamed>>,
+	) -> ShadowPermissionResolver {
+		self.comparison.spawner = spawner;
+		self
+	}
+
+	pub(crate) fn with_metrics(
+		mut self,
+		metrics: Option<BackendMetrics>,
+	) -> ShadowPermissionResolver {
+		self.comparison.metrics = metrics;
+		self
+	}
+}
+
+#[async_trait]
+impl PermissionResolver for ShadowPermissionResolver {
+	async fn resolve_slot(&self, slot: Slot) -> bool {
+		let permission = self.inner.resolve_slot(slot).await;
+		let remote = self.remote.clone();
+		self.comparison.spawn(Key::SLOT, u64::from(slot), permission, async move {
+			remote.resolve_slot(slot).await
+		});
+		permission
+	}
+
+	async fn resolve_round(&self, round: u64) -> bool {
+		let permission = self.inner.resolve_round(round).await;
+		let remote = self.remote.clone();
+		self.comparison
+			.spawn(Key::ROUND, round, permission, async move { remote.resolve_round(round).await });
+		permission
+	}
+
+	async fn resolve_session(&self, session_index: u32) -> bool {
+		let permission = self.inner.resolve_session(session_index).await;
+		let remote = self.remote.clone();
+		self.comparison.spawn(Key::SESSION, session_index, permission, async move {
+			remote.resolve_session(session_index).await
+		});
+		permission
+	}
+}
+
+/// Claims of the named duties made in the shadow mode, every duty is granted like to a node
+/// without replicas.
+pub struct ShadowDutyClaims {
+	claims: Arc<dyn DutyClaims>,
+	comparison: Comparison,
+}
+
+impl ShadowDutyClaims {
+	pub fn new(claims: Arc<dyn DutyClaims>) -> ShadowDutyClaims {
+		ShadowDutyClaims { claims, comparison: Comparison::new() }
+	}
+
+	/// Makes the claims on the given spawner, or on the tokio runtime if not given.
+	pub fn with_spawner(mut self, spawner: Option<Arc<dyn SpawnNamed>>) -> ShadowDutyClaims {
+		self.comparison.spawner = spawner;
+		self
+	}
+
+	pub(crate) fn with_metrics(mut self, metrics: Option<BackendMetrics>) -> ShadowDutyClaims {
+		self.comparison.metrics = metrics;
+		self
+	}
+}
+
+#[async_trait]
+impl DutyClaims for ShadowDutyClaims {
+	async fn claim(&self, duty: &str, index: u64) -> bool {
+		let key = match Key::duty(duty) {
+			Ok(key) => key,
+			Err(e) => {
+				warn!(target: "permission-resolver", "Granting duty without a shadow claim: {}", e);
+				return true
+			},
+		};
+		let (claims, duty) = (self.claims.clone(), duty.to_owned());
+		self.comparison
+			.spawn(key, index, true, async move { claims.claim(&duty, index).await });
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		testing::{CountingSpawner, InMemoryTiKVClient},
+		KeyNamespace, RemoteAuthorityPermissionResolver,
+	};
+	use sp_authority_permission::{AlwaysPermissionGrantedFactory, PermissionResolverFactory};
+	use std::sync::atomic::Ordering;
+
+	async fn replica(client: &InMemoryTiKVClient) -> RemoteAuthorityPermissionResolver {
+		RemoteAuthorityPermissionResolver::new(Box::new(client.clone()), KeyNamespace::Legacy).await
+	}
+
+	async fn shadow(client: &InMemoryTiKVClient) -> ShadowPermissionResolver {
+		let inner = AlwaysPermissionGrantedFactory {}.create().await;
+		ShadowPermissionResolver::new(Box::new(replica(client).await), inner)
+	}
+
+	/// Waits for the claims made in the background to finish.
+	async fn settle(comparison: &Comparison) {
+		let all = MAX_SHADOW_CLAIMS as u32;
+		drop(comparison.in_flight.acquire_many(all).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_claims_in_the_shadow() {
+		let client = InMemoryTiKVClient::default();
+		let alice = shadow(&client).await;
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice.comparison).await;
+		assert!(client.data.lock().unwrap().contains_key(Key::SLOT.as_str()));
+		assert!(!replica(&client).await.resolve_slot(5.into()).await);
+	}
+
+	#[tokio::test]
+	async fn test_defers_to_inner_resolver_when_claim_is_lost() {
+		let client = InMemoryTiKVClient::default();
+		let alice = shadow(&client).await;
+		let bob = shadow(&client).await;
+		assert!(alice.resolve_round(3).await);
+		settle(&alice.comparison).await;
+		assert!(bob.resolve_round(3).await);
+	}
+
+	#[tokio::test]
+	async fn test_counts_disagreements() {
+		let registry = prometheus_endpoint::Registry::new();
+		let metrics = BackendMetrics::new(&registry, None).unwrap();
+		let client = InMemoryTiKVClient::default();
+		let alice = shadow(&client).await;
+		let bob = shadow(&client).await.with_metrics(Some(metrics));
+		assert!(alice.resolve_slot(5.into()).await);
+		settle(&alice.comparison).await;
+		assert!(bob.resolve_slot(5.into()).await);
+		assert!(bob.resolve_slot(6.into()).await);
+		settle(&bob.comparison).await;
+
+		let family = registry
+			.gather()
+			.into_iter()
+			.find(|family| {
+				family.get_name() == "substrate_authority_permission_shadow_disagreements"
+			})
+			.unwrap();
+		assert_eq!(family.get_metric().len(), 1);
+		let labels = family.get_metric()[0].get_label();
+		assert_eq!((labels[0].get_value(), labels[1].get_value()), ("slot", "denied"));
+		assert_eq!(family.get_metric()[0].get_counter().get_value(), 1.0);
+	}
+
+	#[tokio::test]
+	async fn test_defers_to_inner_resolver_when_backend_fails() {
+		let client = InMemoryTiKVClient::default();
+		let alice = shadow(&client).await;
+		client.unavailable.store(true, Ordering::SeqCst);
+		assert!(alice.resolve_session(1).await);
+	}
+
+	#[tokio::test]
+	async fn test_grants_duties_in_the_shadow() {
+		let client = InMemoryTiKVClient::default();
+		let alice = ShadowDutyClaims::new(Arc::new(replica(&client).await));
+		let bob = ShadowDutyClaims::new(Arc::new(replica(&client).await));
+		assert!(alice.claim("price-feed", 1).await);
+		settle(&alice.comparison).await;
+		assert!(bob.claim("price-feed", 1).await);
+		assert!(client.data.lock().unwrap().contains_key("duty/price-feed"));
+	}
+
+	#[tokio::test]
+	async fn test_bounds_claims_in_the_shadow() {
+		let client = InMemoryTiKVClient::default();
+		let spawner = CountingSpawner::default();
+		let alice = shadow(&client).await.with_spawner(Some(Arc::new(spawner.clone())));
+		client.stalled_commits.store(true, Ordering::SeqCst);
+		for round in 0..=MAX_SHADOW_CLAIMS as u64 {
+			assert!(alice.resolve_round(round).await);
+		}
+		assert_eq!(spawner.spawned.load(Ordering::SeqCst), MAX_SHADOW_CLAIMS as u32);
+	}
+}
@@ -0,0 +1,63 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bounds on how far slot claims may be from the slot of the local clock, so that a replica with
+/// a clock running ahead can't lock the others out until real time catches up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockSkewGuard {
+	/// Duration of a slot, the slot of the local clock is the time since the UNIX epoch divided by
+	/// it.
+	pub slot_duration: Duration,
+	/// Number of slots a claim may be away from the slot of the local clock.
+	pub max_drift: u64,
+	/// Overwrite stored slots too far ahead of the local clock. Only to be enabled by the operator
+	/// once the clock of the replica that claimed them is known to be wrong.
+	pub clear_skewed: bool,
+}
+
+impl ClockSkewGuard {
+	/// Slot of the local clock.
+	pub(crate) fn local_slot(&self) -> u64 {
+		self.slot_at(SystemTime::now())
+	}
+
+	fn slot_at(&self, time: SystemTime) -> u64 {
+		let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+		(since_epoch.as_millis() / self.slot_duration.as_millis().max(1)) as u64
+	}
+
+	/// Whether the slot is close enough to the slot of the local clock to be claimed.
+	pub(crate) fn is_plausible(&self, slot: u64, local_slot: u64) -> bool {
+		slot.abs_diff(local_slot) <= self.max_drift
+	}
+
+	/// Latest stored slot that isn't considered skewed.
+	pub(crate) fn latest_plausible(&self, local_slot: u64) -> u64 {
+		local_slot.saturating_add(self.max_drift)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn guard() -> ClockSkewGuard {
+		ClockSkewGuard { slot_duration: Duration::from_secs(6), max_drift: 10, clear_skewed: false }
+	}
+
+	#[test]
+	fn test_slot_of_time() {
+		let time = UNIX_EPOCH + Duration::from_secs(6 * 1000 + 5);
+		assert_eq!(guard().slot_at(time), 1000);
+	}
+
+	#[test]
+	fn test_plausible_slots_are_within_drift() {
+		let guard = guard();
+		assert!(guard.is_plausible(1000, 1000));
+		assert!(guard.is_plausible(1010, 1000));
+		assert!(guard.is_plausible(990, 1000));
+		assert!(!guard.is_plausible(1011, 1000));
+		assert!(!guard.is_plausible(989, 1000));
+		assert_eq!(guard.latest_plausible(1000), 1010);
+	}
+}
@@ -0,0 +1,252 @@
+use crate::{TiKVClient, TiKVRawClient, TiKVTransaction};
+use async_trait::async_trait;
+use sp_core::traits::SpawnNamed;
+use std::{
+	collections::{HashMap, HashSet},
+	future::Future,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Arc, Mutex,
+	},
+};
+use tikv_client::{Error, Timestamp, Value};
+
+/// Client sharing its data between transactions, writes become visible on commit. Commits fail
+/// with a write conflict if a value read by the transaction was changed in the meantime.
+/// Pessimistic transactions wait for the keys they read to be unlocked, and lock them until they
+/// end.
+#[derive(Clone, Default)]
+pub(crate) struct InMemoryTiKVClient {
+	pub data: Arc<Mutex<HashMap<String, Value>>>,
+	pub unavailable: Arc<AtomicBool>,
+	pub transient_failures: Arc<AtomicU32>,
+	pub begins: Arc<AtomicU32>,
+	pub stalled_commits: Arc<AtomicBool>,
+	pub rollbacks: Arc<AtomicU32>,
+	pub raw_requests: Arc<AtomicU32>,
+	pub locks: Arc<Mutex<HashSet<String>>>,
+	pub commit_conflicts: Arc<AtomicU32>,
+	/// Number of the next commits and swaps that land, but fail on the way back.
+	pub lost_replies: Arc<AtomicU32>,
+}
+
+#[async_trait]
+impl TiKVClient for InMemoryTiKVClient {
+	async fn begin_optimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		self.begin(false)
+	}
+
+	async fn begin_pessimistic(&self) -> Result<Box<dyn TiKVTransaction>, Error> {
+		self.begin(true)
+	}
+}
+
+impl InMemoryTiKVClient {
+	fn begin(&self, pessimistic: bool) -> Result<Box<dyn TiKVTransaction>, Error> {
+		self.begins.fetch_add(1, Ordering::SeqCst);
+		if self.unavailable.load(Ordering::SeqCst) {
+			return Err(Error::StringError("TiKV is unavailable".to_owned()))
+		}
+		let failures = self.transient_failures.load(Ordering::SeqCst);
+		if failures > 0 {
+			self.transient_failures.store(failures - 1, Ordering::SeqCst);
+			let e = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "leader changed");
+			return Err(Error::Io(e))
+		}
+		Ok(Box::new(InMemoryTiKVTransaction {
+			client: self.clone(),
+			reads: HashMap::new(),
+			writes: Vec::new(),
+			pessimistic,
+			locked: Vec::new(),
+		}))
+	}
+}
+
+/// Raw requests share the data with the transactions.
+#[async_trait]
+impl TiKVRawClient for InMemoryTiKVClient {
+	async fn get(&self, key: String) -> Result<Option<Value>, Error> {
+		self.raw_request()?;
+		Ok(self.data.lock().unwrap().get(&key).cloned())
+	}
+
+	async fn compare_and_swap(
+		&self,
+		key: String,
+		previous: Option<Value>,
+		value: Value,
+	) -> Result<(Option<Value>, bool), Error> {
+		self.raw_request()?;
+		// lets concurrent requests interleave
+		tokio::task::yield_now().await;
+		let mut data = self.data.lock().unwrap();
+		let stored = data.get(&key).cloned();
+		if stored != previous {
+			return Ok((stored, false))
+		}
+		data.insert(key, value);
+		self.lose_reply()?;
+		Ok((stored, true))
+	}
+}
+
+impl InMemoryTiKVClient {
+	fn raw_request(&self) -> Result<(), Error> {
+		self.raw_requests.fetch_add(1, Ordering::SeqCst);
+		if self.unavailable.load(Ordering::SeqCst) {
+			return Err(Error::StringError("TiKV is unavailable".to_owned()))
+		}
+		Ok(())
+	}
+
+	fn lose_reply(&self) -> Result<(), Error> {
+		let lost = self.lost_replies.load(Ordering::SeqCst);
+		if lost > 0 {
+			self.lost_replies.store(lost - 1, Ordering::SeqCst);
+			let e = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reply lost");
+			return Err(Error::Io(e))
+		}
+		Ok(())
+	}
+}
+
+struct InMemoryTiKVTransaction {
+	client: InMemoryTiKVClient,
+	reads: HashMap<String, Option<Value>>,
+	writes: Vec<(String, Option<Value>)>,
+	pessimistic: bool,
+	locked: Vec<String>,
+}
+
+impl InMemoryTiKVTransaction {
+	fn unlock(&mut self) {
+		let mut locks = self.client.locks.lock().unwrap();
+		for key in self.locked.drain(..) {
+			locks.remove(&key);
+		}
+	}
+}
+
+impl Drop for InMemoryTiKVTransaction {
+	fn drop(&mut self) {
+		self.unlock();
+	}
+}
+
+#[async_trait]
+impl TiKVTransaction for InMemoryTiKVTransaction {
+	async fn get(&mut self, key: String) -> Result<Option<Value>, Error> {
+		Ok(self.client.data.lock().unwrap().get(&key).cloned())
+	}
+
+	async fn get_for_update(&mut self, key: String) -> Result<Option<Value>, Error> {
+		if self.pessimistic && !self.locked.contains(&key) {
+			while !self.client.locks.lock().unwrap().insert(key.clone()) {
+				tokio::task::yield_now().await;
+			}
+			self.locked.push(key.clone());
+		}
+		let value = self.client.data.lock().unwrap().get(&key).cloned();
+		self.reads.insert(key, value.clone());
+		Ok(value)
+	}
+
+	async fn put(&mut self, key: String, value: Vec<u8>) -> Result<(), Error> {
+		self.writes.push((key, Some(value)));
+		Ok(())
+	}
+
+	async fn delete(&mut self, key: String) -> Result<(), Error> {
+		self.writes.push((key, None));
+		Ok(())
+	}
+
+	async fn scan(
+		&mut self,
+		start: String,
+		end: String,
+		limit: u32,
+	) -> Result<Vec<(String, Value)>, Error> {
+		let data = self.client.data.lock().unwrap();
+		let mut entries: Vec<_> = data
+			.iter()
+			.filter(|(key, _)| **key >= start && **key < end)
+			.map(|(key, value)| (key.clone(), value.clone()))
+			.collect();
+		entries.sort();
+		entries.truncate(limit as usize);
+		Ok(entries)
+	}
+
+	async fn commit(&mut self) -> Result<Option<Timestamp>, Error> {
+		if self.client.stalled_commits.load(Ordering::SeqCst) {
+			std::future::pending::<()>().await;
+		}
+		// lets concurrent transactions interleave with the commit
+		tokio::task::yield_now().await;
+		let mut data = self.client.data.lock().unwrap();
+		if self.reads.iter().any(|(key, value)| data.get(key) != value.as_ref()) {
+			self.client.commit_conflicts.fetch_add(1, Ordering::SeqCst);
+			return Err(write_conflict())
+		}
+		for (key, value) in self.writes.drain(..) {
+			match value {
+				Some(value) => data.insert(key, value),
+				None => data.remove(&key),
+			};
+		}
+		drop(data);
+		self.unlock();
+		self.client.lose_reply()?;
+		Ok(Some(Timestamp::default()))
+	}
+
+	async fn rollback(&mut self) -> Result<(), Error> {
+		self.client.rollbacks.fetch_add(1, Ordering::SeqCst);
+		self.writes.clear();
+		self.unlock();
+		Ok(())
+	}
+}
+
+pub(crate) fn write_conflict() -> Error {
+	let mut e = Error::KeyError(Default::default());
+	if let Error::KeyError(key_error) = &mut e {
+		key_error.conflict = Some(Default::default());
+	}
+	e
+}
+
+/// Spawner counting the tasks spawned on the tokio runtime, and the ones finished since.
+#[derive(Clone, Default)]
+pub(crate) struct CountingSpawner {
+	pub spawned: Arc<AtomicU32>,
+	pub finished: Arc<AtomicU32>,
+}
+
+impl SpawnNamed for CountingSpawner {
+	fn spawn_blocking(
+		&self,
+		name: &'static str,
+		group: Option<&'static str>,
+		future: Pin<Box<dyn Future<Output = ()> + Send>>,
+	) {
+		self.spawn(name, group, future)
+	}
+
+	fn spawn(
+		&self,
+		_name: &'static str,
+		_group: Option<&'static str>,
+		future: Pin<Box<dyn Future<Output = ()> + Send>>,
+	) {
+		self.spawned.fetch_add(1, Ordering::SeqCst);
+		let finished = self.finished.clone();
+		tokio::spawn(async move {
+			future.await;
+			finished.fetch_add(1, Ordering::SeqCst);
+		});
+	}
+}
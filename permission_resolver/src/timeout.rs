@@ -0,0 +1,69 @@
+use crate::error::ResolveError;
+use std::{
+	future::Future,
+	time::{Duration, Instant},
+};
+
+/// Hard limit of the time spent on resolving the permission of each duty, retries included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DutyTimeouts {
+	pub slot: Option<Duration>,
+	pub round: Option<Duration>,
+	pub session: Option<Duration>,
+	/// Limit of the claims of the named duties.
+	pub duty: Option<Duration>,
+}
+
+/// Point in time by which the permission has to be resolved.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline {
+	timeout: Option<Duration>,
+	at: Option<Instant>,
+}
+
+impl Deadline {
+	pub fn after(timeout: Option<Duration>) -> Deadline {
+		Deadline { timeout, at: timeout.map(|timeout| Instant::now() + timeout) }
+	}
+
+	/// Whether waiting for the given time still leaves some time before the deadline.
+	pub fn allows(&self, delay: Duration) -> bool {
+		self.at.map_or(true, |at| Instant::now() + delay < at)
+	}
+
+	/// Runs the call, giving up once the deadline is exceeded.
+	pub async fn run<F: Future>(&self, call: F) -> Result<F::Output, ResolveError> {
+		match (self.at, self.timeout) {
+			(Some(at), Some(timeout)) => tokio::time::timeout_at(at.into(), call)
+				.await
+				.map_err(|_| ResolveError::Timeout(timeout)),
+			_ => Ok(call.await),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_runs_call_within_deadline() {
+		let deadline = Deadline::after(Some(Duration::from_millis(100)));
+		assert_eq!(deadline.run(async { 1 }).await.unwrap(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_gives_up_call_after_deadline() {
+		let deadline = Deadline::after(Some(Duration::from_millis(10)));
+		let call = tokio::time::sleep(Duration::from_secs(10));
+		assert!(matches!(deadline.run(call).await, Err(ResolveError::Timeout(_))));
+	}
+
+	#[test]
+	fn test_allows_delays_before_deadline_only() {
+		let deadline = Deadline::after(Some(Duration::from_millis(100)));
+		assert!(deadline.allows(Duration::from_millis(10)));
+		assert!(!deadline.allows(Duration::from_millis(200)));
+		assert!(Deadline::after(None).allows(Duration::from_secs(3600)));
+	}
+}